@@ -0,0 +1,104 @@
+//! Compares the throughput of `LogFile` and `MmapLogFile` on a generated log
+//! where the view keeps one record in a hundred, then that of a view with a
+//! long ELIF chain, which is tested in a single pass.
+//!
+//! Run with `cargo run --release --example bench_readers [LINES]`.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use logviewer::filters::{Condition, Expression, Operation, Pattern, View};
+use logviewer::process;
+use logviewer::readers::{LogFile, LogReader, MmapLogFile};
+
+fn get_view() -> View {
+    View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new(" ERROR (?P<message>.*)$".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![Operation::SkipRecord],
+            },
+        ],
+        ..Default::default()
+    }
+}
+
+/// A chain of ELIF on the message, most records matching none of them.
+fn get_chain_view() -> View {
+    let mut ops = vec![Operation::SkipRecord];
+    for service in &["auth", "billing", "cache", "db", "mail", "search", "queue", "ERROR"] {
+        ops = vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Var("message".to_owned()),
+                    pattern: Pattern::new(format!("^{} (?P<rest>.*)$", service)),
+                },
+                then_ops: vec![],
+                else_ops: ops,
+            },
+        ];
+    }
+    View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^\\S+ \\S+ (?P<message>.*)$".to_owned()),
+                },
+                then_ops: ops,
+                else_ops: vec![Operation::SkipRecord],
+            },
+        ],
+        ..Default::default()
+    }
+}
+
+fn generate(path: &Path, lines: usize) -> std::io::Result<u64> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for i in 0..lines {
+        let level = if i % 100 == 0 { "ERROR" } else { "INFO" };
+        writeln!(
+            file,
+            "2020-01-01T00:{:02}:{:02}Z host{} {} request {} handled in {}ms",
+            (i / 60) % 60, i % 60, i % 7, level, i, i % 1000,
+        )?;
+    }
+    file.flush()?;
+    Ok(std::fs::metadata(path)?.len())
+}
+
+fn run<R: LogReader>(name: &str, reader: R, view: View, size: u64) {
+    let start = Instant::now();
+    let mut kept = 0;
+    for record in process(reader, view) {
+        record.expect("Error during processing");
+        kept += 1;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{:<12} {:>8} kept  {:>8.3} s  {:>8.1} MB/s",
+        name, kept, elapsed, size as f64 / elapsed / 1e6,
+    );
+}
+
+fn main() {
+    let lines = match std::env::args().nth(1) {
+        Some(n) => n.parse().expect("Invalid number of lines"),
+        None => 1_000_000,
+    };
+    let path = std::env::temp_dir().join("logviewer-bench.log");
+    let size = generate(&path, lines).expect("Can't write log");
+    println!("{} lines, {} bytes", lines, size);
+
+    run("LogFile", LogFile::open(&path).expect("Can't open log"), get_view(), size);
+    run("MmapLogFile", MmapLogFile::open(&path).expect("Can't open log"), get_view(), size);
+    run("ELIF chain", LogFile::open(&path).expect("Can't open log"), get_chain_view(), size);
+
+    std::fs::remove_file(&path).expect("Can't remove log");
+}
@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::{Error as IoError};
+use std::str::FromStr;
+
+#[cfg(feature = "json")]
+use serde_derive::{Serialize, Deserialize};
+
+use crate::Record;
+
+/// Number of values kept for percentiles. Past this, a uniform sample of the
+/// values is kept, and percentiles are estimated from it.
+const MAX_SAMPLES: usize = 10_000;
+
+/// Number of groups an aggregation can have.
+pub const MAX_GROUPS: usize = 10_000;
+
+#[derive(Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
+pub enum Metric {
+    Count,
+    Sum(String),
+    Avg(String),
+    Min(String),
+    Max(String),
+    Percentile(String, f64),
+}
+
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
+pub struct Aggregation {
+    pub group_by: Vec<String>,
+    pub metrics: Vec<Metric>,
+}
+
+impl Aggregation {
+    pub fn check(&self) -> Result<(), InvalidMetric> {
+        self.metrics.iter().try_for_each(Metric::check)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidMetric(String);
+
+impl std::fmt::Display for InvalidMetric {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Invalid metric {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidMetric {}
+
+#[derive(Debug)]
+pub struct TooManyGroups;
+
+impl std::fmt::Display for TooManyGroups {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "More than {} groups", MAX_GROUPS)
+    }
+}
+
+impl std::error::Error for TooManyGroups {}
+
+impl FromStr for Metric {
+    type Err = InvalidMetric;
+
+    /// Parse a metric from the command-line form, e.g. `count`, `sum:bytes`,
+    /// `p95:latency`.
+    fn from_str(s: &str) -> Result<Metric, InvalidMetric> {
+        if s == "count" {
+            return Ok(Metric::Count);
+        }
+        let err = || InvalidMetric(s.to_owned());
+        let (function, var) = {
+            let mut parts = s.splitn(2, ':');
+            let function = parts.next().unwrap();
+            let var = parts.next().ok_or_else(err)?.to_owned();
+            (function, var)
+        };
+        match function {
+            "sum" => Ok(Metric::Sum(var)),
+            "avg" => Ok(Metric::Avg(var)),
+            "min" => Ok(Metric::Min(var)),
+            "max" => Ok(Metric::Max(var)),
+            _ if function.starts_with('p') => {
+                let p: f64 = function[1..].parse().map_err(|_| err())?;
+                if !(0.0..=100.0).contains(&p) {
+                    return Err(err());
+                }
+                Ok(Metric::Percentile(var, p))
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+impl Metric {
+    pub fn name(&self) -> String {
+        match self {
+            Metric::Count => "count".to_owned(),
+            Metric::Sum(var) => format!("sum:{}", var),
+            Metric::Avg(var) => format!("avg:{}", var),
+            Metric::Min(var) => format!("min:{}", var),
+            Metric::Max(var) => format!("max:{}", var),
+            Metric::Percentile(var, p) => format!("p{}:{}", p, var),
+        }
+    }
+
+    /// Check that the metric can be computed, e.g. that a percentile is
+    /// between 0 and 100.
+    pub fn check(&self) -> Result<(), InvalidMetric> {
+        match self {
+            Metric::Percentile(_, p) if !(0.0..=100.0).contains(p) => {
+                Err(InvalidMetric(self.name()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn variable(&self) -> Option<&str> {
+        match self {
+            Metric::Count => None,
+            Metric::Sum(var) | Metric::Avg(var) | Metric::Min(var)
+            | Metric::Max(var) | Metric::Percentile(var, _) => Some(var),
+        }
+    }
+}
+
+/// Accumulated values of a numeric variable within a group.
+#[derive(Default)]
+struct Values {
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    /// Sample of the values, only kept if a percentile is requested
+    samples: Vec<f64>,
+    count: u64,
+}
+
+/// A pseudo-random number, from the SplitMix64 generator.
+fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl Values {
+    fn add(&mut self, value: f64, keep: bool) {
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |m| m.min(value)));
+        self.max = Some(self.max.map_or(value, |m| m.max(value)));
+        self.count += 1;
+        if keep {
+            if self.samples.len() < MAX_SAMPLES {
+                self.samples.push(value);
+            } else {
+                // Reservoir sampling: replace a sample with probability
+                // MAX_SAMPLES / count
+                let idx = (mix(self.count) % self.count) as usize;
+                if idx < MAX_SAMPLES {
+                    self.samples[idx] = value;
+                }
+            }
+        }
+    }
+
+    fn percentile(&mut self, p: f64) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        self.samples.sort_by(f64::total_cmp);
+        // Linear interpolation between closest ranks
+        let rank = p / 100.0 * (self.samples.len() - 1) as f64;
+        let low = rank.floor() as usize;
+        let high = rank.ceil() as usize;
+        let frac = rank - low as f64;
+        Some(self.samples[low] + (self.samples[high] - self.samples[low]) * frac)
+    }
+}
+
+struct Group {
+    count: u64,
+    values: Vec<Values>,
+}
+
+/// Streaming aggregation of records.
+pub struct Aggregator<'a> {
+    aggregation: &'a Aggregation,
+    /// Distinct numeric variables used by metrics
+    variables: Vec<String>,
+    /// Whether to keep a sample of the values of each variable, for
+    /// percentiles
+    keep_values: Vec<bool>,
+    groups: BTreeMap<Vec<Option<String>>, Group>,
+}
+
+impl<'a> Aggregator<'a> {
+    pub fn new(aggregation: &'a Aggregation) -> Result<Aggregator<'a>, InvalidMetric> {
+        aggregation.check()?;
+        let mut variables: Vec<String> = Vec::new();
+        let mut keep_values = Vec::new();
+        for metric in &aggregation.metrics {
+            if let Some(var) = metric.variable() {
+                let idx = match variables.iter().position(|v| v == var) {
+                    Some(idx) => idx,
+                    None => {
+                        variables.push(var.to_owned());
+                        keep_values.push(false);
+                        variables.len() - 1
+                    }
+                };
+                if let Metric::Percentile(..) = metric {
+                    keep_values[idx] = true;
+                }
+            }
+        }
+        Ok(Aggregator {
+            aggregation,
+            variables,
+            keep_values,
+            groups: BTreeMap::new(),
+        })
+    }
+
+    pub fn add(&mut self, record: &Record) -> Result<(), TooManyGroups> {
+        let key: Vec<_> = self.aggregation.group_by.iter()
+            .map(|var| record.variables.get(var).cloned())
+            .collect();
+        if self.groups.len() >= MAX_GROUPS && !self.groups.contains_key(&key) {
+            return Err(TooManyGroups);
+        }
+        let nb_variables = self.variables.len();
+        let group = self.groups.entry(key).or_insert_with(|| Group {
+            count: 0,
+            values: (0..nb_variables).map(|_| Default::default()).collect(),
+        });
+        group.count += 1;
+        for (i, var) in self.variables.iter().enumerate() {
+            let value = record.variables.get(var)
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| v.is_finite());
+            if let Some(value) = value {
+                group.values[i].add(value, self.keep_values[i]);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> AggregateResult {
+        let metrics = &self.aggregation.metrics;
+        let variables = &self.variables;
+        let rows = self.groups.into_iter().map(|(key, mut group)| {
+            let values = metrics.iter().map(|metric| {
+                let values = metric.variable()
+                    .and_then(|var| variables.iter().position(|v| v == var))
+                    .map(|idx| &mut group.values[idx]);
+                match (metric, values) {
+                    (Metric::Count, _) => Some(group.count as f64),
+                    (Metric::Sum(_), Some(v)) => Some(v.sum),
+                    (Metric::Avg(_), Some(v)) if v.count > 0 => {
+                        Some(v.sum / v.count as f64)
+                    }
+                    (Metric::Min(_), Some(v)) => v.min,
+                    (Metric::Max(_), Some(v)) => v.max,
+                    (Metric::Percentile(_, p), Some(v)) => v.percentile(*p),
+                    _ => None,
+                }
+            }).collect();
+            AggregateRow { key, values }
+        }).collect();
+        AggregateResult {
+            group_by: self.aggregation.group_by.clone(),
+            metrics: metrics.iter().map(Metric::name).collect(),
+            rows,
+        }
+    }
+}
+
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct AggregateRow {
+    pub key: Vec<Option<String>>,
+    pub values: Vec<Option<f64>>,
+}
+
+#[cfg_attr(feature = "json", derive(Serialize), serde(rename_all = "camelCase"))]
+pub struct AggregateResult {
+    pub group_by: Vec<String>,
+    pub metrics: Vec<String>,
+    pub rows: Vec<AggregateRow>,
+}
+
+impl AggregateResult {
+    /// Print as a text table with aligned columns.
+    pub fn print(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut lines: Vec<Vec<String>> = Vec::with_capacity(self.rows.len() + 1);
+        lines.push(self.group_by.iter().chain(&self.metrics).cloned().collect());
+        for row in &self.rows {
+            lines.push(
+                row.key.iter()
+                    .map(|k| k.clone().unwrap_or_else(|| "-".to_owned()))
+                    .chain(row.values.iter().map(|v| match v {
+                        Some(v) => format!("{}", v),
+                        None => "-".to_owned(),
+                    }))
+                    .collect()
+            );
+        }
+        let mut widths = vec![0; lines[0].len()];
+        for line in &lines {
+            for (width, cell) in widths.iter_mut().zip(line) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for line in &lines {
+            for (i, (width, cell)) in widths.iter().zip(line).enumerate() {
+                if i > 0 {
+                    write!(f, "  ")?;
+                }
+                if i < self.group_by.len() {
+                    write!(f, "{:<width$}", cell, width = width)?;
+                } else {
+                    write!(f, "{:>width$}", cell, width = width)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for AggregateResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.print(f)
+    }
+}
+
+/// Aggregate processed records, for example from `process()`.
+pub fn aggregate<I: Iterator<Item = Result<Record, IoError>>>(
+    records: I,
+    aggregation: &Aggregation,
+) -> Result<AggregateResult, IoError> {
+    let mut aggregator = Aggregator::new(aggregation)
+        .map_err(|e| IoError::new(std::io::ErrorKind::InvalidInput, e))?;
+    for record in records {
+        aggregator.add(&record?)
+            .map_err(|e| IoError::new(std::io::ErrorKind::InvalidInput, e))?;
+    }
+    Ok(aggregator.finish())
+}
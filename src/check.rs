@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, HashSet};
+
+#[cfg(feature = "json")]
+use serde_derive::Serialize;
+
+use crate::filters::{Condition, DedupWindow, Expression, Operation, ParseFormat, Pattern, View};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize), serde(rename_all = "camelCase"))]
+pub enum WarningKind {
+    /// A variable is read but nothing in the view sets it
+    UnsetVariable,
+    /// The last value of a variable is read, but its values are not tracked
+    UntrackedVariable,
+    /// A pattern has a group that captures into a local variable which is
+    /// never read
+    UnusedGroup,
+    /// Operations that can never run
+    Unreachable,
+    /// A ColorBy whose color is always replaced by a later one
+    OverwrittenColor,
+    /// A call to a block that doesn't exist
+    UnknownBlock,
+    /// A block that calls itself, directly or through other blocks
+    RecursiveCall,
+}
+
+/// A likely mistake found in a view.
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Warning {
+    /// Location of the operation in the view, in the same terms as the JSON
+    /// form, e.g. `operations[2].then[0]`
+    pub path: String,
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Variables that a view can set.
+#[derive(Default)]
+struct Assigned {
+    names: HashSet<String>,
+    /// Prefixes of parse operations that can set any variable
+    any_with_prefix: Vec<String>,
+}
+
+impl Assigned {
+    fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+            || self.any_with_prefix.iter().any(|p| name.starts_with(p.as_str()))
+    }
+}
+
+/// The fields a parse format sets, if they are known in advance.
+fn parse_fields(format: &ParseFormat) -> Option<Vec<&str>> {
+    let common = [
+        "client", "ident", "user", "time", "request", "method", "path", "protocol", "status",
+        "bytes",
+    ];
+    let syslog = ["priority", "facility", "severity", "time", "host", "app", "pid", "message"];
+    let mut fields = Vec::new();
+    match format {
+        ParseFormat::ApacheCommon => fields.extend(&common),
+        ParseFormat::ApacheCombined => {
+            fields.extend(&common);
+            fields.extend(&["referer", "user_agent"]);
+        }
+        ParseFormat::Nginx => {
+            fields.extend(&common);
+            fields.extend(&["referer", "user_agent", "forwarded_for"]);
+        }
+        ParseFormat::SyslogRfc3164 => fields.extend(&syslog),
+        ParseFormat::SyslogRfc5424 => {
+            fields.extend(&syslog);
+            fields.extend(&["version", "msgid", "structured_data"]);
+        }
+        // Extra values are named by number, which can't be confused with
+        // column names in practice
+        ParseFormat::Csv { columns, .. } if !columns.is_empty() => {
+            fields.extend(columns.iter().map(String::as_str));
+        }
+        _ => return None,
+    }
+    Some(fields)
+}
+
+fn collect_assigned(operations: &[Operation], assigned: &mut Assigned) {
+    for operation in operations {
+        match operation {
+            Operation::If { condition, then_ops, else_ops } => {
+                let Condition::Match { pattern, .. } = condition;
+                assigned.names.extend(pattern.groups.iter().cloned());
+                collect_assigned(then_ops, assigned);
+                collect_assigned(else_ops, assigned);
+            }
+            Operation::Set { target, .. } | Operation::Cluster { target, .. } => {
+                assigned.names.insert(target.clone());
+            }
+            Operation::CollapseRepeats { count, .. } => {
+                assigned.names.insert(count.clone());
+            }
+            Operation::Parse { format, prefix, else_ops, .. } => {
+                match parse_fields(format) {
+                    Some(fields) => {
+                        let fields = fields.iter().map(|f| format!("{}{}", prefix, f));
+                        assigned.names.extend(fields);
+                    }
+                    None => assigned.any_with_prefix.push(prefix.clone()),
+                }
+                collect_assigned(else_ops, assigned);
+            }
+            Operation::ColorBy(_)
+            | Operation::SkipRecord
+            | Operation::Unset { .. }
+            | Operation::Call(_)
+            | Operation::Dedup { .. } => {}
+        }
+    }
+}
+
+/// The variable an expression reads, if any.
+fn read_variable(expression: &Expression) -> Option<&str> {
+    match expression {
+        Expression::Var(name) | Expression::LastVarValue(name) => Some(name),
+        Expression::Record | Expression::Constant(_) => None,
+    }
+}
+
+fn collect_read<'a>(operations: &'a [Operation], read: &mut HashSet<&'a str>) {
+    for operation in operations {
+        let expressions: Vec<&Expression> = match operation {
+            Operation::If { condition, then_ops, else_ops } => {
+                collect_read(then_ops, read);
+                collect_read(else_ops, read);
+                let Condition::Match { expression, .. } = condition;
+                vec![expression]
+            }
+            Operation::Set { expression, .. }
+            | Operation::ColorBy(expression)
+            | Operation::Cluster { expression, .. } => vec![expression],
+            Operation::Dedup { key, window } => match window {
+                DedupWindow::Seconds { time, .. } => vec![key, time],
+                DedupWindow::Records(_) => vec![key],
+            },
+            Operation::CollapseRepeats { key, .. } => vec![key],
+            Operation::Parse { expression, else_ops, .. } => {
+                collect_read(else_ops, read);
+                vec![expression]
+            }
+            Operation::SkipRecord | Operation::Unset { .. } | Operation::Call(_) => vec![],
+        };
+        read.extend(expressions.into_iter().filter_map(read_variable));
+    }
+}
+
+/// The blocks called by operations.
+fn calls<'a>(operations: &'a [Operation], called: &mut Vec<&'a str>) {
+    for operation in operations {
+        match operation {
+            Operation::Call(name) => called.push(name),
+            Operation::If { then_ops, else_ops, .. } => {
+                calls(then_ops, called);
+                calls(else_ops, called);
+            }
+            Operation::Parse { else_ops, .. } => calls(else_ops, called),
+            _ => {}
+        }
+    }
+}
+
+/// Whether calling a block ends up calling the target block.
+fn reaches<'a>(
+    blocks: &'a BTreeMap<String, Vec<Operation>>,
+    from: &'a str,
+    target: &str,
+    seen: &mut HashSet<&'a str>,
+) -> bool {
+    if from == target {
+        return true;
+    }
+    if !seen.insert(from) {
+        return false;
+    }
+    let mut called = Vec::new();
+    if let Some(block) = blocks.get(from) {
+        calls(block, &mut called);
+    }
+    called.into_iter().any(|name| reaches(blocks, name, target, seen))
+}
+
+/// Whether a pattern matches any text, so the else branch never runs.
+///
+/// Only patterns that obviously do are reported: empty, or `.*` possibly in
+/// a group. With an end anchor, `.` must also match newlines, since records
+/// can span lines.
+fn is_catch_all(pattern: &Pattern) -> bool {
+    let regex = pattern.regex.as_str();
+    let (dot_all, regex) = match regex.strip_prefix("(?s)") {
+        Some(rest) => (true, rest),
+        None => (false, regex),
+    };
+    let regex = regex.strip_prefix('^').unwrap_or(regex);
+    let (anchored, regex) = match regex.strip_suffix('$') {
+        Some(rest) => (true, rest),
+        None => (false, regex),
+    };
+    // Unwrap a group, e.g. (?P<rest>.*)
+    let regex = match regex.strip_prefix('(').and_then(|r| r.strip_suffix(')')) {
+        Some(group) if group.starts_with("?P<") => {
+            group.find('>').map_or(regex, |end| &group[end + 1..])
+        }
+        Some(group) => group.strip_prefix("?:").unwrap_or(group),
+        None => regex,
+    };
+    match regex {
+        "" => !anchored,
+        ".*" | ".*?" => dot_all || !anchored,
+        _ => false,
+    }
+}
+
+/// Whether operations always skip the record.
+fn always_skips(operations: &[Operation]) -> bool {
+    operations.iter().any(|operation| match operation {
+        Operation::SkipRecord => true,
+        Operation::If { then_ops, else_ops, .. } => {
+            always_skips(then_ops) && always_skips(else_ops)
+        }
+        _ => false,
+    })
+}
+
+/// Whether operations always set the color.
+fn always_colors(operations: &[Operation]) -> bool {
+    operations.iter().any(|operation| match operation {
+        Operation::ColorBy(_) => true,
+        Operation::If { then_ops, else_ops, .. } => {
+            always_colors(then_ops) && always_colors(else_ops)
+        }
+        _ => false,
+    })
+}
+
+struct Checker<'a> {
+    view: &'a View,
+    assigned: Assigned,
+    /// Variables that the view reads
+    read: HashSet<&'a str>,
+    /// The block being checked
+    block: Option<&'a str>,
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Checker<'a> {
+    fn warn(&mut self, path: &str, kind: WarningKind, message: String) {
+        self.warnings.push(Warning { path: path.to_owned(), kind, message });
+    }
+
+    fn check_variable(&mut self, path: &str, name: &str, action: &str) {
+        if !self.assigned.contains(name) {
+            self.warn(
+                path,
+                WarningKind::UnsetVariable,
+                format!("Variable {} is {} but never set", name, action),
+            );
+        }
+    }
+
+    fn check_expression(&mut self, path: &str, expression: &Expression) {
+        match expression {
+            Expression::Var(name) => self.check_variable(path, name, "read"),
+            Expression::LastVarValue(name) => {
+                self.check_variable(path, name, "read");
+                if self.view.untracked.contains(name) {
+                    self.warn(
+                        path,
+                        WarningKind::UntrackedVariable,
+                        format!("Variable {} is untracked, its last value is always empty", name),
+                    );
+                }
+            }
+            Expression::Record | Expression::Constant(_) => {}
+        }
+    }
+
+    /// Check a list of operations, `colored` being whether the color is
+    /// always set after them.
+    fn check_operations(&mut self, path: &str, operations: &[Operation], colored: bool) {
+        for (i, operation) in operations.iter().enumerate() {
+            let path = format!("{}[{}]", path, i);
+            let rest = &operations[i + 1..];
+            let colored = colored || always_colors(rest);
+            if !rest.is_empty() && always_skips(std::slice::from_ref(operation)) {
+                self.warn(
+                    &path,
+                    WarningKind::Unreachable,
+                    "This always skips the record, the operations after it never run"
+                        .to_owned(),
+                );
+            }
+            self.check_operation(&path, operation, colored);
+        }
+    }
+
+    fn check_operation(&mut self, path: &str, operation: &Operation, colored: bool) {
+        match operation {
+            Operation::If { condition, then_ops, else_ops } => {
+                let Condition::Match { expression, pattern } = condition;
+                self.check_expression(path, expression);
+                // Other variables are output, so they are used
+                let unused: Vec<&String> = pattern.groups.iter()
+                    .filter(|name| self.view.locals.contains(*name))
+                    .filter(|name| !self.read.contains(name.as_str()))
+                    .collect();
+                for name in unused {
+                    self.warn(
+                        path,
+                        WarningKind::UnusedGroup,
+                        format!(
+                            "Group {} of pattern \"{}\" sets a local variable that \
+                             is never read",
+                            name, pattern.regex,
+                        ),
+                    );
+                }
+                if !else_ops.is_empty() && is_catch_all(pattern) {
+                    let what = match else_ops.as_slice() {
+                        [Operation::If { .. }] => "ELIF",
+                        _ => "ELSE",
+                    };
+                    self.warn(
+                        path,
+                        WarningKind::Unreachable,
+                        format!(
+                            "Pattern \"{}\" matches everything, the {} branch never runs",
+                            pattern.regex, what,
+                        ),
+                    );
+                }
+                self.check_operations(&format!("{}.then", path), then_ops, colored);
+                self.check_operations(&format!("{}.else", path), else_ops, colored);
+            }
+            Operation::Set { expression, .. } => self.check_expression(path, expression),
+            Operation::ColorBy(expression) => {
+                self.check_expression(path, expression);
+                if colored {
+                    self.warn(
+                        path,
+                        WarningKind::OverwrittenColor,
+                        "The color is always replaced by a later COLOR-BY".to_owned(),
+                    );
+                }
+            }
+            Operation::SkipRecord => {}
+            Operation::Unset { target } => self.check_variable(path, target, "unset"),
+            Operation::Call(name) => {
+                if !self.view.blocks.contains_key(name) {
+                    self.warn(
+                        path,
+                        WarningKind::UnknownBlock,
+                        format!("Block {} is not defined", name),
+                    );
+                } else if let Some(block) = self.block {
+                    if reaches(&self.view.blocks, name, block, &mut HashSet::new()) {
+                        self.warn(
+                            path,
+                            WarningKind::RecursiveCall,
+                            format!("Calling block {} from block {} never ends", name, block),
+                        );
+                    }
+                }
+            }
+            Operation::Cluster { expression, .. } => self.check_expression(path, expression),
+            Operation::Dedup { key, window } => {
+                self.check_expression(path, key);
+                if let DedupWindow::Seconds { time, .. } = window {
+                    self.check_expression(path, time);
+                }
+            }
+            Operation::CollapseRepeats { key, .. } => self.check_expression(path, key),
+            Operation::Parse { expression, else_ops, .. } => {
+                self.check_expression(path, expression);
+                self.check_operations(&format!("{}.else", path), else_ops, colored);
+            }
+        }
+    }
+}
+
+/// Look for likely mistakes in a view.
+///
+/// Variables set by the log reader, such as the fields of a journal, are
+/// not known here and are reported as never set. Includes should be resolved
+/// first, otherwise calls to their blocks are reported.
+pub fn check(view: &View) -> Vec<Warning> {
+    let mut assigned = Assigned::default();
+    collect_assigned(&view.operations, &mut assigned);
+    for block in view.blocks.values() {
+        collect_assigned(block, &mut assigned);
+    }
+    let mut read = HashSet::new();
+    collect_read(&view.operations, &mut read);
+    for block in view.blocks.values() {
+        collect_read(block, &mut read);
+    }
+    let mut checker = Checker {
+        view,
+        assigned,
+        read,
+        block: None,
+        warnings: Vec::new(),
+    };
+    checker.check_operations("operations", &view.operations, false);
+    for (name, block) in &view.blocks {
+        checker.block = Some(name);
+        checker.check_operations(&format!("blocks.{}", name), block, false);
+    }
+    checker.warnings
+}
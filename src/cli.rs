@@ -1,25 +1,403 @@
-use clap::{App, Arg, SubCommand, crate_version};
-use std::fs::File;
-use std::io::{Write, stdout};
+use clap::{App, Arg, ArgMatches, SubCommand, crate_version};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::io::{Write, stdin, stdout};
 use std::process;
 
+use logviewer::aggregate::{Aggregation, Metric, aggregate};
+use logviewer::check::check;
+use logviewer::cluster::{ClusterOptions, cluster};
+use logviewer::explain::{explain, explain_text};
+use logviewer::fields::{FieldsOptions, fields};
+use logviewer::histogram::{HistogramOptions, histogram};
+use logviewer::filters::View;
 use logviewer::process;
-use logviewer::readers::LogFile;
+use logviewer::readers::{
+    CriReader, CsvReader, DEFAULT_MAX_RECORD_SIZE, DockerJsonReader, JournalExportFile,
+    JournalJsonReader, LogFile, LogReader, MmapLogFile, ReadOptions, StreamReader,
+};
+use logviewer::store::ViewStore;
+use logviewer::suggest::{SuggestOptions, suggest_patterns, suggest_view};
+
+/// Displays a view in text form, without the `Debug` wrapper.
+struct ViewText<'a>(&'a View);
+
+impl<'a> std::fmt::Display for ViewText<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.print(f, 0)
+    }
+}
+
+/// The views store from the command line, `~/.logviewer/views` by default.
+fn views_store(matches: &ArgMatches) -> Result<ViewStore, Box<dyn std::error::Error>> {
+    if let Some(dir) = matches.value_of_os("views-dir") {
+        return Ok(ViewStore::new(dir));
+    }
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .filter(|home| !home.is_empty())
+        .ok_or("Can't find the home directory for the saved views, use --views-dir")?;
+    Ok(ViewStore::new(Path::new(&home).join(".logviewer").join("views")))
+}
+
+/// The directories where included views are looked up.
+fn include_path(matches: &ArgMatches) -> Vec<PathBuf> {
+    matches.values_of_os("include-path")
+        .map(|v| v.map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
+/// Read the view given on the command line, a file or a saved view, resolving
+/// its includes.
+fn read_view(matches: &ArgMatches) -> Result<View, Box<dyn std::error::Error>> {
+    let search_path = include_path(matches);
+    if let Some(name) = matches.value_of("view-name") {
+        return Ok(views_store(matches)?.load(name, &search_path)?);
+    }
+    let path = matches.value_of_os("VIEW").unwrap();
+    Ok(View::load(Path::new(path), &search_path)?)
+}
+
+/// Open a log file, decoding records according to the input format.
+/// `-` reads standard input, `mmap` maps other files in memory.
+fn open_log(
+    path: &OsStr,
+    format: &str,
+    options: &ReadOptions,
+    mmap: bool,
+) -> Result<Box<dyn LogReader>, std::io::Error> {
+    if format == "journal" {
+        if path == "-" {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "The journal export format can't be read from standard \
+                 input, use journal-json",
+            ));
+        }
+        let mut file = JournalExportFile::open(path)?;
+        file.max_field_size = options.max_record_size;
+        return Ok(Box::new(file));
+    }
+    let (file, binary): (Box<dyn LogReader>, _) = if path == "-" {
+        let stream = StreamReader::with_options(stdin().lock(), options)?;
+        let binary = stream.looks_binary();
+        (Box::new(stream), binary)
+    } else if mmap {
+        let file = MmapLogFile::open_with_options(path, options)?;
+        let binary = file.looks_binary();
+        (Box::new(file), binary)
+    } else {
+        let file = LogFile::open_with_options(path, options)?;
+        let binary = file.looks_binary();
+        (Box::new(file), binary)
+    };
+    if binary {
+        eprintln!("Warning: {} looks like a binary file", Path::new(path).display());
+    }
+    Ok(match format {
+        "csv" => {
+            let mut reader = CsvReader::new(file);
+            reader.max_record_size = options.max_record_size;
+            Box::new(reader)
+        }
+        "docker" => {
+            let mut reader = DockerJsonReader::new(file);
+            reader.max_record_size = options.max_record_size;
+            Box::new(reader)
+        }
+        "cri" => {
+            let mut reader = CriReader::new(file);
+            reader.max_record_size = options.max_record_size;
+            Box::new(reader)
+        }
+        "journal-json" => Box::new(JournalJsonReader::new(file)),
+        _ => Box::new(file),
+    })
+}
+
+/// The options to read the log given on the command line.
+fn read_options(matches: &ArgMatches) -> Result<ReadOptions, Box<dyn std::error::Error>> {
+    let max_record_size = match matches.value_of("max-record-size") {
+        Some("0") => None,
+        Some(s) => Some(s.parse()?),
+        None => Some(DEFAULT_MAX_RECORD_SIZE),
+    };
+    Ok(ReadOptions {
+        encoding: matches.value_of("encoding").unwrap_or("utf-8").parse()?,
+        max_record_size,
+        refuse_binary: matches.is_present("refuse-binary"),
+    })
+}
+
+/// Open a log file with the options given on the command line.
+fn open_log_arg(
+    path: &OsStr,
+    matches: &ArgMatches,
+) -> Result<Box<dyn LogReader>, Box<dyn std::error::Error>> {
+    Ok(open_log(
+        path,
+        matches.value_of("input-format").unwrap_or("lines"),
+        &read_options(matches)?,
+        matches.is_present("mmap"),
+    )?)
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let app = App::new("logviewer")
         .about("Log Viewer")
         .version(crate_version!())
         .author("Remi Rampin <remirampin@gmail.com>")
+        .arg(Arg::with_name("include-path")
+             .short("I")
+             .long("include-path")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .global(true)
+             .help("Directory where views included by other views are \
+                    looked up, after the directory of the including view"))
+        .arg(Arg::with_name("views-dir")
+             .long("views-dir")
+             .takes_value(true)
+             .env("LOGVIEWER_VIEWS")
+             .global(true)
+             .help("Directory of saved views (default ~/.logviewer/views)"))
+        // Options to read the log, for every command that reads one
+        .arg(Arg::with_name("input-format")
+             .long("input-format")
+             .takes_value(true)
+             .possible_values(&[
+                 "lines", "csv", "docker", "cri", "journal", "journal-json",
+             ])
+             .global(true)
+             .help("Format of the log file: plain lines (default), CSV \
+                    with quoted fields spanning lines, Docker json-file or \
+                    CRI container logs, or journalctl export or JSON \
+                    output"))
+        .arg(Arg::with_name("encoding")
+             .long("encoding")
+             .takes_value(true)
+             .global(true)
+             .help("Encoding of the log file: utf-8 (default), latin-1, \
+                    windows-1252, or utf-16. Invalid characters are \
+                    replaced"))
+        .arg(Arg::with_name("max-record-size")
+             .long("max-record-size")
+             .takes_value(true)
+             .global(true)
+             .help("Truncate records longer than this many bytes (default \
+                    1 MiB, 0 for no limit)"))
+        .arg(Arg::with_name("refuse-binary")
+             .long("refuse-binary")
+             .global(true)
+             .help("Fail if the log looks like a binary file, instead of \
+                    only warning"))
+        .arg(Arg::with_name("mmap")
+             .long("mmap")
+             .global(true)
+             .help("Map the log file in memory, which is faster but unsafe \
+                    if the file is truncated while reading"))
         .subcommand(SubCommand::with_name("process")
                     .about("Process a log file according to a view (JSON) and \
                             output records (JSON lines)")
+                    .arg(Arg::with_name("VIEW")
+                         .required_unless("view-name")
+                         .help("View definition (JSON file)"))
+                    .arg(Arg::with_name("LOG")
+                         .required_unless("view-name")
+                         .help("Log file, or - for standard input"))
+                    .arg(Arg::with_name("view-name")
+                         .long("view-name")
+                         .takes_value(true)
+                         .help("Name of a saved view to use instead of a \
+                                file, the only argument is then the log"))
+                    .arg(Arg::with_name("before")
+                         .short("B")
+                         .long("before-context")
+                         .takes_value(true)
+                         .help("Also output this many skipped records \
+                                before each kept record"))
+                    .arg(Arg::with_name("after")
+                         .short("A")
+                         .long("after-context")
+                         .takes_value(true)
+                         .help("Also output this many skipped records \
+                                after each kept record"))
+                    .arg(Arg::with_name("context")
+                         .short("C")
+                         .long("context")
+                         .takes_value(true)
+                         .help("Also output this many skipped records \
+                                around each kept record")))
+        .subcommand(SubCommand::with_name("explain")
+                    .about("Run a view on a single record and show how it \
+                            was evaluated")
                     .arg(Arg::with_name("VIEW")
                          .required(true)
                          .help("View definition (JSON file)"))
                     .arg(Arg::with_name("LOG")
+                         .required_unless("text")
+                         .help("Log file, or - for standard input"))
+                    .arg(Arg::with_name("offset")
+                         .long("offset")
+                         .takes_value(true)
+                         .default_value("0")
+                         .help("Offset of the record in the log file"))
+                    .arg(Arg::with_name("text")
+                         .long("text")
+                         .takes_value(true)
+                         .conflicts_with("LOG")
+                         .help("Record text to use instead of a log file"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Output the trace as JSON")))
+        .subcommand(SubCommand::with_name("check")
+                    .about("Look for likely mistakes in a view, exiting with \
+                            status 1 if there are any")
+                    .arg(Arg::with_name("VIEW")
                          .required(true)
-                         .help("Log file")));
+                         .help("View definition (JSON file)"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Output the warnings as JSON")))
+        .subcommand(SubCommand::with_name("print")
+                    .about("Print a view in text form")
+                    .arg(Arg::with_name("VIEW")
+                         .required(true)
+                         .help("View definition (JSON file)"))
+                    .arg(Arg::with_name("flatten")
+                         .long("flatten")
+                         .help("Replace calls with the operations of their \
+                                blocks")))
+        .subcommand(SubCommand::with_name("aggregate")
+                    .about("Process a log file according to a view (JSON) and \
+                            compute metrics over groups of records")
+                    .arg(Arg::with_name("VIEW")
+                         .required(true)
+                         .help("View definition (JSON file)"))
+                    .arg(Arg::with_name("LOG")
+                         .required(true)
+                         .help("Log file, or - for standard input"))
+                    .arg(Arg::with_name("group-by")
+                         .short("g")
+                         .long("group-by")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Variable to group records by"))
+                    .arg(Arg::with_name("metric")
+                         .short("m")
+                         .long("metric")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Metric to compute: count, sum:VAR, avg:VAR, \
+                                min:VAR, max:VAR, or pN:VAR for the Nth \
+                                percentile (default: count)"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Output the result as JSON")))
+        .subcommand(SubCommand::with_name("histogram")
+                    .about("Process a log file according to a view (JSON) and \
+                            count records over time")
+                    .arg(Arg::with_name("VIEW")
+                         .required(true)
+                         .help("View definition (JSON file)"))
+                    .arg(Arg::with_name("LOG")
+                         .required(true)
+                         .help("Log file, or - for standard input"))
+                    .arg(Arg::with_name("time-var")
+                         .short("t")
+                         .long("time-var")
+                         .takes_value(true)
+                         .default_value("time")
+                         .help("Variable holding the timestamp"))
+                    .arg(Arg::with_name("split-by")
+                         .short("s")
+                         .long("split-by")
+                         .takes_value(true)
+                         .help("Variable to count records separately by"))
+                    .arg(Arg::with_name("buckets")
+                         .short("b")
+                         .long("buckets")
+                         .takes_value(true)
+                         .default_value("60")
+                         .help("Target number of buckets"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Output the result as JSON")))
+        .subcommand(SubCommand::with_name("fields")
+                    .about("Process a log file according to a view (JSON) and \
+                            list the variables set, with their most frequent \
+                            values")
+                    .arg(Arg::with_name("VIEW")
+                         .required(true)
+                         .help("View definition (JSON file)"))
+                    .arg(Arg::with_name("LOG")
+                         .required(true)
+                         .help("Log file, or - for standard input"))
+                    .arg(Arg::with_name("top")
+                         .short("n")
+                         .long("top")
+                         .takes_value(true)
+                         .default_value("10")
+                         .help("Number of values to show for each variable"))
+                    .arg(Arg::with_name("capacity")
+                         .long("capacity")
+                         .takes_value(true)
+                         .default_value("1000")
+                         .help("Number of distinct values to track for each \
+                                variable, beyond which counts are approximate"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Output the result as JSON")))
+        .subcommand(SubCommand::with_name("suggest")
+                    .about("Suggest a starter view (JSON) from the shapes of \
+                            the lines in a log file")
+                    .arg(Arg::with_name("LOG")
+                         .required(true)
+                         .help("Log file, or - for standard input"))
+                    .arg(Arg::with_name("sample")
+                         .long("sample")
+                         .takes_value(true)
+                         .default_value("1000")
+                         .help("Number of lines to read"))
+                    .arg(Arg::with_name("max-patterns")
+                         .long("max-patterns")
+                         .takes_value(true)
+                         .default_value("20")
+                         .help("Maximum number of patterns to suggest"))
+                    .arg(Arg::with_name("dsl")
+                         .long("dsl")
+                         .help("Print the view in text form instead of JSON")))
+        .subcommand(SubCommand::with_name("patterns")
+                    .about("Process a log file according to a view (JSON) and \
+                            group records into templates")
+                    .arg(Arg::with_name("VIEW")
+                         .required(true)
+                         .help("View definition (JSON file)"))
+                    .arg(Arg::with_name("LOG")
+                         .required(true)
+                         .help("Log file, or - for standard input"))
+                    .arg(Arg::with_name("variable")
+                         .long("variable")
+                         .takes_value(true)
+                         .help("Variable to group, instead of the record"))
+                    .arg(Arg::with_name("similarity")
+                         .long("similarity")
+                         .takes_value(true)
+                         .default_value("0.4")
+                         .help("Fraction of tokens a record must share with \
+                                a template to be grouped with it"))
+                    .arg(Arg::with_name("max-templates")
+                         .long("max-templates")
+                         .takes_value(true)
+                         .default_value("1000")
+                         .help("Maximum number of templates, the least used \
+                                one being dropped for new ones"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Output the result as JSON")));
     #[cfg(feature = "web")]
     let app = app
         .subcommand(SubCommand::with_name("web")
@@ -41,36 +419,259 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     match command {
         "process" => {
             let log_file = {
-                let path = matches.value_of_os("LOG").unwrap();
-                LogFile::open(path)?
+                // With a saved view, the log is the first argument
+                let saved = matches.is_present("view-name");
+                let path = match (saved, matches.value_of_os("VIEW"), matches.value_of_os("LOG")) {
+                    (false, _, Some(path)) | (true, Some(path), None) => path,
+                    (true, _, _) => {
+                        eprintln!("With --view-name, only give the log file.");
+                        process::exit(1);
+                    }
+                    (false, _, None) => unreachable!(),
+                };
+                open_log_arg(path, matches)?
             };
-            let view = {
-                let path = matches.value_of_os("VIEW").unwrap();
-                let file = File::open(path)?;
-                serde_json::from_reader(file)?
+            let view = read_view(matches)?;
+
+            let context: usize = match matches.value_of("context") {
+                Some(c) => c.parse()?,
+                None => 0,
+            };
+            let before = match matches.value_of("before") {
+                Some(b) => b.parse()?,
+                None => context,
+            };
+            let after = match matches.value_of("after") {
+                Some(a) => a.parse()?,
+                None => context,
             };
 
             // Process records
             let out = stdout();
             let mut out = out.lock();
-            for record in process(log_file, view) {
-                let record = record?;
-                serde_json::to_writer(&mut out, &record)?;
-                write!(out, "\n")?;
+            let records = process(log_file, view);
+            if before > 0 || after > 0 {
+                for item in records.with_context(before, after) {
+                    let item = item?;
+                    serde_json::to_writer(&mut out, &item)?;
+                    writeln!(out)?;
+                }
+            } else {
+                for record in records {
+                    let record = record?;
+                    serde_json::to_writer(&mut out, &record)?;
+                    writeln!(out)?;
+                }
             }
 
             // TODO: Allocate concrete colors for FromValue colors, print with
             // ANSI colors in terminal
         }
+        "explain" => {
+            let view = read_view(matches)?;
+            let explanation = match matches.value_of("text") {
+                Some(text) => explain_text(&view, text.to_owned())?,
+                None => {
+                    let mut log_file = {
+                        let path = matches.value_of_os("LOG").unwrap();
+                        open_log_arg(path, matches)?
+                    };
+                    let offset = matches.value_of("offset").unwrap().parse()?;
+                    match explain(&mut log_file, &view, offset)? {
+                        Some(e) => e,
+                        None => {
+                            eprintln!("No record at offset {}", offset);
+                            process::exit(1);
+                        }
+                    }
+                }
+            };
+            if matches.is_present("json") {
+                let out = stdout();
+                let mut out = out.lock();
+                serde_json::to_writer(&mut out, &explanation)?;
+                writeln!(out)?;
+            } else {
+                print!("{:?}", explanation);
+            }
+        }
+        "check" => {
+            let view = read_view(matches)?;
+            let warnings = check(&view);
+            if matches.is_present("json") {
+                let out = stdout();
+                let mut out = out.lock();
+                serde_json::to_writer(&mut out, &warnings)?;
+                writeln!(out)?;
+            } else {
+                for warning in &warnings {
+                    println!("{}", warning);
+                }
+            }
+            if !warnings.is_empty() {
+                process::exit(1);
+            }
+        }
+        "print" => {
+            let view = read_view(matches)?;
+            if matches.is_present("flatten") {
+                print!("{}", ViewText(&view.flatten()?));
+            } else {
+                print!("{}", ViewText(&view));
+            }
+        }
+        "aggregate" => {
+            let log_file = {
+                let path = matches.value_of_os("LOG").unwrap();
+                open_log_arg(path, matches)?
+            };
+            let view = read_view(matches)?;
+            let aggregation = Aggregation {
+                group_by: matches.values_of("group-by")
+                    .map(|v| v.map(ToOwned::to_owned).collect())
+                    .unwrap_or_default(),
+                metrics: match matches.values_of("metric") {
+                    Some(v) => v.map(str::parse).collect::<Result<_, _>>()?,
+                    None => vec![Metric::Count],
+                },
+            };
+
+            let result = aggregate(process(log_file, view), &aggregation)?;
+            if matches.is_present("json") {
+                let out = stdout();
+                let mut out = out.lock();
+                serde_json::to_writer(&mut out, &result)?;
+                writeln!(out)?;
+            } else {
+                print!("{:?}", result);
+            }
+        }
+        "histogram" => {
+            let log_file = {
+                let path = matches.value_of_os("LOG").unwrap();
+                open_log_arg(path, matches)?
+            };
+            let view = read_view(matches)?;
+            let options = HistogramOptions {
+                time_var: matches.value_of("time-var").unwrap().to_owned(),
+                split_by: matches.value_of("split-by").map(ToOwned::to_owned),
+                buckets: matches.value_of("buckets").unwrap().parse()?,
+            };
+
+            let result = histogram(process(log_file, view), &options)?;
+            if matches.is_present("json") {
+                let out = stdout();
+                let mut out = out.lock();
+                serde_json::to_writer(&mut out, &result)?;
+                writeln!(out)?;
+            } else {
+                print!("{:?}", result);
+            }
+        }
+        "fields" => {
+            let log_file = {
+                let path = matches.value_of_os("LOG").unwrap();
+                open_log_arg(path, matches)?
+            };
+            let view = read_view(matches)?;
+            let options = FieldsOptions {
+                top: matches.value_of("top").unwrap().parse()?,
+                capacity: matches.value_of("capacity").unwrap().parse()?,
+            };
+
+            let result = fields(process(log_file, view), &options)?;
+            if matches.is_present("json") {
+                let out = stdout();
+                let mut out = out.lock();
+                serde_json::to_writer(&mut out, &result)?;
+                writeln!(out)?;
+            } else {
+                print!("{:?}", result);
+            }
+        }
+        "suggest" => {
+            let mut log_file = {
+                let path = matches.value_of_os("LOG").unwrap();
+                open_log_arg(path, matches)?
+            };
+            let options = SuggestOptions {
+                sample: matches.value_of("sample").unwrap().parse()?,
+                max_patterns: matches.value_of("max-patterns").unwrap().parse()?,
+            };
+
+            let suggestions = suggest_patterns(&mut log_file, &options)?;
+            let view = suggest_view(&suggestions);
+            if matches.is_present("dsl") {
+                for suggestion in &suggestions {
+                    eprintln!("{} lines like: {}", suggestion.count, suggestion.example);
+                }
+                print!("{}", ViewText(&view));
+            } else {
+                let out = stdout();
+                let mut out = out.lock();
+                serde_json::to_writer_pretty(&mut out, &view)?;
+                writeln!(out)?;
+            }
+        }
+        "patterns" => {
+            let log_file = {
+                let path = matches.value_of_os("LOG").unwrap();
+                open_log_arg(path, matches)?
+            };
+            let view = read_view(matches)?;
+            let options = ClusterOptions {
+                variable: matches.value_of("variable").map(ToOwned::to_owned),
+                similarity: matches.value_of("similarity").unwrap().parse()?,
+                max_templates: matches.value_of("max-templates").unwrap().parse()?,
+                ..Default::default()
+            };
+
+            let result = cluster(log_file, view, &options)?;
+            if matches.is_present("json") {
+                let out = stdout();
+                let mut out = out.lock();
+                serde_json::to_writer(&mut out, &result)?;
+                writeln!(out)?;
+            } else {
+                print!("{:?}", result);
+            }
+        }
         #[cfg(feature = "web")]
         "web" => {
+            let log = matches.value_of_os("LOG").unwrap();
+            if log == "-" {
+                // Queries re-read the log, which can't be done with a stream
+                eprintln!("The web interface needs a log file, not standard input.");
+                process::exit(1);
+            }
+            match matches.value_of("input-format") {
+                None | Some("lines") => {}
+                Some(_) => {
+                    eprintln!("The web interface can only read plain lines.");
+                    process::exit(1);
+                }
+            }
+            let log = log.into();
+            let options = read_options(matches)?;
+            // Saved views are only needed by some requests, which fail if
+            // there is no directory for them
+            let views = match views_store(matches) {
+                Ok(views) => Some(views),
+                Err(e) => {
+                    eprintln!("Warning: {}", e);
+                    None
+                }
+            };
+            let search_path = include_path(matches);
             let mut runtime = tokio::runtime::Builder::new()
                 .basic_scheduler()
                 .enable_all()
                 .build()
                 .unwrap();
             runtime.block_on(
-                logviewer::web::serve([127, 0, 0, 1].into(), 8000),
+                logviewer::web::serve(
+                    [127, 0, 0, 1].into(), 8000, log, options, views, search_path,
+                ),
             );
         }
         _ => panic!("Missing code for command {}", command),
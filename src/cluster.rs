@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{Error as IoError};
+
+#[cfg(feature = "json")]
+use serde_derive::{Serialize, Deserialize};
+
+use crate::process;
+use crate::filters::View;
+use crate::readers::LogReader;
+
+const WILDCARD: &str = "<*>";
+
+fn default_similarity() -> f64 {
+    0.4
+}
+
+fn default_depth() -> usize {
+    4
+}
+
+fn default_max_templates() -> usize {
+    1000
+}
+
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
+pub struct ClusterOptions {
+    /// Variable to cluster, instead of the record text
+    #[cfg_attr(feature = "json", serde(default))]
+    pub variable: Option<String>,
+    /// Minimum fraction of tokens a line must share with a template to be
+    /// grouped with it
+    #[cfg_attr(feature = "json", serde(default = "default_similarity"))]
+    pub similarity: f64,
+    /// Depth of the prefix tree; the first `depth - 2` tokens of lines in a
+    /// template must be equal
+    #[cfg_attr(feature = "json", serde(default = "default_depth"))]
+    pub depth: usize,
+    /// Maximum number of templates; past it, the least used template is
+    /// replaced by the new one
+    #[cfg_attr(feature = "json", serde(default = "default_max_templates"))]
+    pub max_templates: usize,
+}
+
+impl Default for ClusterOptions {
+    fn default() -> ClusterOptions {
+        ClusterOptions {
+            variable: None,
+            similarity: default_similarity(),
+            depth: default_depth(),
+            max_templates: default_max_templates(),
+        }
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Template {
+    pub id: usize,
+    pub tokens: Vec<String>,
+    pub count: u64,
+}
+
+impl Template {
+    pub fn text(&self) -> String {
+        self.tokens.join(" ")
+    }
+
+    /// Fraction of tokens that are equal, not counting wildcards.
+    fn similarity(&self, tokens: &[&str]) -> f64 {
+        if tokens.is_empty() {
+            return 1.0;
+        }
+        let equal = self.tokens.iter().zip(tokens)
+            .filter(|(t, token)| t != &WILDCARD && t == *token)
+            .count();
+        equal as f64 / tokens.len() as f64
+    }
+}
+
+/// Groups lines into templates, using the Drain algorithm.
+///
+/// Lines are first split by their number of tokens and their first few
+/// tokens, then matched against the templates in that group by similarity.
+/// Tokens that differ between lines of a template become wildcards.
+///
+/// Like Drain's `max_clusters`, the number of templates is limited: when a
+/// new template is needed, the least used one is dropped to make room. IDs
+/// are never reused.
+pub struct Drain {
+    similarity: f64,
+    prefix_len: usize,
+    max_templates: usize,
+    /// Positions in `templates`, by number of tokens and prefix
+    groups: HashMap<(usize, Vec<String>), Vec<usize>>,
+    templates: Vec<Template>,
+    /// Group of each template, by position
+    template_groups: Vec<(usize, Vec<String>)>,
+    next_id: usize,
+}
+
+impl Drain {
+    pub fn new(similarity: f64, depth: usize) -> Drain {
+        Drain::with_max_templates(similarity, depth, default_max_templates())
+    }
+
+    pub fn with_max_templates(similarity: f64, depth: usize, max_templates: usize) -> Drain {
+        Drain {
+            similarity,
+            prefix_len: depth.saturating_sub(2),
+            max_templates: max_templates.max(1),
+            groups: HashMap::new(),
+            templates: Vec::new(),
+            template_groups: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Add a line, returning the ID of its template.
+    pub fn add(&mut self, line: &str) -> usize {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let prefix: Vec<String> = tokens.iter()
+            .take(self.prefix_len)
+            .map(|t| {
+                // Tokens with digits are probably parameters, don't split on
+                // them
+                if t.chars().any(|c| c.is_ascii_digit()) {
+                    WILDCARD.to_owned()
+                } else {
+                    (*t).to_owned()
+                }
+            })
+            .collect();
+        let key = (tokens.len(), prefix);
+        let group = self.groups.entry(key.clone()).or_default();
+
+        let templates = &mut self.templates;
+        let best = group.iter()
+            .map(|&pos| (pos, templates[pos].similarity(&tokens)))
+            .fold(None, |best: Option<(usize, f64)>, (pos, sim)| match best {
+                Some((_, best_sim)) if best_sim >= sim => best,
+                _ => Some((pos, sim)),
+            });
+        if let Some((pos, sim)) = best {
+            if sim >= self.similarity {
+                let template = &mut templates[pos];
+                for (t, token) in template.tokens.iter_mut().zip(&tokens) {
+                    if t != token {
+                        *t = WILDCARD.to_owned();
+                    }
+                }
+                template.count += 1;
+                return template.id;
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let template = Template {
+            id,
+            tokens: tokens.iter().map(|&t| t.to_owned()).collect(),
+            count: 1,
+        };
+        let pos = if self.templates.len() < self.max_templates {
+            self.templates.push(template);
+            self.template_groups.push(key.clone());
+            self.templates.len() - 1
+        } else {
+            // Replace the least used template, the oldest one if several are
+            let (pos, _) = self.templates.iter()
+                .enumerate()
+                .min_by_key(|(_, t)| (t.count, t.id))
+                .unwrap();
+            let old_key = std::mem::replace(&mut self.template_groups[pos], key.clone());
+            let old_group = self.groups.get_mut(&old_key).unwrap();
+            old_group.retain(|&p| p != pos);
+            if old_group.is_empty() {
+                self.groups.remove(&old_key);
+            }
+            self.templates[pos] = template;
+            pos
+        };
+        self.groups.entry(key).or_default().push(pos);
+        id
+    }
+
+    /// The current templates, in the order they were created unless some
+    /// were replaced.
+    pub fn templates(&self) -> &[Template] {
+        &self.templates
+    }
+}
+
+impl Default for Drain {
+    fn default() -> Drain {
+        Drain::new(default_similarity(), default_depth())
+    }
+}
+
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct ClusterResult {
+    /// Templates, most frequent first
+    pub templates: Vec<Template>,
+}
+
+impl ClusterResult {
+    pub fn print(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for template in &self.templates {
+            writeln!(f, "{:>8}  #{:<4} {}", template.count, template.id, template.text())?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for ClusterResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.print(f)
+    }
+}
+
+/// Process a log file and group the records kept by the view into templates.
+pub fn cluster<R: LogReader>(
+    reader: R,
+    view: View,
+    options: &ClusterOptions,
+) -> Result<ClusterResult, IoError> {
+    let mut drain = Drain::with_max_templates(
+        options.similarity,
+        options.depth,
+        options.max_templates,
+    );
+    for record in process(reader, view) {
+        let record = record?;
+        match &options.variable {
+            Some(var) => {
+                if let Some(value) = record.variables.get(var) {
+                    drain.add(value);
+                }
+            }
+            None => {
+                drain.add(&record.text);
+            }
+        }
+    }
+    let mut templates = drain.templates;
+    templates.sort_by(|a, b| b.count.cmp(&a.count).then(a.id.cmp(&b.id)));
+    Ok(ClusterResult { templates })
+}
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+use std::io::{Error as IoError};
+
+use crate::Record;
+use crate::process::{Evaluated, FilteredLogIterator};
+use crate::readers::LogReader;
+
+/// An entry output when processing with context.
+pub enum ContextItem {
+    /// A record, either kept or shown as context (see `Record::context`)
+    Record(Record),
+    /// Marks a gap between non-contiguous groups of records
+    Separator,
+}
+
+#[cfg(feature = "json")]
+impl serde::ser::Serialize for ContextItem {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer
+    {
+        use serde::ser::SerializeMap;
+
+        match self {
+            ContextItem::Record(record) => record.serialize(serializer),
+            ContextItem::Separator => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("separator", &true)?;
+                map.end()
+            }
+        }
+    }
+}
+
+pub struct ContextLogIterator<R: LogReader> {
+    inner: FilteredLogIterator<R>,
+    before: usize,
+    after: usize,
+    /// Index of the next record read
+    index: u64,
+    /// Index of the last record that was output
+    last_output: Option<u64>,
+    /// Number of records still to output after the last kept record
+    after_remaining: usize,
+    /// Skipped records that might be output as context before the next kept
+    /// record
+    before_buffer: VecDeque<(u64, Record)>,
+    pending: VecDeque<ContextItem>,
+}
+
+impl<R: LogReader> ContextLogIterator<R> {
+    pub(crate) fn new(
+        inner: FilteredLogIterator<R>,
+        before: usize,
+        after: usize,
+    ) -> ContextLogIterator<R> {
+        ContextLogIterator {
+            inner,
+            before,
+            after,
+            index: 0,
+            last_output: None,
+            after_remaining: 0,
+            before_buffer: VecDeque::with_capacity(before),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn output(&mut self, index: u64, record: Record) {
+        if let Some(last) = self.last_output {
+            if index != last + 1 {
+                self.pending.push_back(ContextItem::Separator);
+            }
+        }
+        self.last_output = Some(index);
+        self.pending.push_back(ContextItem::Record(record));
+    }
+
+    fn next_triable(&mut self) -> Result<Option<ContextItem>, IoError> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Ok(Some(item));
+            }
+
+            let evaluated = match self.inner.next_evaluated()? {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+            let index = self.index;
+            self.index += match evaluated {
+                Evaluated::Hidden(n) => n,
+                _ => 1,
+            };
+
+            match evaluated {
+                Evaluated::Kept(record) => {
+                    while let Some((i, r)) = self.before_buffer.pop_front() {
+                        self.output(i, r);
+                    }
+                    self.output(index, record);
+                    self.after_remaining = self.after;
+                }
+                // Skipped records are built for context
+                Evaluated::Skipped(None) => {}
+                Evaluated::Hidden(_) => self.before_buffer.clear(),
+                Evaluated::Skipped(Some(mut record)) => {
+                    record.context = true;
+                    if self.after_remaining > 0 {
+                        self.after_remaining -= 1;
+                        self.output(index, record);
+                    } else if self.before > 0 {
+                        if self.before_buffer.len() == self.before {
+                            self.before_buffer.pop_front();
+                        }
+                        self.before_buffer.push_back((index, record));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: LogReader> Iterator for ContextLogIterator<R> {
+    type Item = Result<ContextItem, IoError>;
+
+    fn next(&mut self) -> Option<Result<ContextItem, IoError>> {
+        match self.next_triable() {
+            Ok(Some(r)) => Some(Ok(r)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
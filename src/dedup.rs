@@ -0,0 +1,75 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::program::Window;
+
+/// Maximum number of keys remembered for a time window, to bound memory if
+/// a lot of records fall within the window.
+const MAX_TIME_WINDOW_KEYS: usize = 100_000;
+
+/// Recently seen keys of a Dedup operation.
+#[derive(Default)]
+pub(crate) struct DedupState {
+    seen: VecDeque<(f64, String)>,
+    counts: HashMap<String, usize>,
+}
+
+impl DedupState {
+    fn forget_oldest(&mut self) {
+        if let Some((_, key)) = self.seen.pop_front() {
+            if let Some(count) = self.counts.get_mut(&key) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Record a key, returning whether it was seen within the window.
+    ///
+    /// `time` is only used for time windows; records without a valid time are
+    /// never considered duplicates.
+    pub(crate) fn check(
+        &mut self,
+        window: &Window,
+        key: String,
+        time: Option<f64>,
+    ) -> bool {
+        let time = match window {
+            Window::Records(size) => {
+                if *size == 0 {
+                    return false;
+                }
+                while self.seen.len() > *size {
+                    self.forget_oldest();
+                }
+                0.0
+            }
+            Window::Seconds { seconds, .. } => {
+                let time = match time {
+                    Some(t) => t,
+                    None => return false,
+                };
+                while let Some((oldest, _)) = self.seen.front() {
+                    if *oldest >= time - seconds && self.seen.len() < MAX_TIME_WINDOW_KEYS {
+                        break;
+                    }
+                    self.forget_oldest();
+                }
+                time
+            }
+        };
+        let count = self.counts.entry(key.clone()).or_insert(0);
+        let duplicate = *count > 0;
+        *count += 1;
+        self.seen.push_back((time, key));
+        duplicate
+    }
+}
+
+/// A run of consecutive duplicate records, being collapsed into the first.
+pub(crate) struct Repeats {
+    /// Key of the run, `None` once a record with another key ended it
+    pub(crate) key: Option<String>,
+    pub(crate) count: u64,
+}
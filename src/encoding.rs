@@ -0,0 +1,280 @@
+use std::borrow::Cow;
+use std::io::{BufRead, Error as IoError, ErrorKind};
+use std::str::FromStr;
+
+use crate::readers::RawRecord;
+
+/// Character encoding of a log file.
+///
+/// Invalid input is never an error: it is replaced with U+FFFD and the
+/// record is flagged as lossy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// ISO 8859-1, every byte is the code point of the same value
+    Latin1,
+    Windows1252,
+    /// UTF-16, big or little endian depending on the byte order mark at the
+    /// start of the file (little endian if there is none)
+    Utf16,
+}
+
+#[derive(Debug)]
+pub struct InvalidEncoding(String);
+
+impl std::fmt::Display for InvalidEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Unknown encoding {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidEncoding {}
+
+impl FromStr for Encoding {
+    type Err = InvalidEncoding;
+
+    fn from_str(s: &str) -> Result<Encoding, InvalidEncoding> {
+        match s.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            "windows-1252" | "cp1252" => Ok(Encoding::Windows1252),
+            "utf-16" | "utf16" => Ok(Encoding::Utf16),
+            _ => Err(InvalidEncoding(s.to_owned())),
+        }
+    }
+}
+
+/// Code points for bytes 0x80 to 0x9F in Windows-1252, 0 where undefined.
+const WINDOWS_1252: [u16; 32] = [
+    0x20AC, 0, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021,
+    0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0, 0x017D, 0,
+    0, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014,
+    0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0, 0x017E, 0x0178,
+];
+
+/// Splits a byte stream into lines and decodes them.
+pub(crate) struct LineDecoder {
+    encoding: Encoding,
+    big_endian: bool,
+    max_size: Option<usize>,
+}
+
+impl LineDecoder {
+    pub(crate) fn new(encoding: Encoding, max_size: Option<usize>) -> LineDecoder {
+        LineDecoder {
+            encoding,
+            big_endian: false,
+            max_size,
+        }
+    }
+
+    /// Look for a byte order mark at the start of the stream, without
+    /// consuming it.
+    pub(crate) fn detect_bom<B: BufRead>(&mut self, reader: &mut B) -> Result<(), IoError> {
+        if self.encoding == Encoding::Utf16 {
+            let buf = reader.fill_buf()?;
+            self.big_endian = buf.starts_with(&[0xFE, 0xFF]);
+        }
+        Ok(())
+    }
+
+    /// Read the bytes of a line, including its line ending, keeping at most
+    /// `max_size` bytes of its content. Returns the number of bytes read and
+    /// whether some were dropped.
+    fn read_raw<B: BufRead>(
+        &self,
+        reader: &mut B,
+        line: &mut Vec<u8>,
+    ) -> Result<(usize, bool), IoError> {
+        let limit = self.max_size.unwrap_or(usize::MAX);
+        // Room for the longest line ending, \r\n
+        let cap = if self.encoding == Encoding::Utf16 {
+            limit.saturating_add(4)
+        } else {
+            limit.saturating_add(2)
+        };
+        let mut total = 0;
+        let mut truncated = false;
+        // In UTF-16 the newline is two bytes, and 0x0A can appear in other
+        // characters, so we go by code unit
+        let mut half_unit = None;
+        loop {
+            let available = match reader.fill_buf() {
+                Ok(b) => b,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                if let Some(b) = half_unit {
+                    if line.len() < cap {
+                        line.push(b);
+                    }
+                }
+                return Ok((total, self.limit_content(line, limit, truncated)));
+            }
+            let (used, done) = if self.encoding == Encoding::Utf16 {
+                let mut used = 0;
+                let mut done = false;
+                for &b in available {
+                    used += 1;
+                    let unit = match half_unit.take() {
+                        None => {
+                            half_unit = Some(b);
+                            continue;
+                        }
+                        Some(first) => [first, b],
+                    };
+                    if line.len() + 2 <= cap {
+                        line.extend_from_slice(&unit);
+                    } else {
+                        truncated = true;
+                    }
+                    let newline = if self.big_endian { [0, b'\n'] } else { [b'\n', 0] };
+                    if unit == newline {
+                        done = true;
+                        break;
+                    }
+                }
+                (used, done)
+            } else {
+                let (used, done) = match memchr::memchr(b'\n', available) {
+                    Some(i) => (i + 1, true),
+                    None => (available.len(), false),
+                };
+                let keep = used.min(cap.saturating_sub(line.len()));
+                if keep < used {
+                    truncated = true;
+                }
+                line.extend_from_slice(&available[..keep]);
+                (used, done)
+            };
+            reader.consume(used);
+            total += used;
+            if done {
+                return Ok((total, self.limit_content(line, limit, truncated)));
+            }
+        }
+    }
+
+    /// Length of the line ending at the end of `line`, 0 if there is none.
+    fn ending_len(&self, line: &[u8]) -> usize {
+        let (cr, lf): (&[u8], &[u8]) = match self.encoding {
+            Encoding::Utf16 if line.len() % 2 == 1 => return 0,
+            Encoding::Utf16 if self.big_endian => (b"\0\r", b"\0\n"),
+            Encoding::Utf16 => (b"\r\0", b"\n\0"),
+            _ => (b"\r", b"\n"),
+        };
+        match line.strip_suffix(lf) {
+            Some(rest) if rest.ends_with(cr) => cr.len() + lf.len(),
+            Some(_) => lf.len(),
+            None => 0,
+        }
+    }
+
+    /// Cut the content of a line read by `read_raw` to `limit` bytes, keeping
+    /// its line ending. Returns whether the line is truncated.
+    fn limit_content(&self, line: &mut Vec<u8>, limit: usize, truncated: bool) -> bool {
+        let limit = if self.encoding == Encoding::Utf16 { limit & !1 } else { limit };
+        if truncated {
+            // The line ending was dropped
+            line.truncate(limit);
+            return true;
+        }
+        let content = line.len() - self.ending_len(line);
+        if content > limit {
+            line.drain(limit..content);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> (Cow<'a, str>, bool) {
+        match self.encoding {
+            Encoding::Utf8 => {
+                let text = String::from_utf8_lossy(bytes);
+                let lossy = matches!(text, Cow::Owned(_));
+                (text, lossy)
+            }
+            Encoding::Latin1 => (bytes.iter().map(|&b| b as char).collect(), false),
+            Encoding::Windows1252 => {
+                let mut lossy = false;
+                let text = bytes.iter()
+                    .map(|&b| match b {
+                        0x80..=0x9F => {
+                            match WINDOWS_1252[(b - 0x80) as usize] {
+                                0 => {
+                                    lossy = true;
+                                    char::REPLACEMENT_CHARACTER
+                                }
+                                c => char::from_u32(c as u32).unwrap(),
+                            }
+                        }
+                        b => b as char,
+                    })
+                    .collect();
+                (text, lossy)
+            }
+            Encoding::Utf16 => {
+                let mut lossy = bytes.len() % 2 == 1;
+                let units = bytes.chunks_exact(2).map(|c| {
+                    if self.big_endian {
+                        u16::from_be_bytes([c[0], c[1]])
+                    } else {
+                        u16::from_le_bytes([c[0], c[1]])
+                    }
+                });
+                let mut text: String = char::decode_utf16(units)
+                    .map(|c| c.unwrap_or_else(|_| {
+                        lossy = true;
+                        char::REPLACEMENT_CHARACTER
+                    }))
+                    .collect();
+                if bytes.len() % 2 == 1 {
+                    text.push(char::REPLACEMENT_CHARACTER);
+                }
+                (text.into(), lossy)
+            }
+        }
+    }
+
+    /// Read and decode a line, without its line ending, advancing `pos`.
+    pub(crate) fn read_line<B: BufRead>(
+        &self,
+        reader: &mut B,
+        pos: &mut u64,
+    ) -> Result<Option<RawRecord<'static>>, IoError> {
+        let mut bytes = Vec::new();
+        let (ret, truncated) = self.read_raw(reader, &mut bytes)?;
+        if ret == 0 {
+            return Ok(None);
+        }
+        let start = *pos;
+        *pos += ret as u64;
+        if truncated && self.encoding == Encoding::Utf8 {
+            // Don't count a character cut in the middle as invalid
+            let content = bytes.len() - self.ending_len(&bytes);
+            if let Err(e) = std::str::from_utf8(&bytes[..content]) {
+                if e.error_len().is_none() {
+                    bytes.drain(e.valid_up_to()..content);
+                }
+            }
+        }
+        let (text, lossy) = self.decode(&bytes);
+        let mut line = text.into_owned();
+        if line.ends_with("\r\n") {
+            line.truncate(line.len() - 2);
+        } else if line.ends_with('\n') {
+            line.pop();
+        }
+        if start == 0 && line.starts_with('\u{FEFF}') {
+            line.remove(0);
+        }
+        Ok(Some(RawRecord {
+            lossy,
+            truncated,
+            ..RawRecord::new(line)
+        }))
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt::Debug;
+use std::io::{Error as IoError};
+
+#[cfg(feature = "json")]
+use serde_derive::Serialize;
+
+use crate::Record;
+use crate::filters::{BlockError, View, idt};
+use crate::process::FilterInner;
+use crate::program::Program;
+use crate::readers::{LogReader, RawRecord};
+
+/// One thing that happened while evaluating a view on a record.
+#[cfg_attr(feature = "json", derive(Serialize), serde(rename_all = "camelCase"))]
+pub enum TraceEvent {
+    Test {
+        elif: bool,
+        expression: String,
+        pattern: String,
+        value: String,
+        matched: bool,
+    },
+    Parse {
+        expression: String,
+        format: String,
+        parsed: bool,
+    },
+    Else,
+    Capture {
+        name: String,
+        value: String,
+    },
+    Set {
+        target: String,
+        value: String,
+    },
+    ColorBy {
+        value: String,
+    },
+    Skip,
+    Unset {
+        target: String,
+    },
+}
+
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct TraceStep {
+    pub depth: usize,
+    pub event: TraceEvent,
+}
+
+/// The evaluation trace of a view on a single record.
+#[derive(Default)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub(crate) depth: usize,
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub(crate) elif: bool,
+}
+
+impl Trace {
+    pub(crate) fn push(&mut self, event: TraceEvent) {
+        self.steps.push(TraceStep { depth: self.depth, event });
+    }
+
+    pub(crate) fn test(
+        &mut self,
+        expression: &str,
+        pattern: &str,
+        value: &str,
+        matched: bool,
+    ) {
+        let elif = self.elif;
+        self.elif = false;
+        self.push(TraceEvent::Test {
+            elif,
+            expression: expression.to_owned(),
+            pattern: pattern.to_owned(),
+            value: value.to_owned(),
+            matched,
+        });
+    }
+
+    pub fn print(
+        &self,
+        f: &mut std::fmt::Formatter,
+        indent: usize,
+    ) -> std::fmt::Result {
+        for step in &self.steps {
+            idt(f, indent + step.depth)?;
+            match &step.event {
+                TraceEvent::Test { elif, expression, pattern, value, matched } => {
+                    write!(
+                        f,
+                        "{} {} match \"{}\" ",
+                        if *elif { "ELIF" } else { "IF" },
+                        expression,
+                        pattern,
+                    )?;
+                    if *matched {
+                        writeln!(f, "-> matched {:?}", value)?;
+                    } else {
+                        writeln!(f, "-> no match for {:?}", value)?;
+                    }
+                }
+                TraceEvent::Parse { expression, format, parsed } => {
+                    write!(f, "PARSE {} AS {} ", expression, format)?;
+                    if *parsed {
+                        writeln!(f, "-> parsed")?;
+                    } else {
+                        writeln!(f, "-> failed")?;
+                    }
+                }
+                TraceEvent::Else => writeln!(f, "ELSE")?,
+                TraceEvent::Capture { name, value } => {
+                    writeln!(f, "CAPTURE {} = {:?}", name, value)?;
+                }
+                TraceEvent::Set { target, value } => {
+                    writeln!(f, "SET {} = {:?}", target, value)?;
+                }
+                TraceEvent::ColorBy { value } => {
+                    writeln!(f, "COLOR-BY {:?}", value)?;
+                }
+                TraceEvent::Skip => writeln!(f, "SKIP")?,
+                TraceEvent::Unset { target } => writeln!(f, "UNSET {}", target)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Trace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.print(f, 0)
+    }
+}
+
+/// The result of running a view on a single record, with its trace.
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Explanation {
+    pub record: Record,
+    pub kept: bool,
+    pub trace: Trace,
+}
+
+impl Explanation {
+    pub fn print(
+        &self,
+        f: &mut std::fmt::Formatter,
+        indent: usize,
+    ) -> std::fmt::Result {
+        idt(f, indent)?;
+        writeln!(f, "RECORD {:?}", self.record.text)?;
+        self.trace.print(f, indent + 1)?;
+        idt(f, indent)?;
+        if self.kept {
+            writeln!(f, "KEPT")?;
+            let mut variables: Vec<_> = self.record.variables.iter().collect();
+            variables.sort();
+            for (key, value) in variables {
+                idt(f, indent + 1)?;
+                writeln!(f, "{} = {:?}", key, value)?;
+            }
+        } else {
+            writeln!(f, "SKIPPED")?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.print(f, 0)
+    }
+}
+
+/// Run a view on a literal record text, tracing the evaluation.
+///
+/// The record is evaluated on its own, so `LastVarValue` expressions only
+/// see values set earlier in the same record.
+///
+/// Tests on constants are decided when the view is compiled, so they show as
+/// setting the captured variables.
+pub fn explain_text(view: &View, text: String) -> Result<Explanation, BlockError> {
+    let program = Program::compile(view)?;
+    let mut filter = FilterInner::traced(&program);
+    Ok(trace_record(&program, &mut filter, RawRecord::new(text)))
+}
+
+/// Run a view on a record as read, with variables set by the reader.
+fn trace_record(program: &Program, filter: &mut FilterInner, raw: RawRecord) -> Explanation {
+    filter.trace = Some(Default::default());
+    let mut record = filter.start(program, raw);
+    let kept = filter.evaluate(program, &mut record);
+    Explanation {
+        record: filter.finish(program, record),
+        kept,
+        trace: filter.trace.take().unwrap_or_default(),
+    }
+}
+
+/// Run a view on the record at the given offset, tracing the evaluation.
+///
+/// The records before it are evaluated first, without tracing, so that what
+/// carries over from one record to the next (last values, deduplication,
+/// headers of parsed formats, clusters) is the same as when processing the
+/// whole log, including the runs of duplicates of CollapseRepeats. The log is
+/// read from the start, which can take a while.
+pub fn explain<R: LogReader>(
+    reader: &mut R,
+    view: &View,
+    offset: u64,
+) -> Result<Option<Explanation>, IoError> {
+    let program = Program::compile(view)
+        .map_err(|e| IoError::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut filter = FilterInner::new(&program);
+    reader.seek(0)?;
+    while reader.tell() < offset {
+        let raw = match reader.read_raw_record()? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let mut record = filter.start(&program, raw);
+        if filter.evaluate(&program, &mut record) {
+            filter.finish(&program, record);
+        } else {
+            filter.discard(record);
+        }
+    }
+    // The offset was not the start of a record
+    if reader.tell() != offset {
+        reader.seek(offset)?;
+    }
+    match reader.read_raw_record()? {
+        Some(raw) => Ok(Some(trace_record(&program, &mut filter, raw))),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,221 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt::Debug;
+use std::io::{Error as IoError};
+
+#[cfg(feature = "json")]
+use serde_derive::{Serialize, Deserialize};
+
+use crate::Record;
+
+fn default_top() -> usize {
+    10
+}
+
+fn default_capacity() -> usize {
+    1000
+}
+
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
+pub struct FieldsOptions {
+    /// Number of most frequent values to report for each variable
+    #[cfg_attr(feature = "json", serde(default = "default_top"))]
+    pub top: usize,
+    /// Maximum number of distinct values tracked for each variable; counts
+    /// are approximate once a variable has more values than this
+    #[cfg_attr(feature = "json", serde(default = "default_capacity"))]
+    pub capacity: usize,
+}
+
+impl Default for FieldsOptions {
+    fn default() -> FieldsOptions {
+        FieldsOptions {
+            top: default_top(),
+            capacity: default_capacity(),
+        }
+    }
+}
+
+/// Approximate counts of the most frequent values, with bounded memory.
+///
+/// This is the Space-Saving algorithm: when a new value arrives and the table
+/// is full, it replaces the value with the lowest count, inheriting that
+/// count as its possible overestimation.
+///
+/// The lowest count is found with a min-heap whose entries are updated
+/// lazily: an entry that is out of date when it reaches the top is pushed
+/// back with the current count.
+struct TopK {
+    capacity: usize,
+    /// Value -> index in `slots`
+    counts: HashMap<String, usize>,
+    /// (value, count, error)
+    slots: Vec<(String, u64, u64)>,
+    /// (count, index in `slots`), with possibly lower counts than current
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+}
+
+impl TopK {
+    fn new(capacity: usize) -> TopK {
+        TopK {
+            capacity: capacity.max(1),
+            counts: HashMap::new(),
+            slots: Vec::new(),
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    fn add(&mut self, value: &str) {
+        if let Some(&slot) = self.counts.get(value) {
+            self.slots[slot].1 += 1;
+        } else if self.slots.len() < self.capacity {
+            self.counts.insert(value.to_owned(), self.slots.len());
+            self.heap.push(Reverse((1, self.slots.len())));
+            self.slots.push((value.to_owned(), 1, 0));
+        } else {
+            loop {
+                let Reverse((count, slot)) = self.heap.pop().unwrap();
+                let entry = &mut self.slots[slot];
+                if entry.1 != count {
+                    self.heap.push(Reverse((entry.1, slot)));
+                    continue;
+                }
+                self.counts.remove(&entry.0);
+                *entry = (value.to_owned(), count + 1, count);
+                self.counts.insert(value.to_owned(), slot);
+                self.heap.push(Reverse((count + 1, slot)));
+                break;
+            }
+        }
+    }
+
+    fn top(&self, n: usize) -> Vec<ValueCount> {
+        let mut values: Vec<ValueCount> = self.slots.iter()
+            .map(|(value, count, error)| ValueCount {
+                value: value.clone(),
+                count: *count,
+                error: *error,
+            })
+            .collect();
+        values.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+        values.truncate(n);
+        values
+    }
+}
+
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct ValueCount {
+    pub value: String,
+    pub count: u64,
+    /// Maximum overestimation of `count`
+    pub error: u64,
+}
+
+#[cfg_attr(feature = "json", derive(Serialize), serde(rename_all = "camelCase"))]
+pub struct FieldSummary {
+    pub name: String,
+    /// Number of records where the variable is set
+    pub present: u64,
+    /// Whether more distinct values were seen than could be tracked
+    pub approximate: bool,
+    pub top_values: Vec<ValueCount>,
+}
+
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct FieldsResult {
+    /// Total number of records
+    pub records: u64,
+    pub fields: Vec<FieldSummary>,
+}
+
+/// Streaming summary of the variables set on records.
+pub struct FieldsSummarizer<'a> {
+    options: &'a FieldsOptions,
+    records: u64,
+    fields: BTreeMap<String, (u64, TopK, bool)>,
+}
+
+impl<'a> FieldsSummarizer<'a> {
+    pub fn new(options: &'a FieldsOptions) -> FieldsSummarizer<'a> {
+        FieldsSummarizer {
+            options,
+            records: 0,
+            fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, record: &Record) {
+        self.records += 1;
+        for (key, value) in &record.variables {
+            let capacity = self.options.capacity;
+            let (present, top, overflow) = self.fields.entry(key.clone())
+                .or_insert_with(|| (0, TopK::new(capacity), false));
+            *present += 1;
+            if !*overflow && top.counts.len() == top.capacity
+                && !top.counts.contains_key(value)
+            {
+                *overflow = true;
+            }
+            top.add(value);
+        }
+    }
+
+    pub fn finish(self) -> FieldsResult {
+        let top = self.options.top;
+        FieldsResult {
+            records: self.records,
+            fields: self.fields.into_iter()
+                .map(|(name, (present, values, approximate))| FieldSummary {
+                    name,
+                    present,
+                    approximate,
+                    top_values: values.top(top),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl FieldsResult {
+    pub fn print(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} records", self.records)?;
+        for field in &self.fields {
+            let percent = if self.records > 0 {
+                field.present as f64 * 100.0 / self.records as f64
+            } else {
+                0.0
+            };
+            writeln!(
+                f,
+                "{}: present in {} records ({:.1}%){}",
+                field.name,
+                field.present,
+                percent,
+                if field.approximate { ", approximate counts" } else { "" },
+            )?;
+            for value in &field.top_values {
+                writeln!(f, "  {:>8}  {:?}", value.count, value.value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for FieldsResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.print(f)
+    }
+}
+
+/// Summarize the variables of processed records, for example from
+/// `process()`.
+pub fn fields<I: Iterator<Item = Result<Record, IoError>>>(
+    records: I,
+    options: &FieldsOptions,
+) -> Result<FieldsResult, IoError> {
+    let mut summarizer = FieldsSummarizer::new(options);
+    for record in records {
+        summarizer.add(&record?);
+    }
+    Ok(summarizer.finish())
+}
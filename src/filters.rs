@@ -1,9 +1,17 @@
+#![allow(clippy::write_with_newline)]
+
 use regex::Regex;
 #[cfg(feature = "json")]
 use serde_derive::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::Debug;
+#[cfg(feature = "json")]
+use std::path::{Path, PathBuf};
+
+use crate::parsers;
+use crate::patterns;
 
+#[derive(Clone)]
 #[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
 pub enum Operation {
     If {
@@ -19,8 +27,104 @@ pub enum Operation {
     },
     ColorBy(Expression),
     SkipRecord,
+    /// Remove a variable from the record
+    Unset {
+        target: String,
+    },
+    /// Run the operations of a named block of the view
+    Call(String),
+    /// Group values into templates, setting the target to the template ID
+    Cluster {
+        expression: Expression,
+        target: String,
+    },
+    /// Skip records whose key was already seen within the window
+    Dedup {
+        key: Expression,
+        window: DedupWindow,
+    },
+    /// Skip consecutive records with the same key, setting the count variable
+    /// of the first one to the number of records
+    CollapseRepeats {
+        key: Expression,
+        count: String,
+    },
+    /// Parse structured data, setting a variable for each field
+    Parse {
+        expression: Expression,
+        format: ParseFormat,
+        /// Prefix added to the variable names
+        #[cfg_attr(feature = "json", serde(default))]
+        prefix: String,
+        /// Operations to run if parsing fails
+        #[cfg_attr(feature = "json", serde(default, rename = "else"))]
+        else_ops: Vec<Operation>,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
+pub enum ParseFormat {
+    /// JSON object, nested keys are flattened into dotted names
+    Json,
+    /// Space-separated `key=value` pairs, with quoted values and flags
+    Logfmt,
+    /// `key=value` pairs anywhere in the text
+    KeyValue,
+    /// Apache common log format, setting `client`, `ident`, `user`, `time`,
+    /// `request`, `method`, `path`, `protocol`, `status` and `bytes`
+    ApacheCommon,
+    /// Apache combined log format, also setting `referer` and `user_agent`
+    ApacheCombined,
+    /// Nginx default format, combined with an optional `forwarded_for`
+    Nginx,
+    /// BSD syslog, setting `priority`, `facility`, `severity`, `time`,
+    /// `host`, `app`, `pid` and `message`
+    SyslogRfc3164,
+    /// Syslog, also setting `version`, `msgid` and `structured_data`
+    SyslogRfc5424,
+    /// W3C extended log format (IIS), using the last `#Fields` directive
+    W3c,
+    /// Delimiter-separated values, with RFC 4180 quoting. Variables are
+    /// named after the columns, or after the header row if no columns are
+    /// given (the header row itself fails to parse). Use `CsvReader` to read
+    /// quoted fields spanning multiple lines.
+    Csv {
+        #[cfg_attr(feature = "json", serde(default = "default_delimiter"))]
+        delimiter: char,
+        #[cfg_attr(feature = "json", serde(default))]
+        columns: Vec<String>,
+    },
+}
+
+#[cfg(feature = "json")]
+fn default_delimiter() -> char {
+    ','
+}
+
+/// State kept by a parse operation between records.
+///
+/// The W3C format uses it to remember the `#Fields` directive, and the CSV
+/// format to remember the header row.
+#[derive(Default)]
+pub struct ParseState {
+    w3c_fields: Option<Vec<String>>,
+    csv_header: Option<Vec<String>>,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
+pub enum DedupWindow {
+    /// Number of records that reached the operation
+    Records(usize),
+    /// Number of seconds, using the timestamp from an expression
+    Seconds {
+        seconds: f64,
+        time: Expression,
+    },
 }
 
+#[derive(Clone)]
 #[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
 pub enum Expression {
     Record,
@@ -29,6 +133,7 @@ pub enum Expression {
     Constant(String),
 }
 
+#[derive(Clone)]
 #[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
 pub enum Condition {
     Match {
@@ -37,11 +142,12 @@ pub enum Condition {
     },
 }
 
+#[derive(Clone)]
 pub struct Pattern {
     pub regex: String,
     pub compiled: Regex,
     pub groups: Vec<String>,
-    all_groups: Vec<Option<String>>,
+    pub(crate) all_groups: Vec<Option<String>>,
 }
 
 #[cfg(feature = "json")]
@@ -85,18 +191,171 @@ impl<'d> serde::de::Deserialize<'d> for Pattern {
         }
 
         let regex = deserializer.deserialize_string(RegexVisitor)?;
-        Ok(Pattern::new(regex))
+        // Named patterns from the view are not known yet, they get resolved
+        // once the whole view is read
+        let expanded = patterns::expand(&regex, &BTreeMap::new(), false)
+            .map_err(serde::de::Error::custom)?;
+        Pattern::compile(regex, &expanded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Error building a pattern.
+#[derive(Debug)]
+pub enum PatternError {
+    /// Reference to a named pattern that doesn't exist
+    UnknownPattern(String),
+    /// Named pattern that references itself
+    Recursive(String),
+    Regex(regex::Error),
+}
+
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PatternError::UnknownPattern(name) => write!(f, "Unknown pattern %{{{}}}", name),
+            PatternError::Recursive(name) => write!(f, "Pattern %{{{}}} references itself", name),
+            PatternError::Regex(e) => write!(f, "Invalid regex: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// Limit on the size of `View::flatten`, as blocks calling other blocks
+/// several times grow exponentially.
+pub const MAX_FLATTENED_OPERATIONS: usize = 100_000;
+
+/// Error using the named blocks of a view.
+#[derive(Debug)]
+pub enum BlockError {
+    /// Call to a block that doesn't exist
+    UnknownBlock(String),
+    /// Block that calls itself, directly or through other blocks
+    Recursive(String),
+    /// Flattening the view takes more than `MAX_FLATTENED_OPERATIONS`
+    TooLarge,
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::UnknownBlock(name) => write!(f, "Unknown block {}", name),
+            BlockError::Recursive(name) => write!(f, "Block {} calls itself", name),
+            BlockError::TooLarge => write!(
+                f,
+                "The view has more than {} operations once flattened",
+                MAX_FLATTENED_OPERATIONS,
+            ),
+        }
     }
 }
 
-#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+impl std::error::Error for BlockError {}
+
+/// Error reading a view from a file.
+#[cfg(feature = "json")]
+#[derive(Debug)]
+pub enum LoadError {
+    Io(PathBuf, std::io::Error),
+    Json(PathBuf, serde_json::Error),
+    /// Included file that is not found in the search path
+    NotFound(String),
+    /// File that includes itself, directly or through other files
+    Cycle(PathBuf),
+}
+
+#[cfg(feature = "json")]
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::Io(path, e) => write!(f, "Can't read {}: {}", path.display(), e),
+            LoadError::Json(path, e) => write!(f, "Invalid view {}: {}", path.display(), e),
+            LoadError::NotFound(name) => write!(f, "Included view {} not found", name),
+            LoadError::Cycle(path) => write!(f, "View {} includes itself", path.display()),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl std::error::Error for LoadError {}
+
+#[derive(Default)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(try_from = "ViewDefinition"))]
 pub struct View {
+    /// Named patterns, usable as `%{NAME}` in the patterns of the view
+    #[cfg_attr(feature = "json", serde(skip_serializing_if = "BTreeMap::is_empty"))]
+    pub patterns: BTreeMap<String, String>,
+    /// Variables that can be used by the view but are not output
+    #[cfg_attr(feature = "json", serde(skip_serializing_if = "BTreeSet::is_empty"))]
+    pub locals: BTreeSet<String>,
+    /// Variables whose values are not remembered for `LastVarValue`
+    #[cfg_attr(feature = "json", serde(skip_serializing_if = "BTreeSet::is_empty"))]
+    pub untracked: BTreeSet<String>,
+    /// Other view files whose blocks are made available, resolved when the
+    /// view is loaded with `View::load`
+    #[cfg_attr(feature = "json", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub includes: Vec<String>,
+    /// Named lists of operations, run with `Operation::Call`
+    #[cfg_attr(feature = "json", serde(skip_serializing_if = "BTreeMap::is_empty"))]
+    pub blocks: BTreeMap<String, Vec<Operation>>,
     pub operations: Vec<Operation>,
 }
 
+/// A view as read, before its patterns are resolved.
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+struct ViewDefinition {
+    #[serde(default)]
+    patterns: BTreeMap<String, String>,
+    #[serde(default)]
+    locals: BTreeSet<String>,
+    #[serde(default)]
+    untracked: BTreeSet<String>,
+    #[serde(default)]
+    includes: Vec<String>,
+    #[serde(default)]
+    blocks: BTreeMap<String, Vec<Operation>>,
+    operations: Vec<Operation>,
+}
+
+#[cfg(feature = "json")]
+impl std::convert::TryFrom<ViewDefinition> for View {
+    type Error = PatternError;
+
+    fn try_from(definition: ViewDefinition) -> Result<View, PatternError> {
+        let mut view = View {
+            patterns: definition.patterns,
+            locals: definition.locals,
+            untracked: definition.untracked,
+            includes: definition.includes,
+            blocks: definition.blocks,
+            operations: definition.operations,
+        };
+        view.resolve_patterns()?;
+        Ok(view)
+    }
+}
+
 impl Pattern {
+    /// Build a pattern, expanding built-in named patterns.
+    ///
+    /// Panics if the regex is invalid.
     pub fn new(regex: String) -> Pattern {
-        let compiled = Regex::new(&regex).expect("Invalid regex");
+        Pattern::with_library(regex, &BTreeMap::new()).expect("Invalid regex")
+    }
+
+    /// Build a pattern, expanding named patterns from `library` and the
+    /// built-in ones.
+    pub fn with_library(
+        regex: String,
+        library: &BTreeMap<String, String>,
+    ) -> Result<Pattern, PatternError> {
+        let expanded = patterns::expand(&regex, library, true)?;
+        Pattern::compile(regex, &expanded)
+    }
+
+    fn compile(regex: String, expanded: &str) -> Result<Pattern, PatternError> {
+        let compiled = Regex::new(expanded).map_err(PatternError::Regex)?;
         let all_groups: Vec<Option<String>> = compiled
             .capture_names() // Option<&str>
             .map(|v: Option<&str>| v.map(ToOwned::to_owned)) // Option<String>
@@ -104,14 +363,15 @@ impl Pattern {
         let groups = all_groups.iter()
             .filter_map(|v| v.as_ref().cloned())
             .collect();
-        Pattern {
+        Ok(Pattern {
             regex,
             compiled,
             groups,
             all_groups,
-        }
+        })
     }
 
+    #[allow(clippy::ptr_arg, clippy::single_match)]
     pub fn match_string(&self, string: &String) -> Option<HashMap<String, String>> {
         match self.compiled.captures(string) {
             Some(m) => {
@@ -134,7 +394,7 @@ impl Pattern {
     }
 }
 
-fn idt(f: &mut std::fmt::Formatter, indent: usize) -> std::fmt::Result {
+pub(crate) fn idt(f: &mut std::fmt::Formatter, indent: usize) -> std::fmt::Result {
     for _ in 0..indent {
         write!(f, "  ")?;
     }
@@ -152,6 +412,68 @@ impl Expression {
     }
 }
 
+impl Debug for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.print(f)
+    }
+}
+
+impl Debug for ParseFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseFormat::Json => write!(f, "json"),
+            ParseFormat::Logfmt => write!(f, "logfmt"),
+            ParseFormat::KeyValue => write!(f, "key=value"),
+            ParseFormat::ApacheCommon => write!(f, "apache-common"),
+            ParseFormat::ApacheCombined => write!(f, "apache-combined"),
+            ParseFormat::Nginx => write!(f, "nginx"),
+            ParseFormat::SyslogRfc3164 => write!(f, "syslog-rfc3164"),
+            ParseFormat::SyslogRfc5424 => write!(f, "syslog-rfc5424"),
+            ParseFormat::W3c => write!(f, "w3c"),
+            ParseFormat::Csv { delimiter, columns } => {
+                match delimiter {
+                    ',' => write!(f, "csv")?,
+                    '\t' => write!(f, "tsv")?,
+                    d => write!(f, "csv DELIMITER {:?}", d)?,
+                }
+                if !columns.is_empty() {
+                    write!(f, " COLUMNS {}", columns.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ParseFormat {
+    /// Parse text into a list of fields, or `None` if it is not valid.
+    pub fn parse(&self, text: &str) -> Option<Vec<(String, String)>> {
+        self.parse_with_state(text, &mut Default::default())
+    }
+
+    /// Parse text, using and updating the state from previous records.
+    pub fn parse_with_state(
+        &self,
+        text: &str,
+        state: &mut ParseState,
+    ) -> Option<Vec<(String, String)>> {
+        match self {
+            ParseFormat::Json => parsers::parse_json(text),
+            ParseFormat::Logfmt => parsers::parse_logfmt(text),
+            ParseFormat::KeyValue => parsers::parse_key_value(text),
+            ParseFormat::ApacheCommon => parsers::parse_apache_common(text),
+            ParseFormat::ApacheCombined => parsers::parse_apache_combined(text),
+            ParseFormat::Nginx => parsers::parse_nginx(text),
+            ParseFormat::SyslogRfc3164 => parsers::parse_syslog_rfc3164(text),
+            ParseFormat::SyslogRfc5424 => parsers::parse_syslog_rfc5424(text),
+            ParseFormat::W3c => parsers::parse_w3c(text, &mut state.w3c_fields),
+            ParseFormat::Csv { delimiter, columns } => {
+                parsers::parse_csv(text, *delimiter, columns, &mut state.csv_header)
+            }
+        }
+    }
+}
+
 impl Operation {
     fn print_if_branch(
         &self,
@@ -225,17 +547,262 @@ impl Operation {
                 idt(f, indent)?;
                 write!(f, "SKIP\n")?;
             }
+            Operation::Unset { target } => {
+                idt(f, indent)?;
+                write!(f, "UNSET {}\n", target)?;
+            }
+            Operation::Call(block) => {
+                idt(f, indent)?;
+                write!(f, "CALL {}\n", block)?;
+            }
+            Operation::Cluster { expression, target } => {
+                idt(f, indent)?;
+                write!(f, "CLUSTER ")?;
+                expression.print(f)?;
+                write!(f, " INTO {}\n", target)?;
+            }
+            Operation::Dedup { key, window } => {
+                idt(f, indent)?;
+                write!(f, "DEDUP ")?;
+                key.print(f)?;
+                match window {
+                    DedupWindow::Records(size) => {
+                        write!(f, " WITHIN {} RECORDS\n", size)?;
+                    }
+                    DedupWindow::Seconds { seconds, time } => {
+                        write!(f, " WITHIN {} SECONDS OF ", seconds)?;
+                        time.print(f)?;
+                        write!(f, "\n")?;
+                    }
+                }
+            }
+            Operation::CollapseRepeats { key, count } => {
+                idt(f, indent)?;
+                write!(f, "COLLAPSE-REPEATS ")?;
+                key.print(f)?;
+                write!(f, " COUNT INTO {}\n", count)?;
+            }
+            Operation::Parse { expression, format, prefix, else_ops } => {
+                idt(f, indent)?;
+                write!(f, "PARSE ")?;
+                expression.print(f)?;
+                write!(f, " AS {:?}", format)?;
+                if !prefix.is_empty() {
+                    write!(f, " PREFIX {:?}", prefix)?;
+                }
+                write!(f, "\n")?;
+                if !else_ops.is_empty() {
+                    idt(f, indent)?;
+                    write!(f, "ELSE\n")?;
+                    for op in else_ops {
+                        op.print(f, indent + 1)?;
+                    }
+                }
+            }
         }
         Ok(())
     }
 }
 
+fn resolve_patterns(
+    operations: &mut [Operation],
+    library: &BTreeMap<String, String>,
+) -> Result<(), PatternError> {
+    for operation in operations {
+        match operation {
+            Operation::If { condition, then_ops, else_ops } => {
+                match condition {
+                    Condition::Match { pattern, .. } => {
+                        if pattern.regex.contains("%{") {
+                            *pattern = Pattern::with_library(pattern.regex.clone(), library)?;
+                        }
+                    }
+                }
+                resolve_patterns(then_ops, library)?;
+                resolve_patterns(else_ops, library)?;
+            }
+            Operation::Parse { else_ops, .. } => resolve_patterns(else_ops, library)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Replace calls by the operations of their block, `budget` being the number
+/// of operations that can still be visited.
+fn flatten(
+    operations: &[Operation],
+    blocks: &BTreeMap<String, Vec<Operation>>,
+    calling: &mut Vec<String>,
+    budget: &mut usize,
+) -> Result<Vec<Operation>, BlockError> {
+    let mut flat = Vec::new();
+    for operation in operations {
+        // Calls count too, as blocks can be empty
+        *budget = budget.checked_sub(1).ok_or(BlockError::TooLarge)?;
+        match operation {
+            Operation::Call(name) => {
+                if calling.contains(name) {
+                    return Err(BlockError::Recursive(name.clone()));
+                }
+                let block = blocks.get(name)
+                    .ok_or_else(|| BlockError::UnknownBlock(name.clone()))?;
+                calling.push(name.clone());
+                flat.extend(flatten(block, blocks, calling, budget)?);
+                calling.pop();
+            }
+            Operation::If { condition, then_ops, else_ops } => {
+                flat.push(Operation::If {
+                    condition: condition.clone(),
+                    then_ops: flatten(then_ops, blocks, calling, budget)?,
+                    else_ops: flatten(else_ops, blocks, calling, budget)?,
+                });
+            }
+            Operation::Parse { expression, format, prefix, else_ops } => {
+                flat.push(Operation::Parse {
+                    expression: expression.clone(),
+                    format: format.clone(),
+                    prefix: prefix.clone(),
+                    else_ops: flatten(else_ops, blocks, calling, budget)?,
+                });
+            }
+            operation => flat.push(operation.clone()),
+        }
+    }
+    Ok(flat)
+}
+
+/// Find an included file, relative to the directory of the including file
+/// then in the search path.
+#[cfg(feature = "json")]
+fn find_include(name: &str, base: Option<&Path>, search_path: &[PathBuf]) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path.is_absolute() {
+        return Some(path.to_owned()).filter(|p| p.is_file());
+    }
+    base.into_iter()
+        .chain(search_path.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(path))
+        .find(|p| p.is_file())
+}
+
+#[cfg(feature = "json")]
+fn load_view(
+    path: &Path,
+    search_path: &[PathBuf],
+    including: &mut Vec<PathBuf>,
+) -> Result<View, LoadError> {
+    let canonical = path.canonicalize().map_err(|e| LoadError::Io(path.to_owned(), e))?;
+    if including.contains(&canonical) {
+        return Err(LoadError::Cycle(path.to_owned()));
+    }
+    let file = std::fs::File::open(path).map_err(|e| LoadError::Io(path.to_owned(), e))?;
+    let mut view: View = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| LoadError::Json(path.to_owned(), e))?;
+    including.push(canonical);
+    view.include(path.parent(), search_path, including)?;
+    including.pop();
+    Ok(view)
+}
+
 impl View {
+    /// Rebuild the patterns of the view using its named patterns.
+    pub fn resolve_patterns(&mut self) -> Result<(), PatternError> {
+        resolve_patterns(&mut self.operations, &self.patterns)?;
+        for block in self.blocks.values_mut() {
+            resolve_patterns(block, &self.patterns)?;
+        }
+        Ok(())
+    }
+
+    /// Read a view from a JSON file, resolving its includes.
+    ///
+    /// Included files are looked up relative to the including file, then in
+    /// each directory of the search path. Their blocks, local and untracked
+    /// variables are added to the view, the first definition of a block
+    /// taking precedence. Their operations are not run, and named patterns
+    /// only apply to the file that defines them.
+    #[cfg(feature = "json")]
+    pub fn load(path: &Path, search_path: &[PathBuf]) -> Result<View, LoadError> {
+        load_view(path, search_path, &mut Vec::new())
+    }
+
+    /// Resolve the includes of a view that wasn't read from a file, using
+    /// only the search path.
+    #[cfg(feature = "json")]
+    pub fn resolve_includes(&mut self, search_path: &[PathBuf]) -> Result<(), LoadError> {
+        self.include(None, search_path, &mut Vec::new())
+    }
+
+    #[cfg(feature = "json")]
+    fn include(
+        &mut self,
+        base: Option<&Path>,
+        search_path: &[PathBuf],
+        including: &mut Vec<PathBuf>,
+    ) -> Result<(), LoadError> {
+        for name in std::mem::take(&mut self.includes) {
+            let path = find_include(&name, base, search_path)
+                .ok_or(LoadError::NotFound(name))?;
+            let included = load_view(&path, search_path, including)?;
+            for (block_name, block) in included.blocks {
+                self.blocks.entry(block_name).or_insert(block);
+            }
+            self.locals.extend(included.locals);
+            self.untracked.extend(included.untracked);
+        }
+        Ok(())
+    }
+
+    /// The view with its calls replaced by the operations of the blocks, and
+    /// no blocks.
+    pub fn flatten(&self) -> Result<View, BlockError> {
+        let mut budget = MAX_FLATTENED_OPERATIONS;
+        Ok(View {
+            patterns: self.patterns.clone(),
+            locals: self.locals.clone(),
+            untracked: self.untracked.clone(),
+            includes: self.includes.clone(),
+            blocks: BTreeMap::new(),
+            operations: flatten(
+                &self.operations,
+                &self.blocks,
+                &mut Vec::new(),
+                &mut budget,
+            )?,
+        })
+    }
+
     pub fn print(
         &self,
         f: &mut std::fmt::Formatter,
         indent: usize,
     ) -> std::fmt::Result {
+        for (name, regex) in &self.patterns {
+            idt(f, indent)?;
+            write!(f, "PATTERN {} = \"{}\"\n", name, regex)?;
+        }
+        if !self.locals.is_empty() {
+            idt(f, indent)?;
+            let locals: Vec<_> = self.locals.iter().cloned().collect();
+            write!(f, "LOCAL {}\n", locals.join(", "))?;
+        }
+        if !self.untracked.is_empty() {
+            idt(f, indent)?;
+            let untracked: Vec<_> = self.untracked.iter().cloned().collect();
+            write!(f, "UNTRACKED {}\n", untracked.join(", "))?;
+        }
+        for include in &self.includes {
+            idt(f, indent)?;
+            write!(f, "INCLUDE {:?}\n", include)?;
+        }
+        for (name, block) in &self.blocks {
+            idt(f, indent)?;
+            write!(f, "BLOCK {}\n", name)?;
+            for operation in block {
+                operation.print(f, indent + 1)?;
+            }
+        }
         for operation in &self.operations {
             operation.print(f, indent)?;
         }
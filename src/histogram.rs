@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::{Error as IoError};
+
+#[cfg(feature = "json")]
+use serde_derive::{Serialize, Deserialize};
+
+use crate::Record;
+use crate::timestamp::{format_timestamp, parse_timestamp};
+
+/// Bucket sizes to pick from, in seconds.
+const BUCKET_SIZES: &[i64] = &[
+    1, 2, 5, 10, 15, 30,
+    60, 2 * 60, 5 * 60, 10 * 60, 15 * 60, 30 * 60,
+    3600, 2 * 3600, 3 * 3600, 6 * 3600, 12 * 3600,
+    86400, 2 * 86400, 7 * 86400, 14 * 86400, 30 * 86400,
+];
+
+/// Sizes of the buckets used while counting, in seconds. Each one is a
+/// multiple of the previous one, so that buckets can be merged, and divides
+/// the bucket sizes that are multiples of it.
+const FINE_SIZES: &[i64] = &[
+    1, 2, 10, 30, 60, 2 * 60, 10 * 60, 30 * 60,
+    3600, 2 * 3600, 6 * 3600, 12 * 3600, 86400, 30 * 86400,
+];
+
+/// Number of buckets kept while counting, before merging them.
+const MAX_FINE_BUCKETS: usize = 10_000;
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+fn default_time_var() -> String {
+    "time".to_owned()
+}
+
+fn default_buckets() -> usize {
+    60
+}
+
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
+pub struct HistogramOptions {
+    /// Variable holding the timestamp of each record
+    #[cfg_attr(feature = "json", serde(default = "default_time_var"))]
+    pub time_var: String,
+    /// Variable whose values get a separate series each
+    #[cfg_attr(feature = "json", serde(default))]
+    pub split_by: Option<String>,
+    /// Target number of buckets, used to pick a bucket size
+    #[cfg_attr(feature = "json", serde(default = "default_buckets"))]
+    pub buckets: usize,
+}
+
+impl Default for HistogramOptions {
+    fn default() -> HistogramOptions {
+        HistogramOptions {
+            time_var: default_time_var(),
+            split_by: None,
+            buckets: default_buckets(),
+        }
+    }
+}
+
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Series {
+    pub key: Option<String>,
+    pub counts: Vec<u64>,
+}
+
+#[cfg_attr(feature = "json", derive(Serialize), serde(rename_all = "camelCase"))]
+pub struct HistogramResult {
+    /// Start of the first bucket, in seconds since the Unix epoch
+    pub start: i64,
+    /// Size of each bucket, in seconds
+    pub bucket_size: i64,
+    pub series: Vec<Series>,
+    /// Number of records that had no valid timestamp
+    pub no_timestamp: u64,
+}
+
+/// Pick the smallest bucket size that gives at most `target` buckets, and
+/// is a multiple of the size of the buckets that were counted.
+fn bucket_size(span: i64, target: usize, fine: i64) -> i64 {
+    let target = target.max(1) as i64;
+    for &size in BUCKET_SIZES {
+        if size % fine == 0 && span / size < target {
+            return size;
+        }
+    }
+    // Over a long period, use a multiple of the largest size
+    let largest = (*BUCKET_SIZES.last().unwrap()).max(fine);
+    (span / target / largest + 1) * largest
+}
+
+/// The next size of the buckets used while counting.
+fn coarser(fine: i64) -> i64 {
+    match FINE_SIZES.iter().find(|&&size| size > fine) {
+        Some(&size) => size,
+        None => fine * 2,
+    }
+}
+
+/// Count processed records over time, for example from `process()`.
+///
+/// Records are counted in buckets of a fixed size, which grows when there
+/// are too many, so memory doesn't depend on the number of records.
+pub fn histogram<I: Iterator<Item = Result<Record, IoError>>>(
+    records: I,
+    options: &HistogramOptions,
+) -> Result<HistogramResult, IoError> {
+    let mut keys: BTreeMap<Option<String>, usize> = BTreeMap::new();
+    let mut fine = FINE_SIZES[0];
+    // Counts by fine bucket, then by key
+    let mut fine_counts: BTreeMap<i64, Vec<u64>> = BTreeMap::new();
+    let mut range: Option<(i64, i64)> = None;
+    let mut no_timestamp = 0;
+    for record in records {
+        let record = record?;
+        let time = record.variables.get(&options.time_var)
+            .and_then(|t| parse_timestamp(t));
+        let time = match time {
+            Some(t) => t.floor() as i64,
+            None => {
+                no_timestamp += 1;
+                continue;
+            }
+        };
+        let key = options.split_by.as_ref()
+            .and_then(|var| record.variables.get(var).cloned());
+        let next_key = keys.len();
+        let key = *keys.entry(key).or_insert(next_key);
+        range = Some(match range {
+            Some((min, max)) => (min.min(time), max.max(time)),
+            None => (time, time),
+        });
+
+        let counts = fine_counts.entry(time.div_euclid(fine)).or_default();
+        if counts.len() <= key {
+            counts.resize(key + 1, 0);
+        }
+        counts[key] += 1;
+        if fine_counts.len() > MAX_FINE_BUCKETS {
+            let size = coarser(fine);
+            let mut merged: BTreeMap<i64, Vec<u64>> = BTreeMap::new();
+            for (bucket, counts) in fine_counts {
+                let target = merged.entry((bucket * fine).div_euclid(size)).or_default();
+                if target.len() < counts.len() {
+                    target.resize(counts.len(), 0);
+                }
+                for (total, count) in target.iter_mut().zip(counts) {
+                    *total += count;
+                }
+            }
+            fine_counts = merged;
+            fine = size;
+        }
+    }
+
+    let (min, max) = match range {
+        Some(range) => range,
+        None => {
+            return Ok(HistogramResult {
+                start: 0,
+                bucket_size: 1,
+                series: Vec::new(),
+                no_timestamp,
+            });
+        }
+    };
+    let bucket_size = bucket_size(max - min, options.buckets, fine);
+    let start = min.div_euclid(bucket_size) * bucket_size;
+    let nb_buckets = ((max - start) / bucket_size + 1) as usize;
+    let mut counts = vec![vec![0; nb_buckets]; keys.len()];
+    for (bucket, bucket_counts) in fine_counts {
+        let idx = ((bucket * fine - start) / bucket_size) as usize;
+        for (key, count) in bucket_counts.into_iter().enumerate() {
+            counts[key][idx] += count;
+        }
+    }
+    let series = keys.into_iter()
+        .map(|(key, idx)| Series {
+            key,
+            counts: std::mem::take(&mut counts[idx]),
+        })
+        .collect();
+    Ok(HistogramResult {
+        start,
+        bucket_size,
+        series,
+        no_timestamp,
+    })
+}
+
+impl HistogramResult {
+    pub fn totals(&self) -> Vec<u64> {
+        let mut totals = Vec::new();
+        for series in &self.series {
+            totals.resize(series.counts.len(), 0);
+            for (total, count) in totals.iter_mut().zip(&series.counts) {
+                *total += count;
+            }
+        }
+        totals
+    }
+
+    /// Print as a sparkline per series, followed by a bar chart of totals.
+    pub fn print(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const BAR_WIDTH: u64 = 50;
+
+        let totals = self.totals();
+        let max = totals.iter().cloned().max().unwrap_or(0);
+        if max == 0 {
+            return writeln!(f, "No records with a timestamp");
+        }
+        writeln!(
+            f,
+            "{} buckets of {}s from {}",
+            totals.len(),
+            self.bucket_size,
+            format_timestamp(self.start),
+        )?;
+
+        // Sparklines
+        let names: Vec<&str> = self.series.iter()
+            .map(|s| s.key.as_deref().unwrap_or("-"))
+            .collect();
+        let width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0);
+        for (series, name) in self.series.iter().zip(names) {
+            write!(f, "{:<width$}  ", name, width = width)?;
+            for &count in &series.counts {
+                if count == 0 {
+                    write!(f, " ")?;
+                } else {
+                    let level = (count * SPARKS.len() as u64).div_ceil(max) - 1;
+                    write!(f, "{}", SPARKS[level as usize])?;
+                }
+            }
+            writeln!(f, "  {}", series.counts.iter().sum::<u64>())?;
+        }
+        writeln!(f)?;
+
+        // Bar chart
+        for (i, &total) in totals.iter().enumerate() {
+            let bar = (total * BAR_WIDTH).div_ceil(max);
+            writeln!(
+                f,
+                "{}  {:<width$}  {}",
+                format_timestamp(self.start + i as i64 * self.bucket_size),
+                "#".repeat(bar as usize),
+                total,
+                width = BAR_WIDTH as usize,
+            )?;
+        }
+        if self.no_timestamp > 0 {
+            writeln!(f, "{} records without a timestamp", self.no_timestamp)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for HistogramResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.print(f)
+    }
+}
@@ -1,6 +1,24 @@
+pub mod aggregate;
+pub mod check;
+pub mod cluster;
+mod context;
+mod dedup;
+mod encoding;
+pub mod explain;
+pub mod fields;
 pub mod filters;
+pub mod histogram;
+mod parsers;
+mod patterns;
+mod prefilter;
 mod process;
+mod program;
 pub mod readers;
+#[cfg(feature = "json")]
+pub mod store;
+pub mod suggest;
+mod timestamp;
+mod variables;
 #[cfg(feature = "web")]
 pub mod web;
 
@@ -9,6 +27,7 @@ mod tests;
 
 use std::collections::HashMap;
 
+pub use context::{ContextItem, ContextLogIterator};
 pub use process::{FilteredLogIterator, process};
 
 #[cfg_attr(feature = "json", derive(serde_derive::Serialize))]
@@ -30,6 +49,15 @@ pub struct Record {
     pub text: String,
     pub variables: HashMap<String, String>,
     pub color: Color,
+    /// Whether this record was skipped, and is only shown as context
+    #[cfg_attr(feature = "json", serde(skip_serializing_if = "std::ops::Not::not"))]
+    pub context: bool,
+    /// Whether invalid input was replaced when decoding the text
+    #[cfg_attr(feature = "json", serde(skip_serializing_if = "std::ops::Not::not"))]
+    pub lossy: bool,
+    /// Whether the text was cut at the maximum record size
+    #[cfg_attr(feature = "json", serde(skip_serializing_if = "std::ops::Not::not"))]
+    pub truncated: bool,
 }
 
 impl Record {
@@ -38,6 +66,9 @@ impl Record {
             text,
             variables: HashMap::new(),
             color: Color::Default,
+            context: false,
+            lossy: false,
+            truncated: false,
         }
     }
 }
@@ -0,0 +1,416 @@
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Parse a JSON object, flattening nested keys into dotted names.
+#[cfg(feature = "json")]
+pub(crate) fn parse_json(text: &str) -> Option<Vec<(String, String)>> {
+    use serde_json::Value;
+
+    fn flatten(prefix: String, value: Value, fields: &mut Vec<(String, String)>) {
+        let join = |key: &str| {
+            if prefix.is_empty() { key.to_owned() } else { format!("{}.{}", prefix, key) }
+        };
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    flatten(join(&key), value, fields);
+                }
+            }
+            Value::Array(array) => {
+                for (i, value) in array.into_iter().enumerate() {
+                    flatten(join(&i.to_string()), value, fields);
+                }
+            }
+            Value::String(s) => fields.push((prefix, s)),
+            Value::Null => fields.push((prefix, String::new())),
+            other => fields.push((prefix, other.to_string())),
+        }
+    }
+
+    match serde_json::from_str(text) {
+        Ok(value @ Value::Object(_)) => {
+            let mut fields = Vec::new();
+            flatten(String::new(), value, &mut fields);
+            Some(fields)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "json"))]
+pub(crate) fn parse_json(_text: &str) -> Option<Vec<(String, String)>> {
+    None
+}
+
+/// Read a value that might be quoted with `"` or `'`, handling backslash
+/// escapes. Unquoted values end at whitespace or one of the separators.
+/// Returns the value and the remaining text.
+fn read_value<'a>(text: &'a str, separators: &[char]) -> Option<(String, &'a str)> {
+    let mut chars = text.char_indices();
+    match chars.next() {
+        Some((_, quote)) if quote == '"' || quote == '\'' => {
+            let mut value = String::new();
+            let mut escaped = false;
+            for (i, c) in chars {
+                if escaped {
+                    value.push(match c {
+                        'n' => '\n',
+                        't' => '\t',
+                        c => c,
+                    });
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == quote {
+                    return Some((value, &text[i + 1..]));
+                } else {
+                    value.push(c);
+                }
+            }
+            // Unterminated quote
+            None
+        }
+        _ => {
+            let end = text.find(|c: char| c.is_whitespace() || separators.contains(&c))
+                .unwrap_or(text.len());
+            Some((text[..end].to_owned(), &text[end..]))
+        }
+    }
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '-' || c == '/' || c == '@'
+}
+
+/// Parse logfmt: space-separated `key=value` pairs, where values can be
+/// quoted and keys without a value are flags set to `true`.
+pub(crate) fn parse_logfmt(text: &str) -> Option<Vec<(String, String)>> {
+    let mut fields = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let key_end = rest.find(|c| !is_key_char(c)).unwrap_or(rest.len());
+        if key_end == 0 {
+            return None;
+        }
+        let key = rest[..key_end].to_owned();
+        rest = &rest[key_end..];
+        if let Some(after) = rest.strip_prefix('=') {
+            let (value, after) = read_value(after, &[])?;
+            fields.push((key, value));
+            rest = after;
+        } else {
+            fields.push((key, "true".to_owned()));
+        }
+        // Pairs have to be separated by whitespace
+        let trimmed = rest.trim_start();
+        if trimmed.len() == rest.len() && !rest.is_empty() {
+            return None;
+        }
+        rest = trimmed;
+    }
+    if fields.is_empty() { None } else { Some(fields) }
+}
+
+/// Find `key=value` pairs anywhere in the text, ignoring anything else.
+/// Pairs can be separated by whitespace, commas or semicolons, and values
+/// can be quoted.
+pub(crate) fn parse_key_value(text: &str) -> Option<Vec<(String, String)>> {
+    let mut fields = Vec::new();
+    let mut rest = text;
+    while let Some(eq) = rest.find('=') {
+        let key_start = rest[..eq]
+            .rfind(|c| !is_key_char(c))
+            .map(|i| i + rest[i..].chars().next().unwrap().len_utf8())
+            .unwrap_or(0);
+        let key = &rest[key_start..eq];
+        let after = &rest[eq + 1..];
+        if key.is_empty() {
+            rest = after;
+            continue;
+        }
+        match read_value(after, &[',', ';']) {
+            Some((value, remaining)) => {
+                fields.push((key.to_owned(), value));
+                rest = remaining;
+            }
+            None => break,
+        }
+    }
+    if fields.is_empty() { None } else { Some(fields) }
+}
+
+fn server_regexes() -> &'static ServerRegexes {
+    static REGEXES: OnceLock<ServerRegexes> = OnceLock::new();
+    REGEXES.get_or_init(ServerRegexes::new)
+}
+
+/// Compiled regexes for the server formats, shared by all operations.
+struct ServerRegexes {
+    common: Regex,
+    combined: Regex,
+    nginx: Regex,
+    request: Regex,
+    syslog3164: Regex,
+    syslog5424: Regex,
+}
+
+const COMMON: &str = r#"^(?P<client>\S+) (?P<ident>\S+) (?P<user>\S+) \[(?P<time>[^\]]+)\] "(?P<request>(?:[^"\\]|\\.)*)" (?P<status>\d{3}|-) (?P<bytes>\d+|-)"#;
+const REFERER_UA: &str = r#" "(?P<referer>(?:[^"\\]|\\.)*)" "(?P<user_agent>(?:[^"\\]|\\.)*)""#;
+
+impl ServerRegexes {
+    fn new() -> ServerRegexes {
+        ServerRegexes {
+            common: Regex::new(&format!("{}$", COMMON)).unwrap(),
+            combined: Regex::new(&format!("{}{}$", COMMON, REFERER_UA)).unwrap(),
+            nginx: Regex::new(&format!(
+                r#"{}{}(?: "(?P<forwarded_for>(?:[^"\\]|\\.)*)")?$"#,
+                COMMON, REFERER_UA,
+            )).unwrap(),
+            request: Regex::new(r"^(?P<method>[A-Z]+) (?P<path>\S+)(?: (?P<protocol>\S+))?$").unwrap(),
+            syslog3164: Regex::new(
+                r"^(?:<(?P<priority>\d{1,3})>)?(?P<time>[A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}) (?P<host>\S+) (?P<app>[^:\[\s]+)(?:\[(?P<pid>[^\]]+)\])?: ?(?P<message>.*)$"
+            ).unwrap(),
+            syslog5424: Regex::new(
+                r"^<(?P<priority>\d{1,3})>(?P<version>\d{1,2}) (?P<time>\S+) (?P<host>\S+) (?P<app>\S+) (?P<pid>\S+) (?P<msgid>\S+) (?P<structured_data>-|(?:\[(?:[^\]\\]|\\.)*\])+)(?: \x{feff}?(?P<message>.*))?$"
+            ).unwrap(),
+        }
+    }
+}
+
+/// Collect the named groups of a regex match, leaving out `-` which these
+/// formats use for missing values.
+fn captures(regex: &Regex, text: &str) -> Option<Vec<(String, String)>> {
+    let captures = regex.captures(text)?;
+    Some(
+        regex.capture_names()
+            .flatten()
+            .filter_map(|name| {
+                let value = captures.name(name)?.as_str();
+                if value == "-" {
+                    None
+                } else {
+                    Some((name.to_owned(), value.to_owned()))
+                }
+            })
+            .collect()
+    )
+}
+
+/// Parse an HTTP access log line, also splitting the request line.
+fn parse_access(regex: &Regex, text: &str) -> Option<Vec<(String, String)>> {
+    let mut fields = captures(regex, text)?;
+    let request = fields.iter().find(|(k, _)| k == "request").map(|(_, v)| v.clone());
+    if let Some(request) = request {
+        if let Some(request_fields) = captures(&server_regexes().request, &request) {
+            fields.extend(request_fields);
+        }
+    }
+    Some(fields)
+}
+
+pub(crate) fn parse_apache_common(text: &str) -> Option<Vec<(String, String)>> {
+    parse_access(&server_regexes().common, text)
+}
+
+pub(crate) fn parse_apache_combined(text: &str) -> Option<Vec<(String, String)>> {
+    parse_access(&server_regexes().combined, text)
+}
+
+/// Nginx's default format is Apache's combined, often with the
+/// `X-Forwarded-For` header appended.
+pub(crate) fn parse_nginx(text: &str) -> Option<Vec<(String, String)>> {
+    parse_access(&server_regexes().nginx, text)
+}
+
+/// Split the priority into facility and severity.
+fn add_priority(fields: &mut Vec<(String, String)>) {
+    let priority = fields.iter()
+        .find(|(k, _)| k == "priority")
+        .and_then(|(_, v)| v.parse::<u32>().ok());
+    if let Some(priority) = priority {
+        fields.push(("facility".to_owned(), (priority / 8).to_string()));
+        fields.push(("severity".to_owned(), (priority % 8).to_string()));
+    }
+}
+
+/// BSD syslog (RFC 3164): `<34>Oct 11 22:14:15 host app[123]: message`
+pub(crate) fn parse_syslog_rfc3164(text: &str) -> Option<Vec<(String, String)>> {
+    let mut fields = captures(&server_regexes().syslog3164, text)?;
+    add_priority(&mut fields);
+    Some(fields)
+}
+
+/// Syslog (RFC 5424):
+/// `<165>1 2003-10-11T22:14:15.003Z host app 123 ID47 [sd] message`
+pub(crate) fn parse_syslog_rfc5424(text: &str) -> Option<Vec<(String, String)>> {
+    let mut fields = captures(&server_regexes().syslog5424, text)?;
+    add_priority(&mut fields);
+    Some(fields)
+}
+
+/// Fields used by IIS when no `#Fields` directive was seen.
+const W3C_DEFAULT_FIELDS: &[&str] = &[
+    "date", "time", "s-ip", "cs-method", "cs-uri-stem", "cs-uri-query",
+    "s-port", "cs-username", "c-ip", "cs(User-Agent)", "cs(Referer)",
+    "sc-status", "sc-substatus", "sc-win32-status", "time-taken",
+];
+
+/// Standard variable name for a W3C field.
+fn w3c_variable(field: &str) -> String {
+    let name = match field {
+        "c-ip" => "client",
+        "cs-method" => "method",
+        "cs-uri-stem" => "path",
+        "cs-uri-query" => "query",
+        "cs-version" => "protocol",
+        "cs-username" => "user",
+        "sc-status" => "status",
+        "sc-bytes" => "bytes",
+        "cs(Referer)" | "cs(Referrer)" => "referer",
+        "cs(User-Agent)" => "user_agent",
+        "cs-host" => "vhost",
+        _ => {
+            return field.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+                .collect::<String>()
+                .trim_matches('_')
+                .to_owned();
+        }
+    };
+    name.to_owned()
+}
+
+/// W3C extended log format, used by IIS. Directive lines starting with `#`
+/// don't parse, but `#Fields` sets the fields of the following lines.
+pub(crate) fn parse_w3c(
+    text: &str,
+    fields_directive: &mut Option<Vec<String>>,
+) -> Option<Vec<(String, String)>> {
+    if let Some(directive) = text.strip_prefix('#') {
+        if let Some(names) = directive.strip_prefix("Fields:") {
+            *fields_directive = Some(names.split_whitespace().map(ToOwned::to_owned).collect());
+        }
+        return None;
+    }
+    let default_names;
+    let names: &[String] = match fields_directive {
+        Some(names) => names,
+        None => {
+            default_names = W3C_DEFAULT_FIELDS.iter()
+                .map(|&n| n.to_owned())
+                .collect::<Vec<_>>();
+            &default_names
+        }
+    };
+    let values: Vec<&str> = text.split_whitespace().collect();
+    if values.len() != names.len() {
+        return None;
+    }
+    let mut fields = Vec::with_capacity(values.len() + 1);
+    let mut date = None;
+    let mut time = None;
+    for (name, value) in names.iter().zip(values) {
+        match name.as_str() {
+            "date" => date = Some(value),
+            "time" => time = Some(value),
+            _ => {}
+        }
+        if value != "-" {
+            // Spaces are encoded as '+'
+            let value = if name.starts_with("cs(") { value.replace('+', " ") } else { value.to_owned() };
+            fields.push((w3c_variable(name), value));
+        }
+    }
+    // Timestamps are UTC, combine date and time into a standard timestamp
+    if let (Some(date), Some(time)) = (date, time) {
+        fields.retain(|(k, _)| k != "date" && k != "time");
+        fields.push(("time".to_owned(), format!("{}T{}Z", date, time)));
+    }
+    Some(fields)
+}
+
+/// Split a delimiter-separated record into fields, following RFC 4180:
+/// fields can be quoted with `"`, with `""` for a literal quote, and quoted
+/// fields can contain the delimiter and newlines.
+pub(crate) fn split_csv(text: &str, delimiter: char) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    // Unterminated quote
+                    None => return None,
+                }
+            }
+            match chars.next() {
+                None => {
+                    fields.push(field);
+                    return Some(fields);
+                }
+                Some(c) if c == delimiter => fields.push(field),
+                // Text after the closing quote
+                Some(_) => return None,
+            }
+        } else {
+            loop {
+                match chars.next() {
+                    Some(c) if c == delimiter => {
+                        fields.push(field);
+                        break;
+                    }
+                    Some(c) => field.push(c),
+                    None => {
+                        fields.push(field);
+                        return Some(fields);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parse a delimiter-separated record, naming fields after `columns`, or
+/// after the header row if `columns` is empty. The header row itself
+/// doesn't parse. Values beyond the known columns are named by their
+/// position, starting at 1.
+pub(crate) fn parse_csv(
+    text: &str,
+    delimiter: char,
+    columns: &[String],
+    header: &mut Option<Vec<String>>,
+) -> Option<Vec<(String, String)>> {
+    let values = split_csv(text, delimiter)?;
+    let names = if !columns.is_empty() {
+        columns
+    } else {
+        match header {
+            Some(header) if *header != values => header,
+            // This is the header row, or a repeat of it
+            _ => {
+                *header = Some(values);
+                return None;
+            }
+        }
+    };
+    Some(
+        values.into_iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let name = match names.get(i) {
+                    Some(name) => name.clone(),
+                    None => (i + 1).to_string(),
+                };
+                (name, value)
+            })
+            .collect()
+    )
+}
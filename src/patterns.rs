@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use crate::filters::PatternError;
+
+/// Built-in named patterns, usable as `%{NAME}` or `%{NAME:variable}`.
+///
+/// Definitions can reference each other, and only use non-capturing groups
+/// so that the only captures are the ones named in the pattern.
+const BUILTIN: &[(&str, &str)] = &[
+    ("USERNAME", r"[a-zA-Z0-9._-]+"),
+    ("USER", r"%{USERNAME}"),
+    ("INT", r"[+-]?[0-9]+"),
+    ("POSINT", r"\b[1-9][0-9]*\b"),
+    ("NONNEGINT", r"\b[0-9]+\b"),
+    ("NUMBER", r"[+-]?(?:[0-9]+(?:\.[0-9]*)?|\.[0-9]+)"),
+    ("BASE16NUM", r"[+-]?(?:0x)?[0-9A-Fa-f]+"),
+    ("WORD", r"\b\w+\b"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("QUOTEDSTRING", r#""(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*'"#),
+    ("UUID", r"[A-Fa-f0-9]{8}-(?:[A-Fa-f0-9]{4}-){3}[A-Fa-f0-9]{12}"),
+    ("MAC", r"(?:[A-Fa-f0-9]{2}[:-]){5}[A-Fa-f0-9]{2}"),
+    ("IPV4", r"(?:(?:25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])\.){3}(?:25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])"),
+    ("IPV6", r"(?:[A-Fa-f0-9]{0,4}:){2,7}[A-Fa-f0-9]{0,4}"),
+    ("IP", r"%{IPV4}|%{IPV6}"),
+    ("HOSTNAME", r"\b[0-9A-Za-z][0-9A-Za-z-]{0,62}(?:\.[0-9A-Za-z][0-9A-Za-z-]{0,62})*\.?\b"),
+    ("IPORHOST", r"%{IP}|%{HOSTNAME}"),
+    ("HOSTPORT", r"%{IPORHOST}:%{POSINT}"),
+    ("PATH", r"(?:/[^\s/]*)+"),
+    ("URIPATH", r"/[^\s?#]*"),
+    ("URIPARAM", r"\?[^\s#]*"),
+    ("URIPATHPARAM", r"%{URIPATH}%{URIPARAM}?"),
+    ("URI", r"[A-Za-z][A-Za-z0-9+.-]*://\S+"),
+    ("EMAILADDRESS", r"[a-zA-Z0-9._%+-]+@[a-zA-Z0-9.-]+"),
+    ("LOGLEVEL", r"(?i:trace|debug|info|notice|warn(?:ing)?|err(?:or)?|crit(?:ical)?|alert|emerg(?:ency)?|fatal|severe)"),
+    ("MONTH", r"\b(?:Jan(?:uary)?|Feb(?:ruary)?|Mar(?:ch)?|Apr(?:il)?|May|June?|July?|Aug(?:ust)?|Sep(?:tember)?|Oct(?:ober)?|Nov(?:ember)?|Dec(?:ember)?)\b"),
+    ("MONTHNUM", r"1[0-2]|0?[1-9]"),
+    ("MONTHDAY", r"3[01]|[12][0-9]|0?[1-9]"),
+    ("DAY", r"\b(?:Mon|Tue|Wed|Thu|Fri|Sat|Sun)[a-z]*\b"),
+    ("YEAR", r"[0-9]{4}"),
+    ("HOUR", r"2[0-3]|[01]?[0-9]"),
+    ("MINUTE", r"[0-5][0-9]"),
+    ("SECOND", r"(?:60|[0-5]?[0-9])(?:[.,][0-9]+)?"),
+    ("TIME", r"%{HOUR}:%{MINUTE}(?::%{SECOND})?"),
+    ("ISO8601_TIMEZONE", r"Z|[+-]%{HOUR}(?::?%{MINUTE})?"),
+    ("TIMESTAMP_ISO8601", r"%{YEAR}-%{MONTHNUM}-%{MONTHDAY}[T ]%{HOUR}:?%{MINUTE}(?::?%{SECOND})?%{ISO8601_TIMEZONE}?"),
+    ("HTTPDATE", r"%{MONTHDAY}/%{MONTH}/%{YEAR}:%{TIME} %{INT}"),
+    ("SYSLOGTIMESTAMP", r"%{MONTH} +%{MONTHDAY} %{TIME}"),
+];
+
+fn builtin(name: &str) -> Option<&'static str> {
+    BUILTIN.iter().find(|(n, _)| *n == name).map(|(_, d)| *d)
+}
+
+/// Whether this is a valid name for a pattern or a variable.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Expand `%{NAME}` and `%{NAME:variable}` references into plain regex
+/// syntax, looking names up in `library` then in the built-in patterns.
+/// Other text, such as `%{2}`, is left as it is.
+///
+/// If `strict` is false, unknown names expand to an empty group instead of
+/// failing, for patterns whose library is not known yet.
+pub(crate) fn expand(
+    regex: &str,
+    library: &BTreeMap<String, String>,
+    strict: bool,
+) -> Result<String, PatternError> {
+    expand_inner(regex, library, strict, &mut Vec::new())
+}
+
+fn expand_inner(
+    regex: &str,
+    library: &BTreeMap<String, String>,
+    strict: bool,
+    stack: &mut Vec<String>,
+) -> Result<String, PatternError> {
+    let mut result = String::with_capacity(regex.len());
+    let mut rest = regex;
+    while let Some(start) = rest.find("%{") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let reference = &rest[start + 2..end];
+        let (name, variable) = match reference.find(':') {
+            Some(colon) => (&reference[..colon], Some(&reference[colon + 1..])),
+            None => (reference, None),
+        };
+        if !is_identifier(name) || !variable.is_none_or(is_identifier) {
+            // Not a reference
+            result.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let definition = library.get(name)
+            .map(String::as_str)
+            .or_else(|| builtin(name));
+        let expanded = match definition {
+            Some(_) if stack.iter().any(|n| n == name) => {
+                return Err(PatternError::Recursive(name.to_owned()));
+            }
+            Some(definition) => {
+                stack.push(name.to_owned());
+                let expanded = expand_inner(definition, library, strict, stack)?;
+                stack.pop();
+                expanded
+            }
+            None if strict => return Err(PatternError::UnknownPattern(name.to_owned())),
+            None => String::new(),
+        };
+        match variable {
+            Some(variable) => {
+                result.push_str("(?P<");
+                result.push_str(variable);
+                result.push('>');
+            }
+            None => result.push_str("(?:"),
+        }
+        result.push_str(&expanded);
+        result.push(')');
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
@@ -0,0 +1,101 @@
+use regex::RegexSet;
+
+use crate::program::Op;
+
+/// Chains shorter than this are tested one pattern at a time.
+const MIN_CHAIN_LENGTH: usize = 3;
+
+/// The text that a pattern requires at the start of the value, if it is
+/// anchored there.
+///
+/// Only simple patterns are understood, others give an empty prefix which
+/// any value has.
+fn literal_prefix(regex: &str) -> String {
+    let mut prefix = String::new();
+    let mut chars = match regex.strip_prefix('^') {
+        // An alternation could be outside of the anchor
+        Some(rest) if !rest.contains('|') => rest.chars(),
+        _ => return prefix,
+    };
+    while let Some(c) = chars.next() {
+        let literal = match c {
+            '\\' => match chars.next() {
+                Some(escaped) if escaped.is_ascii_punctuation() => escaped,
+                _ => break,
+            },
+            '?' | '*' | '{' => {
+                // The last character is optional
+                prefix.pop();
+                break;
+            }
+            '.' | '[' | ']' | '(' | ')' | '}' | '+' | '^' | '$' => break,
+            c => c,
+        };
+        prefix.push(literal);
+    }
+    prefix
+}
+
+/// A chain of IF/ELIF matching the same expression against different
+/// patterns, tested in a single pass.
+pub(crate) struct ElifChain {
+    set: RegexSet,
+    /// Text one of the patterns requires at the start of the value, if they
+    /// all do, so that most values matching none are rejected quickly
+    prefixes: Option<Vec<String>>,
+}
+
+impl ElifChain {
+    /// The next If in a chain, if the else branch is a lone If matching the
+    /// same expression.
+    pub(crate) fn next(op: &Op) -> Option<&Op> {
+        match op {
+            Op::If { expression, else_ops, .. } => match else_ops.as_slice() {
+                [next @ Op::If { expression: next_expression, .. }]
+                    if next_expression == expression => Some(next),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Build the chain starting at an If operation, if it is long enough.
+    pub(crate) fn new(op: &Op) -> Option<ElifChain> {
+        let mut patterns = Vec::new();
+        let mut current = Some(op);
+        while let Some(op) = current {
+            if let Op::If { pattern, .. } = op {
+                patterns.push(pattern.compiled.as_str());
+            }
+            current = ElifChain::next(op);
+        }
+        if patterns.len() < MIN_CHAIN_LENGTH {
+            return None;
+        }
+        let prefixes: Vec<String> = patterns.iter().map(|p| literal_prefix(p)).collect();
+        let prefixes = if prefixes.iter().all(|p| !p.is_empty()) {
+            Some(prefixes)
+        } else {
+            None
+        };
+        // Fall back to testing patterns one at a time if they can't be
+        // combined, for example if the set would be too big
+        RegexSet::new(patterns).ok().map(|set| ElifChain { set, prefixes })
+    }
+
+    /// Find the If in the chain whose test decides the branch taken: the
+    /// first one that matches the value, or the last one if none does.
+    /// Also returns whether it matches.
+    pub(crate) fn select<'p>(&self, head: &'p Op, value: &str) -> (&'p Op, bool) {
+        let first = match &self.prefixes {
+            Some(prefixes) if !prefixes.iter().any(|p| value.starts_with(p.as_str())) => None,
+            _ => self.set.matches(value).iter().next(),
+        };
+        let stop = first.unwrap_or(self.set.len() - 1);
+        let mut op = head;
+        for _ in 0..stop {
+            op = ElifChain::next(op).unwrap();
+        }
+        (op, first.is_some())
+    }
+}
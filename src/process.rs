@@ -1,98 +1,597 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::io::{Error as IoError};
 
+use regex::CaptureLocations;
+
 use crate::{Color, Record};
-use crate::filters::{Condition, Expression, Operation, View};
-use crate::readers::LogReader;
+use crate::cluster::Drain;
+use crate::context::ContextLogIterator;
+use crate::dedup::{DedupState, Repeats};
+use crate::explain::{Trace, TraceEvent};
+use crate::filters::{BlockError, ParseState, View};
+use crate::program::{Expr, Op, Program, Slot, Window};
+use crate::readers::{LogReader, RawRecord};
+use crate::timestamp::parse_timestamp;
+use crate::variables::{Value, Variables};
+
+/// State of the evaluation of a compiled view, across records.
+pub(crate) struct FilterInner {
+    /// Last value of the variables read with LastVarValue, by slot, `None`
+    /// for other variables
+    variables_last: Vec<Option<String>>,
+    pub(crate) trace: Option<Trace>,
+    /// Storage for the variables of the next record
+    spare_variables: Variables,
+    /// Capture locations of If operations, by id
+    locations: Vec<CaptureLocations>,
+    /// Storage for the captures of a match
+    captures: Vec<(Slot, Value)>,
+    clusters: Vec<Drain>,
+    dedups: Vec<DedupState>,
+    parsers: Vec<ParseState>,
+    /// Runs of duplicates of the held record, by CollapseRepeats id
+    repeats: Vec<Option<Repeats>>,
+    /// Runs ended by the last kept record, whose counts are not set yet
+    ended_repeats: Vec<Option<Repeats>>,
+    /// Runs that the current record starts if it is kept, with their key
+    started_repeats: Vec<(usize, String)>,
+    /// Whether the current record was skipped as a duplicate
+    collapsed: bool,
+}
 
-#[derive(Default)]
-struct FilterInner {
-    variables_last: HashMap<String, String>,
+/// A record being evaluated.
+///
+/// The text can be borrowed from the reader, and variables captured from it
+/// are stored as spans, so that skipped records are never copied.
+pub(crate) struct Evaluation<'a> {
+    text: Cow<'a, str>,
+    variables: Variables,
+    color: Option<Value>,
+    lossy: bool,
+    truncated: bool,
+}
+
+/// The outcome of evaluating a record.
+pub(crate) enum Evaluated {
+    Kept(Record),
+    /// Skipped records are only built if they are needed for context
+    Skipped(Option<Record>),
+    /// A number of skipped records that are too far from kept ones to be
+    /// output as context
+    Hidden(u64),
 }
 
 pub struct FilteredLogIterator<R: LogReader> {
     filter: FilterInner,
     reader: R,
-    view: View,
+    program: Program,
+    /// Whether to build skipped records, for context
+    keep_skipped: bool,
+    /// Number of skipped records kept before and after a held record, for
+    /// context
+    before: usize,
+    after: usize,
+    /// Evaluated records, waiting for the held record to be released
+    queue: VecDeque<Evaluated>,
+    /// Position in the queue of the record that started runs of duplicates,
+    /// waiting for them to end to set its counts
+    held: Option<usize>,
+    /// Error compiling the view, returned instead of the first record
+    error: Option<BlockError>,
+    /// Whether the error was returned, ending the iteration
+    failed: bool,
+}
+
+/// The value of an expression.
+struct Operand<'a> {
+    text: &'a str,
+    /// How to store the value without copying it, if possible
+    stored: Option<Value>,
+}
+
+impl<'a> Operand<'a> {
+    fn into_value(self) -> Value {
+        let text = self.text;
+        self.stored.unwrap_or_else(|| Value::Owned(text.to_owned()))
+    }
+
+    /// Store part of the value, as a span if it is part of the record text.
+    fn capture(&self, start: usize, end: usize) -> Value {
+        match self.stored {
+            Some(Value::Span(offset, _)) => Value::Span(offset + start, offset + end),
+            _ => Value::Owned(self.text[start..end].to_owned()),
+        }
+    }
+}
+
+fn evaluate<'a>(
+    variables_last: &'a [Option<String>],
+    expression: &'a Expr,
+    record: &'a Evaluation,
+) -> Operand<'a> {
+    match expression {
+        Expr::Record => Operand {
+            text: &record.text,
+            stored: Some(Value::Span(0, record.text.len())),
+        },
+        Expr::Var(slot) => match record.variables.get(*slot) {
+            Some(Value::Owned(value)) => Operand { text: value, stored: None },
+            Some(value) => Operand {
+                text: value.as_str(&record.text),
+                stored: Some(value.clone()),
+            },
+            None => Operand { text: "", stored: None },
+        },
+        Expr::LastVar(slot) => Operand {
+            text: variables_last[*slot].as_deref().unwrap_or(""),
+            stored: None,
+        },
+        Expr::Constant(value) => Operand {
+            text: value,
+            stored: Some(Value::Shared(value.clone())),
+        },
+    }
 }
 
 impl FilterInner {
-    fn set_variable(&mut self, record: &mut Record, key: String, value: String) {
-        record.variables.insert(key.clone(), value.clone());
-        self.variables_last.insert(key, value);
+    pub(crate) fn new(program: &Program) -> FilterInner {
+        FilterInner {
+            // Variables that were never set read as empty
+            variables_last: program.tracked.iter()
+                .map(|&tracked| if tracked { Some(String::new()) } else { None })
+                .collect(),
+            trace: None,
+            spare_variables: Default::default(),
+            locations: program.locations.clone(),
+            captures: Vec::new(),
+            clusters: (0..program.clusters).map(|_| Default::default()).collect(),
+            dedups: (0..program.dedups).map(|_| Default::default()).collect(),
+            parsers: (0..program.parsers).map(|_| Default::default()).collect(),
+            repeats: (0..program.repeats.len()).map(|_| None).collect(),
+            ended_repeats: (0..program.repeats.len()).map(|_| None).collect(),
+            started_repeats: Vec::new(),
+            collapsed: false,
+        }
+    }
+
+    /// A filter that records a trace of the evaluation.
+    pub(crate) fn traced(program: &Program) -> FilterInner {
+        FilterInner {
+            trace: Some(Default::default()),
+            ..FilterInner::new(program)
+        }
+    }
+
+    /// Start evaluating a record, setting the variables from the reader.
+    pub(crate) fn start<'a>(&mut self, program: &Program, raw: RawRecord<'a>) -> Evaluation<'a> {
+        let mut variables = std::mem::take(&mut self.spare_variables);
+        variables.reset(program.names.len());
+        let mut record = Evaluation {
+            text: raw.text,
+            variables,
+            color: None,
+            lossy: raw.lossy,
+            truncated: raw.truncated,
+        };
+        for (key, value) in raw.fields {
+            self.set_named(program, &mut record, key, value);
+        }
+        record
+    }
+
+    /// Build the public record, copying the text and variables.
+    pub(crate) fn finish(&mut self, program: &Program, record: Evaluation) -> Record {
+        let built = Record {
+            variables: record.variables.to_map(program, &record.text),
+            color: match &record.color {
+                Some(value) => Color::FromValue {
+                    value: value.as_str(&record.text).to_owned(),
+                },
+                None => Color::Default,
+            },
+            lossy: record.lossy,
+            truncated: record.truncated,
+            ..Record::new(record.text.as_ref().to_owned())
+        };
+        self.discard(record);
+        built
+    }
+
+    /// Drop a record, keeping its storage for the next one.
+    pub(crate) fn discard(&mut self, record: Evaluation) {
+        self.spare_variables = record.variables;
     }
 
-    fn evaluate(&self, expression: &Expression, record: &Record) -> String {
-        match expression {
-            Expression::Record => record.text.to_owned(),
-            Expression::Var(name) => record.variables.get(name).cloned().unwrap_or_default(),
-            Expression::LastVarValue(name) => self.variables_last.get(name).cloned().unwrap_or_default(),
-            Expression::Constant(value) => value.clone(),
+    fn set_variable(&mut self, record: &mut Evaluation, slot: Slot, value: Value) {
+        if let Some(last) = &mut self.variables_last[slot] {
+            last.clear();
+            last.push_str(value.as_str(&record.text));
         }
+        record.variables.set(slot, value);
     }
 
-    fn apply_operations(
+    /// Set a variable whose name is only known when reading, such as a
+    /// parsed field.
+    fn set_named(
         &mut self,
-        record: &mut Record,
-        operations: &[Operation],
+        program: &Program,
+        record: &mut Evaluation,
+        key: String,
+        value: String,
+    ) {
+        match program.slot(&key) {
+            Some(slot) => self.set_variable(record, slot, Value::Owned(value)),
+            None => record.variables.set_other(key.into(), Value::Owned(value)),
+        }
+    }
+
+    /// Skip to the If that decides the branch taken in a chain of ELIF
+    /// matching the same expression, testing all the patterns at once.
+    /// Also returns whether its pattern matches, if that is known.
+    fn skip_elifs<'p>(&self, op: &'p Op, record: &Evaluation) -> (&'p Op, Option<bool>) {
+        // When tracing, each test is shown
+        if self.trace.is_some() {
+            return (op, None);
+        }
+        match op {
+            Op::If { expression, chain: Some(chain), .. } => {
+                let value = evaluate(&self.variables_last, expression, record);
+                let (op, matched) = chain.select(op, value.text);
+                (op, Some(matched))
+            }
+            _ => (op, None),
+        }
+    }
+
+    pub(crate) fn apply_operations(
+        &mut self,
+        program: &Program,
+        record: &mut Evaluation,
+        ops: &[Op],
     ) -> bool {
-        for operation in operations {
-            match operation {
-                Operation::If { condition, then_ops, else_ops } => {
-                    match condition {
-                        Condition::Match { expression, pattern } => {
-                            let value = self.evaluate(expression, &record);
-                            if let Some(m) =  pattern.match_string(&value) {
-                                for (key, value) in m {
-                                    self.set_variable(record, key, value);
-                                }
-                                if !self.apply_operations(record, then_ops) {
-                                    return false;
-                                }
-                            } else {
-                                if !self.apply_operations(record, else_ops) {
-                                    return false;
-                                }
+        for op in ops {
+            match op {
+                Op::If { .. } => {
+                    let (op, known) = self.skip_elifs(op, record);
+                    let (expression, pattern, captures, then_ops, else_ops, id, label) =
+                        match op {
+                            Op::If {
+                                expression, pattern, captures, then_ops, else_ops, id, label, ..
+                            } => (expression, pattern, captures, then_ops, else_ops, *id, label),
+                            _ => unreachable!(),
+                        };
+                    let value = evaluate(&self.variables_last, expression, record);
+                    let locations = &mut self.locations[id];
+                    // The pattern only needs to run again to find the groups
+                    let matched = match known {
+                        Some(false) => false,
+                        Some(true) if captures.is_empty() => true,
+                        _ => pattern.compiled.captures_read(locations, value.text).is_some(),
+                    };
+                    let depth = self.trace.as_ref().map(|t| t.depth);
+                    if let Some(trace) = &mut self.trace {
+                        trace.test(label, &pattern.regex, value.text, matched);
+                    }
+                    let kept = if matched {
+                        if let Some(trace) = &mut self.trace {
+                            trace.depth += 1;
+                        }
+                        let mut values = std::mem::take(&mut self.captures);
+                        for &(group, slot) in captures {
+                            if let Some((start, end)) = locations.get(group) {
+                                values.push((slot, value.capture(start, end)));
                             }
                         }
+                        for (slot, value) in values.drain(..) {
+                            if let Some(trace) = &mut self.trace {
+                                trace.push(TraceEvent::Capture {
+                                    name: program.names[slot].to_string(),
+                                    value: value.as_str(&record.text).to_owned(),
+                                });
+                            }
+                            self.set_variable(record, slot, value);
+                        }
+                        self.captures = values;
+                        self.apply_operations(program, record, then_ops)
+                    } else {
+                        if let Some(trace) = &mut self.trace {
+                            // Show a lone nested If as ELIF, like
+                            // Operation::print does
+                            if let [Op::If { .. }] = else_ops.as_slice() {
+                                trace.elif = true;
+                            } else if !else_ops.is_empty() {
+                                trace.push(TraceEvent::Else);
+                                trace.depth += 1;
+                            }
+                        }
+                        self.apply_operations(program, record, else_ops)
+                    };
+                    if let (Some(trace), Some(depth)) = (&mut self.trace, depth) {
+                        trace.depth = depth;
+                    }
+                    if !kept {
+                        return false;
                     }
                 }
-                Operation::Set { target, expression } => {
-                    let value = self.evaluate(expression, &record);
-                    self.set_variable(record, target.to_owned(), value);
+                Op::Set { target, expression } => {
+                    let value = evaluate(&self.variables_last, expression, record);
+                    if let Some(trace) = &mut self.trace {
+                        trace.push(TraceEvent::Set {
+                            target: program.names[*target].to_string(),
+                            value: value.text.to_owned(),
+                        });
+                    }
+                    let value = value.into_value();
+                    self.set_variable(record, *target, value);
                 }
-                Operation::ColorBy(expression) => {
-                    let value = self.evaluate(expression, &record);
-                    record.color = Color::FromValue { value };
+                Op::ColorBy(expression) => {
+                    let value = evaluate(&self.variables_last, expression, record);
+                    if let Some(trace) = &mut self.trace {
+                        trace.push(TraceEvent::ColorBy { value: value.text.to_owned() });
+                    }
+                    record.color = Some(value.into_value());
                 }
-                Operation::SkipRecord => {
+                Op::SkipRecord => {
+                    if let Some(trace) = &mut self.trace {
+                        trace.push(TraceEvent::Skip);
+                    }
                     return false;
                 }
+                Op::Call(id) => {
+                    if !self.apply_operations(program, record, &program.blocks[*id]) {
+                        return false;
+                    }
+                }
+                Op::Unset(target) => {
+                    if let Some(trace) = &mut self.trace {
+                        trace.push(TraceEvent::Unset {
+                            target: program.names[*target].to_string(),
+                        });
+                    }
+                    record.variables.unset(*target);
+                }
+                Op::Cluster { expression, target, id } => {
+                    let value = evaluate(&self.variables_last, expression, record);
+                    let cluster = self.clusters[*id].add(value.text).to_string();
+                    if let Some(trace) = &mut self.trace {
+                        trace.push(TraceEvent::Set {
+                            target: program.names[*target].to_string(),
+                            value: cluster.clone(),
+                        });
+                    }
+                    self.set_variable(record, *target, Value::Owned(cluster));
+                }
+                Op::Dedup { key, window, id } => {
+                    let key = evaluate(&self.variables_last, key, record).text.to_owned();
+                    let time = match window {
+                        Window::Seconds { time, .. } => {
+                            parse_timestamp(evaluate(&self.variables_last, time, record).text)
+                        }
+                        Window::Records(_) => None,
+                    };
+                    if self.dedups[*id].check(window, key, time) {
+                        if let Some(trace) = &mut self.trace {
+                            trace.push(TraceEvent::Skip);
+                        }
+                        return false;
+                    }
+                }
+                Op::CollapseRepeats { key, id } => {
+                    let key = evaluate(&self.variables_last, key, record).text;
+                    if let Some(repeats) = &mut self.repeats[*id] {
+                        if repeats.key.as_deref() == Some(key) {
+                            repeats.count += 1;
+                            self.collapsed = true;
+                            if let Some(trace) = &mut self.trace {
+                                trace.push(TraceEvent::Skip);
+                            }
+                            return false;
+                        }
+                        // Any other record reaching the operation ends the run,
+                        // even if it is skipped later
+                        repeats.key = None;
+                    }
+                    self.started_repeats.push((*id, key.to_owned()));
+                }
+                Op::Parse { expression, format, prefix, else_ops, id, label } => {
+                    let value = evaluate(&self.variables_last, expression, record);
+                    let fields = format.parse_with_state(value.text, &mut self.parsers[*id]);
+                    let depth = self.trace.as_ref().map(|t| t.depth);
+                    if let Some(trace) = &mut self.trace {
+                        trace.push(TraceEvent::Parse {
+                            expression: label.clone(),
+                            format: format!("{:?}", format),
+                            parsed: fields.is_some(),
+                        });
+                        trace.depth += 1;
+                    }
+                    let kept = match fields {
+                        Some(fields) => {
+                            for (key, value) in fields {
+                                let key = format!("{}{}", prefix, key);
+                                if let Some(trace) = &mut self.trace {
+                                    trace.push(TraceEvent::Capture {
+                                        name: key.clone(),
+                                        value: value.clone(),
+                                    });
+                                }
+                                self.set_named(program, record, key, value);
+                            }
+                            true
+                        }
+                        None => self.apply_operations(program, record, else_ops),
+                    };
+                    if let (Some(trace), Some(depth)) = (&mut self.trace, depth) {
+                        trace.depth = depth;
+                    }
+                    if !kept {
+                        return false;
+                    }
+                }
             }
         }
         true
     }
+
+    /// Apply the view to a record, and keep track of the runs of duplicates.
+    ///
+    /// A kept record ends the runs of the previous kept record, which move to
+    /// `ended_repeats`, and starts its own.
+    pub(crate) fn evaluate(&mut self, program: &Program, record: &mut Evaluation) -> bool {
+        self.started_repeats.clear();
+        self.collapsed = false;
+        if !self.apply_operations(program, record, &program.ops) {
+            return false;
+        }
+        std::mem::swap(&mut self.repeats, &mut self.ended_repeats);
+        for repeats in &mut self.repeats {
+            *repeats = None;
+        }
+        for (id, key) in self.started_repeats.drain(..) {
+            self.repeats[id] = Some(Repeats { key: Some(key), count: 1 });
+        }
+        true
+    }
 }
 
 impl<R: LogReader> FilteredLogIterator<R> {
-    fn next_triable(&mut self) -> Result<Option<Record>, IoError> {
+    /// Read the next record and apply the view.
+    ///
+    /// Duplicates collapsed into the held record are not returned.
+    fn evaluate_next(&mut self) -> Result<Option<Evaluated>, IoError> {
+        if let Some(e) = self.error.take() {
+            self.failed = true;
+            return Err(IoError::new(std::io::ErrorKind::InvalidInput, e));
+        } else if self.failed {
+            return Ok(None);
+        }
         loop {
             // Read text from reader
-            let text = match self.reader.read_record()? {
-                Some(t) => t,
+            let raw = match self.reader.read_raw_record()? {
+                Some(r) => r,
                 None => return Ok(None),
             };
-            let mut record = Record::new(text);
+            let mut record = self.filter.start(&self.program, raw);
 
             // Apply filters
-            if !self.filter.apply_operations(&mut record, &self.view.operations) {
-                // Skipped
-                continue
+            let program = &self.program;
+            if self.filter.evaluate(program, &mut record) {
+                return Ok(Some(Evaluated::Kept(self.filter.finish(program, record))));
+            } else if self.filter.collapsed {
+                self.filter.discard(record);
+            } else if self.keep_skipped {
+                return Ok(Some(Evaluated::Skipped(Some(self.filter.finish(program, record)))));
+            } else {
+                self.filter.discard(record);
+                return Ok(Some(Evaluated::Skipped(None)));
+            }
+        }
+    }
+
+    /// Set the counts of the held record, whose runs of duplicates ended.
+    ///
+    /// The runs were ended by a kept record if `by_kept`, and are then in
+    /// `ended_repeats`.
+    fn release_held(&mut self, by_kept: bool) {
+        let held = match self.held.take() {
+            Some(held) => held,
+            None => return,
+        };
+        let runs = if by_kept {
+            &mut self.filter.ended_repeats
+        } else {
+            &mut self.filter.repeats
+        };
+        if let Evaluated::Kept(record) = &mut self.queue[held] {
+            for (id, repeats) in runs.iter_mut().enumerate() {
+                if let Some(repeats) = repeats.take() {
+                    record.variables.insert(
+                        self.program.repeats[id].clone(),
+                        repeats.count.to_string(),
+                    );
+                }
             }
+        }
+    }
 
-            return Ok(Some(record));
+    fn push(&mut self, evaluated: Evaluated) {
+        match evaluated {
+            Evaluated::Kept(record) => {
+                // A kept record ends all the runs
+                self.release_held(true);
+                if self.filter.repeats.iter().any(Option::is_some) {
+                    self.held = Some(self.queue.len());
+                }
+                self.queue.push_back(Evaluated::Kept(record));
+            }
+            skipped => self.queue.push_back(skipped),
+        }
+        if let Some(held) = self.held {
+            let ended = self.filter.repeats.iter().flatten().all(|r| r.key.is_none());
+            if ended {
+                self.release_held(false);
+            } else {
+                // Only the first records after the held one and the last ones
+                // can be output as context, hide the others so that long runs
+                // don't use memory
+                let size = |e: &Evaluated| match e {
+                    Evaluated::Hidden(n) => *n,
+                    _ => 1,
+                };
+                let gap = held + 1 + self.after;
+                while self.queue.len() > gap + 1 + self.before {
+                    let next = self.queue.remove(gap + 1).unwrap();
+                    self.queue[gap] = Evaluated::Hidden(size(&self.queue[gap]) + size(&next));
+                }
+            }
         }
     }
+
+    /// Get the next evaluated record, in order.
+    ///
+    /// A record that starts runs of duplicates for CollapseRepeats is held,
+    /// with the records after it, until its runs end and its counts are set.
+    pub(crate) fn next_evaluated(&mut self) -> Result<Option<Evaluated>, IoError> {
+        loop {
+            let ready = match self.held {
+                Some(held) => held > 0,
+                None => !self.queue.is_empty(),
+            };
+            if ready {
+                if let Some(held) = &mut self.held {
+                    *held -= 1;
+                }
+                return Ok(self.queue.pop_front());
+            }
+            match self.evaluate_next()? {
+                Some(evaluated) => self.push(evaluated),
+                None => {
+                    self.release_held(false);
+                    return Ok(self.queue.pop_front());
+                }
+            }
+        }
+    }
+
+    fn next_triable(&mut self) -> Result<Option<Record>, IoError> {
+        loop {
+            match self.next_evaluated()? {
+                Some(Evaluated::Kept(record)) => return Ok(Some(record)),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Also emit skipped records that are within `before` or `after` records
+    /// of a kept record, like `grep -B` and `grep -A`.
+    pub fn with_context(mut self, before: usize, after: usize) -> ContextLogIterator<R> {
+        self.keep_skipped = true;
+        self.before = before;
+        self.after = after;
+        ContextLogIterator::new(self, before, after)
+    }
 }
 
 impl<R: LogReader> Iterator for FilteredLogIterator<R> {
@@ -107,10 +606,25 @@ impl<R: LogReader> Iterator for FilteredLogIterator<R> {
     }
 }
 
+/// Process the records from a reader with a view.
+///
+/// If the view can't be compiled, for example because it calls a block that
+/// doesn't exist, the first item is an `InvalidInput` error.
 pub fn process<R: LogReader>(reader: R, view: View) -> FilteredLogIterator<R> {
+    let (program, error) = match Program::compile(&view) {
+        Ok(program) => (program, None),
+        Err(e) => (Program::default(), Some(e)),
+    };
     FilteredLogIterator {
-        filter: Default::default(),
+        filter: FilterInner::new(&program),
         reader,
-        view,
+        program,
+        keep_skipped: false,
+        before: 0,
+        after: 0,
+        queue: VecDeque::new(),
+        held: None,
+        error,
+        failed: false,
     }
 }
@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use regex::CaptureLocations;
+
+use crate::filters::{
+    BlockError, Condition, DedupWindow, Expression, MAX_FLATTENED_OPERATIONS, Operation,
+    ParseFormat, Pattern, View,
+};
+use crate::prefilter::ElifChain;
+
+/// Index of a variable in a compiled program.
+pub(crate) type Slot = usize;
+
+#[derive(PartialEq)]
+pub(crate) enum Expr {
+    Record,
+    Var(Slot),
+    LastVar(Slot),
+    Constant(Arc<str>),
+}
+
+pub(crate) enum Window {
+    Records(usize),
+    Seconds {
+        seconds: f64,
+        time: Expr,
+    },
+}
+
+/// An operation of a compiled view.
+///
+/// Stateful operations have an `id`, the index of their state in the filter.
+pub(crate) enum Op {
+    If {
+        expression: Expr,
+        pattern: Pattern,
+        /// Slots set by the named groups, with the index of the group
+        captures: Vec<(usize, Slot)>,
+        then_ops: Vec<Op>,
+        else_ops: Vec<Op>,
+        id: usize,
+        /// Patterns of the ELIF chain starting here, if it is long enough
+        chain: Option<ElifChain>,
+        /// The expression as written in the view, for traces
+        label: String,
+    },
+    Set {
+        target: Slot,
+        expression: Expr,
+    },
+    ColorBy(Expr),
+    SkipRecord,
+    Unset(Slot),
+    /// Run a compiled block, by index
+    Call(usize),
+    Cluster {
+        expression: Expr,
+        target: Slot,
+        id: usize,
+    },
+    Dedup {
+        key: Expr,
+        window: Window,
+        id: usize,
+    },
+    CollapseRepeats {
+        key: Expr,
+        id: usize,
+    },
+    Parse {
+        expression: Expr,
+        format: ParseFormat,
+        prefix: String,
+        else_ops: Vec<Op>,
+        id: usize,
+        /// The expression as written in the view, for traces
+        label: String,
+    },
+}
+
+/// A view compiled into a form that is faster to execute.
+///
+/// Variables are numbered, tests on constants are decided once, and
+/// operations that can't run are removed. Blocks are compiled once, the
+/// stateful operations in a block share their state between calls.
+#[derive(Default)]
+pub(crate) struct Program {
+    pub(crate) ops: Vec<Op>,
+    /// Compiled blocks, by index
+    pub(crate) blocks: Vec<Vec<Op>>,
+    /// Names of the variables, by slot
+    pub(crate) names: Vec<Arc<str>>,
+    slots: HashMap<String, Slot>,
+    /// Whether the last value of each variable is read and remembered
+    pub(crate) tracked: Vec<bool>,
+    /// Whether each variable is left out of the output
+    pub(crate) local: Vec<bool>,
+    /// Capture locations for each If, by id
+    pub(crate) locations: Vec<CaptureLocations>,
+    /// Number of Cluster states, operations with the same target share one
+    pub(crate) clusters: usize,
+    pub(crate) dedups: usize,
+    pub(crate) parsers: usize,
+    /// Count variable of each CollapseRepeats, by id
+    pub(crate) repeats: Vec<String>,
+}
+
+impl Program {
+    pub(crate) fn compile(view: &View) -> Result<Program, BlockError> {
+        let mut compiler = Compiler {
+            program: Default::default(),
+            cluster_ids: HashMap::new(),
+            blocks: &view.blocks,
+            block_ids: HashMap::new(),
+            block_skips: Vec::new(),
+            block_sizes: Vec::new(),
+            calling: Vec::new(),
+        };
+        for name in &view.locals {
+            let slot = compiler.slot(name);
+            compiler.program.local[slot] = true;
+        }
+        let mut ops = compiler.compile_ops(&view.operations)?;
+        // Calls run the same blocks several times, limit the total like
+        // View::flatten does
+        if size(&ops, &compiler.block_sizes) > MAX_FLATTENED_OPERATIONS {
+            return Err(BlockError::TooLarge);
+        }
+        add_chains(&mut ops, false);
+        for block in &mut compiler.program.blocks {
+            add_chains(block, false);
+        }
+        for name in &view.untracked {
+            if let Some(slot) = compiler.program.slot(name) {
+                compiler.program.tracked[slot] = false;
+            }
+        }
+        compiler.program.ops = ops;
+        Ok(compiler.program)
+    }
+
+    /// The slot of a variable, if the view uses it.
+    pub(crate) fn slot(&self, name: &str) -> Option<Slot> {
+        self.slots.get(name).cloned()
+    }
+}
+
+struct Compiler<'a> {
+    program: Program,
+    /// Cluster states, by target variable
+    cluster_ids: HashMap<String, usize>,
+    blocks: &'a BTreeMap<String, Vec<Operation>>,
+    /// Compiled blocks, by name
+    block_ids: HashMap<&'a str, usize>,
+    /// Whether each compiled block always skips the record
+    block_skips: Vec<bool>,
+    /// Number of operations each compiled block can run, with its calls
+    block_sizes: Vec<usize>,
+    /// Blocks being compiled, to find recursive calls
+    calling: Vec<&'a str>,
+}
+
+impl<'a> Compiler<'a> {
+    fn slot(&mut self, name: &str) -> Slot {
+        if let Some(&slot) = self.program.slots.get(name) {
+            return slot;
+        }
+        let slot = self.program.names.len();
+        self.program.names.push(name.into());
+        self.program.slots.insert(name.to_owned(), slot);
+        self.program.tracked.push(false);
+        self.program.local.push(false);
+        slot
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Expr {
+        match expression {
+            Expression::Record => Expr::Record,
+            Expression::Var(name) => Expr::Var(self.slot(name)),
+            Expression::LastVarValue(name) => {
+                let slot = self.slot(name);
+                self.program.tracked[slot] = true;
+                Expr::LastVar(slot)
+            }
+            Expression::Constant(value) => Expr::Constant(value.as_str().into()),
+        }
+    }
+
+    fn compile_ops(&mut self, operations: &'a [Operation]) -> Result<Vec<Op>, BlockError> {
+        let mut ops = Vec::new();
+        self.compile_into(operations, &mut ops)?;
+        Ok(ops)
+    }
+
+    /// Compile operations, returning whether they always skip the record, in
+    /// which case what follows is never run.
+    fn compile_into(
+        &mut self,
+        operations: &'a [Operation],
+        ops: &mut Vec<Op>,
+    ) -> Result<bool, BlockError> {
+        for operation in operations {
+            if self.compile_operation(operation, ops)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Compile a block the first time it is called, returning its index.
+    fn compile_block(&mut self, name: &'a str) -> Result<usize, BlockError> {
+        if let Some(&id) = self.block_ids.get(name) {
+            return Ok(id);
+        }
+        if self.calling.contains(&name) {
+            return Err(BlockError::Recursive(name.to_owned()));
+        }
+        let block = self.blocks.get(name)
+            .ok_or_else(|| BlockError::UnknownBlock(name.to_owned()))?;
+        self.calling.push(name);
+        let mut ops = Vec::new();
+        let skips = self.compile_into(block, &mut ops)?;
+        self.calling.pop();
+        let id = self.program.blocks.len();
+        self.block_sizes.push(size(&ops, &self.block_sizes));
+        self.program.blocks.push(ops);
+        self.block_skips.push(skips);
+        self.block_ids.insert(name, id);
+        Ok(id)
+    }
+
+    fn compile_operation(
+        &mut self,
+        operation: &'a Operation,
+        ops: &mut Vec<Op>,
+    ) -> Result<bool, BlockError> {
+        match operation {
+            Operation::If { condition, then_ops, else_ops } => {
+                let Condition::Match { expression, pattern } = condition;
+                if let Expression::Constant(value) = expression {
+                    // Decide the test now
+                    return match pattern.compiled.captures(value) {
+                        Some(m) => {
+                            for (group, name) in m.iter().zip(&pattern.all_groups) {
+                                if let (Some(group), Some(name)) = (group, name) {
+                                    ops.push(Op::Set {
+                                        target: self.slot(name),
+                                        expression: Expr::Constant(group.as_str().into()),
+                                    });
+                                }
+                            }
+                            self.compile_into(then_ops, ops)
+                        }
+                        None => self.compile_into(else_ops, ops),
+                    };
+                }
+                let captures: Vec<_> = pattern.all_groups.iter()
+                    .enumerate()
+                    .filter_map(|(i, name)| name.as_ref().map(|name| (i, self.slot(name))))
+                    .collect();
+                let mut compiled_then = Vec::new();
+                let then_skips = self.compile_into(then_ops, &mut compiled_then)?;
+                let mut compiled_else = Vec::new();
+                let else_skips = self.compile_into(else_ops, &mut compiled_else)?;
+                if captures.is_empty() && compiled_then.is_empty() && compiled_else.is_empty() {
+                    // The test has no effect
+                    return Ok(false);
+                }
+                let id = self.program.locations.len();
+                self.program.locations.push(pattern.compiled.capture_locations());
+                ops.push(Op::If {
+                    expression: self.expression(expression),
+                    pattern: pattern.clone(),
+                    captures,
+                    then_ops: compiled_then,
+                    else_ops: compiled_else,
+                    id,
+                    chain: None,
+                    label: format!("{:?}", expression),
+                });
+                Ok(then_skips && else_skips)
+            }
+            Operation::Set { target, expression } => {
+                ops.push(Op::Set {
+                    target: self.slot(target),
+                    expression: self.expression(expression),
+                });
+                Ok(false)
+            }
+            Operation::ColorBy(expression) => {
+                ops.push(Op::ColorBy(self.expression(expression)));
+                Ok(false)
+            }
+            Operation::SkipRecord => {
+                ops.push(Op::SkipRecord);
+                Ok(true)
+            }
+            Operation::Unset { target } => {
+                ops.push(Op::Unset(self.slot(target)));
+                Ok(false)
+            }
+            Operation::Call(name) => {
+                let id = self.compile_block(name)?;
+                ops.push(Op::Call(id));
+                Ok(self.block_skips[id])
+            }
+            Operation::Cluster { expression, target } => {
+                let next_id = self.cluster_ids.len();
+                let id = *self.cluster_ids.entry(target.clone()).or_insert(next_id);
+                self.program.clusters = self.cluster_ids.len();
+                ops.push(Op::Cluster {
+                    expression: self.expression(expression),
+                    target: self.slot(target),
+                    id,
+                });
+                Ok(false)
+            }
+            Operation::Dedup { key, window } => {
+                let window = match window {
+                    DedupWindow::Records(size) => Window::Records(*size),
+                    DedupWindow::Seconds { seconds, time } => Window::Seconds {
+                        seconds: *seconds,
+                        time: self.expression(time),
+                    },
+                };
+                ops.push(Op::Dedup {
+                    key: self.expression(key),
+                    window,
+                    id: self.program.dedups,
+                });
+                self.program.dedups += 1;
+                Ok(false)
+            }
+            Operation::CollapseRepeats { key, count } => {
+                ops.push(Op::CollapseRepeats {
+                    key: self.expression(key),
+                    id: self.program.repeats.len(),
+                });
+                self.program.repeats.push(count.clone());
+                Ok(false)
+            }
+            Operation::Parse { expression, format, prefix, else_ops } => {
+                let mut compiled_else = Vec::new();
+                // Parsing can fail, so what follows can still run
+                self.compile_into(else_ops, &mut compiled_else)?;
+                ops.push(Op::Parse {
+                    expression: self.expression(expression),
+                    format: format.clone(),
+                    prefix: prefix.clone(),
+                    else_ops: compiled_else,
+                    id: self.program.parsers,
+                    label: format!("{:?}", expression),
+                });
+                self.program.parsers += 1;
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Number of operations that can run, counting those of called blocks.
+fn size(ops: &[Op], block_sizes: &[usize]) -> usize {
+    ops.iter().fold(0, |total, op| {
+        let size = match op {
+            Op::If { then_ops, else_ops, .. } => {
+                size(then_ops, block_sizes).saturating_add(size(else_ops, block_sizes))
+            }
+            Op::Parse { else_ops, .. } => size(else_ops, block_sizes),
+            Op::Call(id) => block_sizes[*id],
+            _ => 0,
+        };
+        total.saturating_add(1).saturating_add(size)
+    })
+}
+
+/// Prepare the ELIF chains, only at their first operation.
+fn add_chains(ops: &mut [Op], elif: bool) {
+    for op in ops {
+        let chain = if elif { None } else { ElifChain::new(op) };
+        let continues = ElifChain::next(op).is_some();
+        match op {
+            Op::If { then_ops, else_ops, chain: prepared, .. } => {
+                *prepared = chain;
+                add_chains(then_ops, false);
+                add_chains(else_ops, continues);
+            }
+            Op::Parse { else_ops, .. } => add_chains(else_ops, false),
+            _ => {}
+        }
+    }
+}
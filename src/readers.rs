@@ -1,30 +1,152 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::fs;
-use std::io::{BufRead, BufReader, Error as IoError, Seek, SeekFrom};
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Seek, SeekFrom};
 use std::path::Path;
 
+use crate::encoding::LineDecoder;
+pub use crate::encoding::{Encoding, InvalidEncoding};
+
+/// Variables decoded by a reader, in the order they appear in the record.
+pub type Fields = Vec<(String, String)>;
+
+/// Default limit on the size of a record, in bytes.
+pub const DEFAULT_MAX_RECORD_SIZE: usize = 1 << 20;
+
+/// A record as read from the log, before the view is applied.
+///
+/// The text can be borrowed from the reader, for example from a memory-mapped
+/// file, so that records the view skips are never copied.
+pub struct RawRecord<'a> {
+    pub text: Cow<'a, str>,
+    /// Variables decoded from the log format, such as the stream of a
+    /// container log
+    pub fields: Fields,
+    /// Whether invalid input was replaced when decoding the text
+    pub lossy: bool,
+    /// Whether the text was cut at the maximum record size
+    pub truncated: bool,
+}
+
+impl<'a> RawRecord<'a> {
+    pub fn new<T: Into<Cow<'a, str>>>(text: T) -> RawRecord<'a> {
+        RawRecord {
+            text: text.into(),
+            fields: Vec::new(),
+            lossy: false,
+            truncated: false,
+        }
+    }
+
+    /// Copy the text if it is borrowed.
+    pub fn into_owned(self) -> RawRecord<'static> {
+        RawRecord {
+            text: Cow::Owned(self.text.into_owned()),
+            fields: self.fields,
+            lossy: self.lossy,
+            truncated: self.truncated,
+        }
+    }
+}
+
+/// How to read a file of lines.
+#[derive(Clone)]
+pub struct ReadOptions {
+    pub encoding: Encoding,
+    /// Records longer than this many bytes, not counting the line ending, are
+    /// truncated, the rest of the line is skipped
+    pub max_record_size: Option<usize>,
+    /// Fail to open files that look binary, instead of only flagging them
+    pub refuse_binary: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            encoding: Encoding::Utf8,
+            max_record_size: Some(DEFAULT_MAX_RECORD_SIZE),
+            refuse_binary: false,
+        }
+    }
+}
+
+/// Guess whether data is binary rather than text, from a sample at the
+/// start: text has no NUL bytes and few control characters.
+fn looks_binary(sample: &[u8], encoding: Encoding) -> bool {
+    if sample.is_empty() || encoding == Encoding::Utf16 {
+        return false;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+    let control = sample.iter()
+        .filter(|&&b| (b < 0x20 && !b"\t\n\r\x0c\x1b".contains(&b)) || b == 0x7F)
+        .count();
+    control * 10 > sample.len()
+}
+
+/// Set up decoding of a stream, returning whether it looks binary.
+fn start_stream<B: BufRead>(
+    reader: &mut B,
+    options: &ReadOptions,
+) -> Result<(LineDecoder, bool), IoError> {
+    let mut decoder = LineDecoder::new(options.encoding, options.max_record_size);
+    decoder.detect_bom(reader)?;
+    let binary = looks_binary(reader.fill_buf()?, options.encoding);
+    if binary && options.refuse_binary {
+        return Err(IoError::new(ErrorKind::InvalidData, "This looks like a binary file"));
+    }
+    Ok((decoder, binary))
+}
+
 pub trait LogReader {
     fn seek(&mut self, pos: u64) -> Result<(), IoError>;
     fn tell(&self) -> u64;
     fn read_record(&mut self) -> Result<Option<String>, IoError>;
+
+    /// Read a record along with what the reader knows about it, such as
+    /// variables decoded from the log format. Plain readers only have text.
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        Ok(self.read_record()?.map(RawRecord::new))
+    }
 }
 
 pub struct LogFile {
     pub file: BufReader<fs::File>,
     pub pos: u64,
+    decoder: LineDecoder,
+    binary: bool,
 }
 
 impl LogFile {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<LogFile, IoError> {
+        LogFile::open_with_options(path, &Default::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: &ReadOptions,
+    ) -> Result<LogFile, IoError> {
+        let mut file = BufReader::new(fs::File::open(path)?);
+        let (decoder, binary) = start_stream(&mut file, options)?;
         Ok(LogFile {
-            file: BufReader::new(fs::File::open(path)?),
+            file,
             pos: 0,
+            decoder,
+            binary,
         })
     }
+
+    /// Whether the start of the file looks like binary data.
+    pub fn looks_binary(&self) -> bool {
+        self.binary
+    }
 }
 
 impl LogReader for LogFile {
     fn seek(&mut self, pos: u64) -> Result<(), IoError> {
         self.file.seek(SeekFrom::Start(pos))?;
+        self.pos = pos;
         Ok(())
     }
 
@@ -33,19 +155,779 @@ impl LogReader for LogFile {
     }
 
     fn read_record(&mut self) -> Result<Option<String>, IoError> {
-        let mut line = String::new();
-        let ret = self.file.read_line(&mut line)?;
-        if ret == 0 {
-            Ok(None)
+        Ok(self.read_raw_record()?.map(|r| r.text.into_owned()))
+    }
+
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        self.decoder.read_line(&mut self.file, &mut self.pos)
+    }
+}
+
+/// Reads lines from any `BufRead`, such as standard input, a pipe, or an
+/// in-memory buffer.
+///
+/// Streams can't seek, so `seek` fails unless it is to the current position.
+pub struct StreamReader<B: BufRead> {
+    pub reader: B,
+    pub pos: u64,
+    decoder: LineDecoder,
+    binary: bool,
+}
+
+impl<B: BufRead> StreamReader<B> {
+    pub fn new(reader: B) -> StreamReader<B> {
+        let options = ReadOptions::default();
+        StreamReader {
+            reader,
+            pos: 0,
+            decoder: LineDecoder::new(options.encoding, options.max_record_size),
+            binary: false,
+        }
+    }
+
+    /// Create a reader with options, which waits for the start of the
+    /// stream to detect binary data and byte order marks.
+    pub fn with_options(mut reader: B, options: &ReadOptions) -> Result<StreamReader<B>, IoError> {
+        let (decoder, binary) = start_stream(&mut reader, options)?;
+        Ok(StreamReader {
+            reader,
+            pos: 0,
+            decoder,
+            binary,
+        })
+    }
+
+    /// Whether the start of the stream looks like binary data.
+    pub fn looks_binary(&self) -> bool {
+        self.binary
+    }
+}
+
+impl<B: BufRead> LogReader for StreamReader<B> {
+    fn seek(&mut self, pos: u64) -> Result<(), IoError> {
+        if pos == self.pos {
+            Ok(())
         } else {
-            self.pos += ret as u64;
-            if line.len() >= 2 && line.ends_with("\r\n") {
-                line.pop();
-                line.pop();
-            } else if line.len() >= 1 && line.ends_with("\n") {
-                line.pop();
+            Err(IoError::new(
+                ErrorKind::Unsupported,
+                format!("Can't seek to offset {} in a stream", pos),
+            ))
+        }
+    }
+
+    fn tell(&self) -> u64 {
+        self.pos
+    }
+
+    fn read_record(&mut self) -> Result<Option<String>, IoError> {
+        Ok(self.read_raw_record()?.map(|r| r.text.into_owned()))
+    }
+
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        self.decoder.read_line(&mut self.reader, &mut self.pos)
+    }
+}
+
+/// Reads lines from a memory-mapped file.
+///
+/// Lines of UTF-8 text are found with a fast byte search and handed out
+/// borrowed from the mapping, so records the view skips are never copied.
+/// Other encodings are decoded like `LogFile` does.
+///
+/// The file should not be modified while it is mapped, and data appended to
+/// it after opening is not seen.
+#[cfg(feature = "mmap")]
+pub struct MmapLogFile {
+    /// The mapping, `None` for an empty file which can't be mapped
+    map: Option<memmap2::Mmap>,
+    pub pos: u64,
+    decoder: LineDecoder,
+    encoding: Encoding,
+    max_record_size: Option<usize>,
+    binary: bool,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapLogFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MmapLogFile, IoError> {
+        MmapLogFile::open_with_options(path, &Default::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: &ReadOptions,
+    ) -> Result<MmapLogFile, IoError> {
+        let file = fs::File::open(path)?;
+        let map = if file.metadata()?.len() == 0 {
+            None
+        } else {
+            // Safety: the mapping is only read, changes to the file while
+            // it is open are the caller's responsibility as documented
+            Some(unsafe { memmap2::Mmap::map(&file)? })
+        };
+        let data = map.as_deref().unwrap_or(&[]);
+        // Look at the same amount of data a buffered reader would
+        let (decoder, binary) = start_stream(&mut &data[..data.len().min(8192)], options)?;
+        Ok(MmapLogFile {
+            map,
+            pos: 0,
+            decoder,
+            encoding: options.encoding,
+            max_record_size: options.max_record_size,
+            binary,
+        })
+    }
+
+    /// Whether the start of the file looks like binary data.
+    pub fn looks_binary(&self) -> bool {
+        self.binary
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl LogReader for MmapLogFile {
+    fn seek(&mut self, pos: u64) -> Result<(), IoError> {
+        self.pos = pos;
+        Ok(())
+    }
+
+    fn tell(&self) -> u64 {
+        self.pos
+    }
+
+    fn read_record(&mut self) -> Result<Option<String>, IoError> {
+        Ok(self.read_raw_record()?.map(|r| r.text.into_owned()))
+    }
+
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        let data = self.map.as_deref().unwrap_or(&[]);
+        let start = self.pos.min(data.len() as u64) as usize;
+        let rest = &data[start..];
+        if rest.is_empty() {
+            return Ok(None);
+        }
+        if self.encoding != Encoding::Utf8 {
+            return self.decoder.read_line(&mut &rest[..], &mut self.pos);
+        }
+
+        // The limit doesn't count the line ending, like LogFile
+        let used = match memchr::memchr(b'\n', rest) {
+            Some(i) => i + 1,
+            None => rest.len(),
+        };
+        self.pos += used as u64;
+        let mut line = &rest[..used];
+        if line.ends_with(b"\r\n") {
+            line = &line[..line.len() - 2];
+        } else if line.ends_with(b"\n") {
+            line = &line[..line.len() - 1];
+        }
+        let limit = self.max_record_size.unwrap_or(usize::MAX);
+        let truncated = line.len() > limit;
+        if truncated {
+            line = &line[..limit];
+            // Don't count a character cut in the middle as invalid
+            if let Err(e) = std::str::from_utf8(line) {
+                if e.error_len().is_none() {
+                    line = &line[..e.valid_up_to()];
+                }
+            }
+        }
+        if start == 0 && line.starts_with("\u{FEFF}".as_bytes()) {
+            line = &line[3..];
+        }
+        let text = String::from_utf8_lossy(line);
+        let lossy = matches!(text, Cow::Owned(_));
+        Ok(Some(RawRecord {
+            lossy,
+            truncated,
+            ..RawRecord::new(text)
+        }))
+    }
+}
+
+impl<R: LogReader + ?Sized> LogReader for Box<R> {
+    fn seek(&mut self, pos: u64) -> Result<(), IoError> {
+        (**self).seek(pos)
+    }
+
+    fn tell(&self) -> u64 {
+        (**self).tell()
+    }
+
+    fn read_record(&mut self) -> Result<Option<String>, IoError> {
+        (**self).read_record()
+    }
+
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        (**self).read_raw_record()
+    }
+}
+
+/// Append text to a record joined from several lines, cutting it at the
+/// maximum record size.
+fn append_capped(record: &mut RawRecord<'_>, text: &str, max_size: Option<usize>) {
+    let joined = record.text.to_mut();
+    let room = max_size.unwrap_or(usize::MAX).saturating_sub(joined.len());
+    if text.len() <= room {
+        joined.push_str(text);
+    } else {
+        let mut end = room;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        joined.push_str(&text[..end]);
+        record.truncated = true;
+    }
+}
+
+/// Reads delimiter-separated records, where quoted fields can span lines.
+///
+/// Lines are joined while a `"` is left open, following RFC 4180 where a
+/// quote inside a quoted field is written `""`. Use with the `csv` parse
+/// format to get the fields.
+pub struct CsvReader<R: LogReader> {
+    pub inner: R,
+    /// Joined records longer than this many bytes are truncated
+    pub max_record_size: Option<usize>,
+}
+
+impl<R: LogReader> CsvReader<R> {
+    pub fn new(inner: R) -> CsvReader<R> {
+        CsvReader { inner, max_record_size: Some(DEFAULT_MAX_RECORD_SIZE) }
+    }
+}
+
+impl<R: LogReader> LogReader for CsvReader<R> {
+    fn seek(&mut self, pos: u64) -> Result<(), IoError> {
+        self.inner.seek(pos)
+    }
+
+    fn tell(&self) -> u64 {
+        self.inner.tell()
+    }
+
+    fn read_record(&mut self) -> Result<Option<String>, IoError> {
+        Ok(self.read_raw_record()?.map(|r| r.text.into_owned()))
+    }
+
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        let mut record = match self.inner.read_raw_record()? {
+            Some(line) => line.into_owned(),
+            None => return Ok(None),
+        };
+        let mut quotes = record.text.matches('"').count();
+        while quotes % 2 == 1 {
+            match self.inner.read_raw_record()? {
+                Some(line) => {
+                    quotes += line.text.matches('"').count();
+                    append_capped(&mut record, "\n", self.max_record_size);
+                    append_capped(&mut record, &line.text, self.max_record_size);
+                    record.lossy |= line.lossy;
+                    record.truncated |= line.truncated;
+                }
+                // Unterminated quote at the end of the file
+                None => break,
+            }
+        }
+        Ok(Some(record))
+    }
+}
+
+/// A line of a container log, holding a chunk of a record's text.
+struct Chunk {
+    stream: Option<String>,
+    time: Option<String>,
+    text: String,
+    /// Whether the chunk ends the record
+    last: bool,
+}
+
+/// Joins the chunks of container log records.
+///
+/// Each stream has its own partial record, since the runtime can interleave
+/// the chunks of stdout and stderr. Records that are complete are queued with
+/// their position instead of seeking back, so that streams can be read.
+#[derive(Default)]
+struct Chunks {
+    /// Partial record of each stream, with the position of its first chunk
+    partial: Vec<(Option<String>, u64, RawRecord<'static>)>,
+    /// Records to return before reading more lines
+    ready: VecDeque<(u64, RawRecord<'static>)>,
+}
+
+impl Chunks {
+    fn seek<R: LogReader>(&mut self, inner: &mut R, pos: u64) -> Result<(), IoError> {
+        self.partial.clear();
+        self.ready.clear();
+        inner.seek(pos)
+    }
+
+    /// Position of the next record, or of the oldest partial one.
+    fn tell<R: LogReader>(&self, inner: &R) -> u64 {
+        self.ready.front().map(|(pos, _)| *pos)
+            .or_else(|| self.partial.first().map(|(_, pos, _)| *pos))
+            .unwrap_or_else(|| inner.tell())
+    }
+
+    /// Queue the partial records, oldest first.
+    fn flush(&mut self) {
+        for (_, pos, record) in self.partial.drain(..) {
+            self.ready.push_back((pos, record));
+        }
+    }
+
+    fn read<R: LogReader, F: Fn(&str) -> Option<Chunk>>(
+        &mut self,
+        inner: &mut R,
+        max_size: Option<usize>,
+        parse: F,
+    ) -> Result<Option<RawRecord<'static>>, IoError> {
+        loop {
+            if let Some((_, record)) = self.ready.pop_front() {
+                return Ok(Some(record));
+            }
+            let pos = inner.tell();
+            let line = match inner.read_raw_record()? {
+                Some(line) => line,
+                // Partial lines at the end of the file
+                None if self.partial.is_empty() => return Ok(None),
+                None => {
+                    self.flush();
+                    continue;
+                }
+            };
+            let Chunk { stream, time, text, last } = match parse(&line.text) {
+                Some(chunk) => chunk,
+                None => {
+                    // Not from a container, pass it through after the partial
+                    // records
+                    self.flush();
+                    self.ready.push_back((pos, line.into_owned()));
+                    continue;
+                }
+            };
+            let index = match self.partial.iter().position(|(s, _, _)| *s == stream) {
+                Some(index) => index,
+                None => {
+                    let mut record = RawRecord::new(String::new());
+                    record.fields.extend(stream.clone().map(|s| ("stream".to_owned(), s)));
+                    record.fields.extend(time.map(|t| ("time".to_owned(), t)));
+                    self.partial.push((stream, pos, record));
+                    self.partial.len() - 1
+                }
+            };
+            let record = &mut self.partial[index].2;
+            record.lossy |= line.lossy;
+            record.truncated |= line.truncated;
+            append_capped(record, &text, max_size);
+            if last {
+                return Ok(Some(self.partial.remove(index).2));
+            }
+        }
+    }
+}
+
+/// Reads container logs written by Docker's `json-file` driver, where each
+/// line is `{"log": "...", "stream": "stdout", "time": "..."}`.
+///
+/// Records get `stream` and `time` variables. Lines that Docker split
+/// because they were too long are put back together.
+#[cfg(feature = "json")]
+pub struct DockerJsonReader<R: LogReader> {
+    pub inner: R,
+    /// Joined records longer than this many bytes are truncated
+    pub max_record_size: Option<usize>,
+    chunks: Chunks,
+}
+
+#[cfg(feature = "json")]
+impl<R: LogReader> DockerJsonReader<R> {
+    pub fn new(inner: R) -> DockerJsonReader<R> {
+        DockerJsonReader {
+            inner,
+            max_record_size: Some(DEFAULT_MAX_RECORD_SIZE),
+            chunks: Default::default(),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+#[derive(serde_derive::Deserialize)]
+struct DockerLine {
+    log: String,
+    #[serde(default)]
+    stream: Option<String>,
+    #[serde(default)]
+    time: Option<String>,
+}
+
+#[cfg(feature = "json")]
+impl<R: LogReader> LogReader for DockerJsonReader<R> {
+    fn seek(&mut self, pos: u64) -> Result<(), IoError> {
+        self.chunks.seek(&mut self.inner, pos)
+    }
+
+    fn tell(&self) -> u64 {
+        self.chunks.tell(&self.inner)
+    }
+
+    fn read_record(&mut self) -> Result<Option<String>, IoError> {
+        Ok(self.read_raw_record()?.map(|r| r.text.into_owned()))
+    }
+
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        self.chunks.read(&mut self.inner, self.max_record_size, |line| {
+            let DockerLine { mut log, stream, time } = serde_json::from_str(line).ok()?;
+            // Docker splits long lines into chunks, only the last one ends
+            // with a newline
+            let last = log.ends_with('\n');
+            if last {
+                log.pop();
+                if log.ends_with('\r') {
+                    log.pop();
+                }
+            }
+            Some(Chunk { stream, time, text: log, last })
+        })
+    }
+}
+
+/// Reads container logs in the CRI format used by Kubernetes, where each
+/// line is `<time> <stream> <tag> <message>`.
+///
+/// Records get `stream` and `time` variables. Partial lines (tag `P`) are
+/// joined with the following ones of the same stream, up to the final one
+/// (tag `F`).
+pub struct CriReader<R: LogReader> {
+    pub inner: R,
+    /// Joined records longer than this many bytes are truncated
+    pub max_record_size: Option<usize>,
+    chunks: Chunks,
+}
+
+impl<R: LogReader> CriReader<R> {
+    pub fn new(inner: R) -> CriReader<R> {
+        CriReader {
+            inner,
+            max_record_size: Some(DEFAULT_MAX_RECORD_SIZE),
+            chunks: Default::default(),
+        }
+    }
+}
+
+impl<R: LogReader> LogReader for CriReader<R> {
+    fn seek(&mut self, pos: u64) -> Result<(), IoError> {
+        self.chunks.seek(&mut self.inner, pos)
+    }
+
+    fn tell(&self) -> u64 {
+        self.chunks.tell(&self.inner)
+    }
+
+    fn read_record(&mut self) -> Result<Option<String>, IoError> {
+        Ok(self.read_raw_record()?.map(|r| r.text.into_owned()))
+    }
+
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        self.chunks.read(&mut self.inner, self.max_record_size, |line| {
+            let mut parts = line.splitn(4, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(time), Some(stream), Some(tag)) if tag == "P" || tag == "F" => {
+                    Some(Chunk {
+                        stream: Some(stream.to_owned()),
+                        time: Some(time.to_owned()),
+                        text: parts.next().unwrap_or("").to_owned(),
+                        last: tag == "F",
+                    })
+                }
+                // Not CRI
+                _ => None,
+            }
+        })
+    }
+}
+
+/// Reads the journal export format, from `journalctl -o export`.
+///
+/// Each entry becomes a record, with `MESSAGE` as its text and the other
+/// fields as variables (e.g. `_SYSTEMD_UNIT`, `PRIORITY`,
+/// `__REALTIME_TIMESTAMP`). Binary fields are decoded as lossy UTF-8.
+///
+/// Positions are those of the start of entries; seeking into the middle of
+/// an entry skips to the next one. Seeking backward reads from the start of
+/// the file.
+pub struct JournalExportFile {
+    pub file: BufReader<fs::File>,
+    pub pos: u64,
+    /// Binary fields longer than this many bytes are truncated
+    pub max_field_size: Option<usize>,
+}
+
+impl JournalExportFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<JournalExportFile, IoError> {
+        Ok(JournalExportFile {
+            file: BufReader::new(fs::File::open(path)?),
+            pos: 0,
+            max_field_size: Some(DEFAULT_MAX_RECORD_SIZE),
+        })
+    }
+
+    /// Read a line, without the newline. Returns `None` at the end of file.
+    ///
+    /// Like binary fields, lines are truncated to `max_field_size`; the
+    /// returned flag is set if this one was.
+    fn read_line(&mut self) -> Result<Option<(Vec<u8>, bool)>, IoError> {
+        let limit = self.max_field_size.map_or(u64::MAX, |max| max as u64);
+        let mut line = Vec::new();
+        let ret = (&mut self.file).take(limit.saturating_add(1)).read_until(b'\n', &mut line)?;
+        if ret == 0 {
+            return Ok(None);
+        }
+        self.pos += ret as u64;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            return Ok(Some((line, false)));
+        } else if line.len() as u64 <= limit {
+            // Last line, without a newline
+            return Ok(Some((line, false)));
+        }
+        // Skip the rest of the line
+        line.truncate(limit as usize);
+        loop {
+            let buf = self.file.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let (used, done) = match buf.iter().position(|&b| b == b'\n') {
+                Some(newline) => (newline + 1, true),
+                None => (buf.len(), false),
+            };
+            self.file.consume(used);
+            self.pos += used as u64;
+            if done {
+                break;
+            }
+        }
+        Ok(Some((line, true)))
+    }
+
+    /// Read the size of a binary field.
+    fn read_size(&mut self) -> Result<u64, IoError> {
+        let mut size = [0u8; 8];
+        self.file.read_exact(&mut size)?;
+        self.pos += 8;
+        Ok(u64::from_le_bytes(size))
+    }
+
+    /// Read past `size` bytes of binary data and the newline after them.
+    fn skip_data(&mut self, size: u64) -> Result<(), IoError> {
+        let skipped = std::io::copy(
+            &mut (&mut self.file).take(size),
+            &mut std::io::sink(),
+        )?;
+        self.pos += skipped;
+        if skipped < size {
+            return Err(past_end());
+        }
+        let mut newline = [0u8];
+        self.pos += self.file.read(&mut newline)? as u64;
+        Ok(())
+    }
+
+    /// Read past an entry, without decoding it. Returns `false` at the end of
+    /// file.
+    fn skip_entry(&mut self) -> Result<bool, IoError> {
+        let mut empty = true;
+        while let Some((line, _)) = self.read_line()? {
+            if line.is_empty() {
+                if empty {
+                    // Extra separator
+                    continue;
+                }
+                return Ok(true);
+            }
+            empty = false;
+            if !line.contains(&b'=') {
+                // Binary field: little-endian size, data, newline
+                let size = self.read_size()?;
+                self.skip_data(size)?;
+            }
+        }
+        Ok(!empty)
+    }
+}
+
+fn past_end() -> IoError {
+    IoError::new(
+        ErrorKind::InvalidData,
+        "Binary field runs past the end of the journal export",
+    )
+}
+
+impl LogReader for JournalExportFile {
+    fn seek(&mut self, pos: u64) -> Result<(), IoError> {
+        // Binary data can hold anything, including empty lines, so entries
+        // are only found by reading them from a known start: the current
+        // position, or the start of the file
+        if pos < self.pos {
+            self.file.seek(SeekFrom::Start(0))?;
+            self.pos = 0;
+        }
+        while self.pos < pos {
+            if !self.skip_entry()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn tell(&self) -> u64 {
+        self.pos
+    }
+
+    fn read_record(&mut self) -> Result<Option<String>, IoError> {
+        Ok(self.read_raw_record()?.map(|r| r.text.into_owned()))
+    }
+
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        let mut message = None;
+        let mut fields = Vec::new();
+        let mut truncated = false;
+        while let Some((mut line, cut)) = self.read_line()? {
+            if line.is_empty() {
+                if message.is_none() && fields.is_empty() {
+                    // Extra separator
+                    continue;
+                }
+                break;
+            }
+            let value = match line.iter().position(|&b| b == b'=') {
+                Some(eq) => {
+                    let value = line[eq + 1..].to_vec();
+                    line.truncate(eq);
+                    truncated |= cut && line == b"MESSAGE";
+                    value
+                }
+                None => {
+                    // Binary field: little-endian size, data, newline
+                    let size = self.read_size()?;
+                    let keep = match self.max_field_size {
+                        Some(max) if (max as u64) < size => {
+                            truncated |= line == b"MESSAGE";
+                            max as u64
+                        }
+                        _ => size,
+                    };
+                    let mut data = Vec::new();
+                    (&mut self.file).take(keep).read_to_end(&mut data)?;
+                    self.pos += data.len() as u64;
+                    if (data.len() as u64) < keep {
+                        return Err(past_end());
+                    }
+                    // Skip the rest and the newline
+                    self.skip_data(size - keep)?;
+                    data
+                }
+            };
+            let name = String::from_utf8_lossy(&line);
+            if name == "MESSAGE" {
+                message = Some(value);
+            } else {
+                fields.push((name.into_owned(), String::from_utf8_lossy(&value).into_owned()));
+            }
+        }
+        if message.is_none() && fields.is_empty() {
+            return Ok(None);
+        }
+        let message = message.unwrap_or_default();
+        let text = String::from_utf8_lossy(&message);
+        Ok(Some(RawRecord {
+            lossy: matches!(text, Cow::Owned(_)),
+            text: Cow::Owned(text.into_owned()),
+            fields,
+            truncated,
+        }))
+    }
+}
+
+/// Reads journal entries in JSON, from `journalctl -o json`.
+///
+/// Like `JournalExportFile`, `MESSAGE` is the text of the record and other
+/// fields are variables. Binary values (arrays of bytes) are decoded as lossy
+/// UTF-8, and only the first value of fields set multiple times is kept.
+#[cfg(feature = "json")]
+pub struct JournalJsonReader<R: LogReader> {
+    pub inner: R,
+}
+
+#[cfg(feature = "json")]
+impl<R: LogReader> JournalJsonReader<R> {
+    pub fn new(inner: R) -> JournalJsonReader<R> {
+        JournalJsonReader { inner }
+    }
+}
+
+#[cfg(feature = "json")]
+fn journal_json_value(value: serde_json::Value) -> String {
+    use serde_json::Value;
+
+    match value {
+        Value::String(s) => s,
+        Value::Null => String::new(),
+        Value::Array(array) => {
+            if array.iter().all(|v| v.is_u64()) {
+                let bytes: Vec<u8> = array.iter()
+                    .map(|v| v.as_u64().unwrap() as u8)
+                    .collect();
+                String::from_utf8_lossy(&bytes).into_owned()
+            } else {
+                array.into_iter().next().map(journal_json_value).unwrap_or_default()
+            }
+        }
+        other => other.to_string(),
+    }
+}
+
+#[cfg(feature = "json")]
+impl<R: LogReader> LogReader for JournalJsonReader<R> {
+    fn seek(&mut self, pos: u64) -> Result<(), IoError> {
+        self.inner.seek(pos)
+    }
+
+    fn tell(&self) -> u64 {
+        self.inner.tell()
+    }
+
+    fn read_record(&mut self) -> Result<Option<String>, IoError> {
+        Ok(self.read_raw_record()?.map(|r| r.text.into_owned()))
+    }
+
+    fn read_raw_record(&mut self) -> Result<Option<RawRecord<'_>>, IoError> {
+        let line = match self.inner.read_raw_record()? {
+            Some(line) => line.into_owned(),
+            None => return Ok(None),
+        };
+        let entry: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(&line.text) {
+            Ok(e) => e,
+            // Not from the journal, pass it through
+            Err(_) => return Ok(Some(line)),
+        };
+        let mut message = String::new();
+        let mut fields = Vec::with_capacity(entry.len());
+        for (name, value) in entry {
+            let value = journal_json_value(value);
+            if name == "MESSAGE" {
+                message = value;
+            } else {
+                fields.push((name, value));
             }
-            Ok(Some(line))
         }
+        Ok(Some(RawRecord {
+            text: message.into(),
+            fields,
+            lossy: line.lossy,
+            truncated: line.truncated,
+        }))
     }
 }
@@ -0,0 +1,205 @@
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::filters::{LoadError, View};
+use crate::timestamp::format_timestamp;
+
+/// Information about a saved view, kept in its file next to the view.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ViewMetadata {
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub author: String,
+    /// Glob matching the log files the view is meant for, e.g. `access*.log`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_glob: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ViewInfo {
+    pub name: String,
+    #[serde(flatten)]
+    pub metadata: ViewMetadata,
+    /// Time the file was last modified, in ISO 8601 form
+    pub modified: Option<String>,
+    /// Why the view can't be read, when listing views
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct StoredView {
+    #[serde(flatten)]
+    pub info: ViewInfo,
+    pub view: View,
+}
+
+/// Error using the views store.
+#[derive(Debug)]
+pub enum StoreError {
+    /// Names can only use letters, digits, `-`, `_` and `.`, and can't start
+    /// with `.`
+    InvalidName(String),
+    NotFound(String),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Load(LoadError),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoreError::InvalidName(name) => write!(f, "Invalid view name {:?}", name),
+            StoreError::NotFound(name) => write!(f, "No saved view named {}", name),
+            StoreError::Io(e) => write!(f, "I/O error: {}", e),
+            StoreError::Json(e) => write!(f, "Invalid view: {}", e),
+            StoreError::Load(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> StoreError {
+        StoreError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> StoreError {
+        StoreError::Json(e)
+    }
+}
+
+/// A directory of named views, each saved as `NAME.json`.
+///
+/// The files are regular view files with extra metadata fields, so they can
+/// also be used directly, and can include each other by file name.
+pub struct ViewStore {
+    dir: PathBuf,
+}
+
+impl ViewStore {
+    /// Use a directory, which is created when the first view is saved.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> ViewStore {
+        ViewStore { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, StoreError> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if !valid {
+            return Err(StoreError::InvalidName(name.to_owned()));
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+
+    fn read(&self, name: &str) -> Result<(ViewInfo, Vec<u8>), StoreError> {
+        let path = self.path(name)?;
+        let data = match std::fs::read(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(StoreError::NotFound(name.to_owned()));
+            }
+            r => r?,
+        };
+        let modified = std::fs::metadata(&path)?.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| format_timestamp(d.as_secs() as i64));
+        let info = ViewInfo {
+            name: name.to_owned(),
+            metadata: serde_json::from_slice(&data)?,
+            modified,
+            error: None,
+        };
+        Ok((info, data))
+    }
+
+    /// List the saved views, by name.
+    ///
+    /// Views that can't be read are listed with the error, so one bad file
+    /// doesn't hide the others.
+    pub fn list(&self) -> Result<Vec<ViewInfo>, StoreError> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            r => r?,
+        };
+        let mut views = Vec::new();
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let name = match file_name.to_str().and_then(|n| n.strip_suffix(".json")) {
+                Some(name) if self.path(name).is_ok() => name,
+                _ => continue,
+            };
+            let info = match self.read(name) {
+                Ok((info, _)) => info,
+                Err(e) => ViewInfo {
+                    name: name.to_owned(),
+                    metadata: Default::default(),
+                    modified: None,
+                    error: Some(e.to_string()),
+                },
+            };
+            views.push(info);
+        }
+        views.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(views)
+    }
+
+    /// Get a saved view as it was written, without resolving its includes.
+    pub fn get(&self, name: &str) -> Result<StoredView, StoreError> {
+        let (info, data) = self.read(name)?;
+        let view = serde_json::from_slice(&data)?;
+        Ok(StoredView { info, view })
+    }
+
+    /// Load a saved view to run it, resolving its includes from the store
+    /// then from the search path.
+    pub fn load(&self, name: &str, search_path: &[PathBuf]) -> Result<View, StoreError> {
+        let path = self.path(name)?;
+        if !path.is_file() {
+            return Err(StoreError::NotFound(name.to_owned()));
+        }
+        View::load(&path, search_path).map_err(StoreError::Load)
+    }
+
+    /// Save a view, replacing any view with the same name.
+    pub fn save(
+        &self,
+        name: &str,
+        metadata: &ViewMetadata,
+        view: &View,
+    ) -> Result<ViewInfo, StoreError> {
+        let path = self.path(name)?;
+        let mut value = serde_json::to_value(view)?;
+        if let (Some(object), serde_json::Value::Object(fields)) =
+            (value.as_object_mut(), serde_json::to_value(metadata)?)
+        {
+            object.extend(fields);
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        // Write to a temporary file first, so readers never see a partial view
+        let temp = self.dir.join(format!(".{}.json.tmp", name));
+        std::fs::write(&temp, serde_json::to_vec_pretty(&value)?)?;
+        std::fs::rename(&temp, &path)?;
+        Ok(self.read(name)?.0)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), StoreError> {
+        match std::fs::remove_file(self.path(name)?) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(StoreError::NotFound(name.to_owned()))
+            }
+            r => Ok(r?),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error as IoError};
+
+#[cfg(feature = "json")]
+use serde_derive::{Serialize, Deserialize};
+
+use crate::filters::{Condition, Expression, Operation, Pattern, View};
+use crate::readers::LogReader;
+use crate::timestamp::parse_timestamp;
+
+const ISO_TIME: &str = r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?";
+const IPV4: &str = r"\d{1,3}(?:\.\d{1,3}){3}";
+const NUMBER: &str = r"-?\d+(?:\.\d+)?";
+const QUOTED: &str = r#""(?:[^"\\]|\\.)*""#;
+
+fn default_sample() -> usize {
+    1000
+}
+
+fn default_max_patterns() -> usize {
+    20
+}
+
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(rename_all = "camelCase"))]
+pub struct SuggestOptions {
+    /// Number of lines to read from the start of the log
+    #[cfg_attr(feature = "json", serde(default = "default_sample"))]
+    pub sample: usize,
+    /// Maximum number of patterns to suggest, most common first
+    #[cfg_attr(feature = "json", serde(default = "default_max_patterns"))]
+    pub max_patterns: usize,
+}
+
+impl Default for SuggestOptions {
+    fn default() -> SuggestOptions {
+        SuggestOptions {
+            sample: default_sample(),
+            max_patterns: default_max_patterns(),
+        }
+    }
+}
+
+/// A pattern matching a group of similar sample lines.
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct Suggestion {
+    pub pattern: Pattern,
+    /// Number of sample lines that have this shape
+    pub count: usize,
+    pub example: String,
+}
+
+/// The kind of a token, which lines must agree on to be grouped together.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Kind {
+    Time,
+    /// Timestamp in brackets, e.g. `[27/Nov/2020:00:15:12 +0000]`
+    BracketTime,
+    Bracket,
+    Ip,
+    Number,
+    Quoted,
+    KeyValue { key: String, quoted: bool },
+    Word,
+    /// Free text until the end of the line
+    Rest,
+}
+
+struct Token<'a> {
+    /// Whitespace before the token
+    space: &'a str,
+    kind: Kind,
+    text: &'a str,
+}
+
+struct Tokenizer {
+    time: Regex,
+    bracket: Regex,
+    quoted: Regex,
+    key_value: Regex,
+    ip: Regex,
+    number: Regex,
+}
+
+impl Tokenizer {
+    fn new() -> Tokenizer {
+        Tokenizer {
+            time: Regex::new(&format!(r"^{}", ISO_TIME)).unwrap(),
+            bracket: Regex::new(r"^\[[^\]]*\]").unwrap(),
+            quoted: Regex::new(&format!("^{}", QUOTED)).unwrap(),
+            key_value: Regex::new(&format!(r#"^([A-Za-z_][\w.]*)=({}|\S*)"#, QUOTED)).unwrap(),
+            ip: Regex::new(&format!("^{}$", IPV4)).unwrap(),
+            number: Regex::new(&format!("^{}$", NUMBER)).unwrap(),
+        }
+    }
+
+    fn tokenize<'a>(&self, line: &'a str) -> Vec<Token<'a>> {
+        let mut tokens = Vec::new();
+        let mut rest = line;
+        loop {
+            let trimmed = rest.trim_start();
+            let space = &rest[..rest.len() - trimmed.len()];
+            rest = trimmed;
+            if rest.is_empty() {
+                break;
+            }
+
+            let (kind, len) = if let Some(m) = self.time.find(rest) {
+                (Kind::Time, m.end())
+            } else if let Some(m) = self.bracket.find(rest) {
+                if parse_timestamp(&rest[1..m.end() - 1]).is_some() {
+                    (Kind::BracketTime, m.end())
+                } else {
+                    (Kind::Bracket, m.end())
+                }
+            } else if let Some(m) = self.quoted.find(rest) {
+                (Kind::Quoted, m.end())
+            } else if let Some(c) = self.key_value.captures(rest) {
+                let kind = Kind::KeyValue {
+                    key: c[1].to_owned(),
+                    quoted: c[2].starts_with('"'),
+                };
+                (kind, c.get(0).unwrap().end())
+            } else {
+                let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let word = &rest[..len];
+                if self.ip.is_match(word) {
+                    (Kind::Ip, len)
+                } else if self.number.is_match(word) {
+                    (Kind::Number, len)
+                } else {
+                    (Kind::Word, len)
+                }
+            };
+            tokens.push(Token { space, kind, text: &rest[..len] });
+            rest = &rest[len..];
+        }
+
+        // Collapse free text at the end of the line, i.e. the longest run of
+        // words and numbers starting with a word
+        let mut start = tokens.len();
+        while start > 0 && [Kind::Word, Kind::Number].contains(&tokens[start - 1].kind) {
+            start -= 1;
+        }
+        while start < tokens.len() && tokens[start].kind != Kind::Word {
+            start += 1;
+        }
+        if start < tokens.len() {
+            let offset = tokens[start].text.as_ptr() as usize - line.as_ptr() as usize;
+            let space = tokens[start].space;
+            tokens.truncate(start);
+            tokens.push(Token { space, kind: Kind::Rest, text: &line[offset..] });
+        }
+        tokens
+    }
+}
+
+/// Lines that have the same sequence of token kinds.
+struct Cluster {
+    count: usize,
+    example: String,
+    /// Separator before each token, if it is always the same
+    spaces: Vec<Option<String>>,
+    /// Text of each token, if it is always the same
+    texts: Vec<Option<String>>,
+}
+
+fn group_name(kind: &Kind) -> String {
+    let name = match kind {
+        Kind::Time | Kind::BracketTime => "time",
+        Kind::Bracket => "bracket",
+        Kind::Ip => "ip",
+        Kind::Number => "number",
+        Kind::Quoted => "string",
+        Kind::KeyValue { key, .. } => {
+            let name: String = key.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            return name;
+        }
+        Kind::Word => "field",
+        Kind::Rest => "message",
+    };
+    name.to_owned()
+}
+
+fn build_pattern(kinds: &[Kind], cluster: &Cluster) -> String {
+    let mut regex = "^".to_owned();
+    // Names already used, a suffix is added to repeated ones until they are
+    // free, since a key can look like a suffixed name (`a`, `a2`, `a`)
+    let mut names: HashSet<String> = HashSet::new();
+    let mut group = |kind: &Kind| {
+        let base = group_name(kind);
+        let mut name = base.clone();
+        let mut count = 1;
+        while names.contains(&name) {
+            count += 1;
+            name = format!("{}{}", base, count);
+        }
+        names.insert(name.clone());
+        name
+    };
+    for (i, kind) in kinds.iter().enumerate() {
+        match &cluster.spaces[i] {
+            Some(space) => regex.push_str(&regex::escape(space)),
+            None => regex.push_str(r"\s+"),
+        }
+        let fragment = match (kind, &cluster.texts[i]) {
+            // Keep literal words, which are probably part of the format
+            (Kind::Word, Some(text)) => regex::escape(text),
+            (Kind::Word, None) => format!(r"(?P<{}>\S+)", group(kind)),
+            (Kind::Time, _) => format!("(?P<{}>{})", group(kind), ISO_TIME),
+            (Kind::BracketTime, _) => format!(r"\[(?P<{}>[^\]]+)\]", group(kind)),
+            (Kind::Bracket, _) => format!(r"\[(?P<{}>[^\]]*)\]", group(kind)),
+            (Kind::Ip, _) => format!("(?P<{}>{})", group(kind), IPV4),
+            (Kind::Number, _) => format!("(?P<{}>{})", group(kind), NUMBER),
+            (Kind::Quoted, _) => format!(r#""(?P<{}>(?:[^"\\]|\\.)*)""#, group(kind)),
+            (Kind::KeyValue { key, quoted: true }, _) => format!(
+                r#"{}="(?P<{}>(?:[^"\\]|\\.)*)""#,
+                regex::escape(key), group(kind),
+            ),
+            (Kind::KeyValue { key, quoted: false }, _) => format!(
+                r"{}=(?P<{}>\S*)",
+                regex::escape(key), group(kind),
+            ),
+            (Kind::Rest, _) => format!("(?P<{}>.*)", group(kind)),
+        };
+        regex.push_str(&fragment);
+    }
+    regex.push('$');
+    regex
+}
+
+/// Group sample lines from the reader by shape and suggest a pattern for
+/// each group, most common first.
+pub fn suggest_patterns<R: LogReader>(
+    reader: &mut R,
+    options: &SuggestOptions,
+) -> Result<Vec<Suggestion>, IoError> {
+    let tokenizer = Tokenizer::new();
+    let mut clusters: HashMap<Vec<Kind>, Cluster> = HashMap::new();
+    for _ in 0..options.sample {
+        let line = match reader.read_record()? {
+            Some(l) => l,
+            None => break,
+        };
+        let tokens = tokenizer.tokenize(&line);
+        let kinds: Vec<Kind> = tokens.iter().map(|t| t.kind.clone()).collect();
+        match clusters.get_mut(&kinds) {
+            Some(cluster) => {
+                cluster.count += 1;
+                for (i, token) in tokens.iter().enumerate() {
+                    if cluster.spaces[i].as_deref() != Some(token.space) {
+                        cluster.spaces[i] = None;
+                    }
+                    if cluster.texts[i].as_deref() != Some(token.text) {
+                        cluster.texts[i] = None;
+                    }
+                }
+            }
+            None => {
+                let cluster = Cluster {
+                    count: 1,
+                    spaces: tokens.iter().map(|t| Some(t.space.to_owned())).collect(),
+                    texts: tokens.iter().map(|t| Some(t.text.to_owned())).collect(),
+                    example: line.clone(),
+                };
+                clusters.insert(kinds, cluster);
+            }
+        }
+    }
+
+    let mut clusters: Vec<(Vec<Kind>, Cluster)> = clusters.into_iter().collect();
+    clusters.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.1.example.cmp(&b.1.example)));
+    clusters.truncate(options.max_patterns);
+    Ok(clusters.into_iter().map(|(kinds, cluster)| Suggestion {
+        pattern: Pattern::new(build_pattern(&kinds, &cluster)),
+        count: cluster.count,
+        example: cluster.example,
+    }).collect())
+}
+
+/// Build a starter view trying each suggested pattern in turn.
+pub fn suggest_view(suggestions: &[Suggestion]) -> View {
+    let mut else_ops = Vec::new();
+    for suggestion in suggestions.iter().rev() {
+        else_ops = vec![Operation::If {
+            condition: Condition::Match {
+                expression: Expression::Record,
+                pattern: suggestion.pattern.clone(),
+            },
+            then_ops: vec![],
+            else_ops,
+        }];
+    }
+    View {
+        operations: else_ops,
+        ..Default::default()
+    }
+}
@@ -1,6 +1,65 @@
-use crate::filters::{View, Operation, Expression, Condition, Pattern};
-use crate::process;
-use crate::readers::LogFile;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::aggregate::{Aggregation, Metric, aggregate};
+use crate::check::{WarningKind, check};
+use crate::cluster::{ClusterOptions, Drain, cluster};
+use crate::explain::{explain, explain_text};
+use crate::fields::{FieldsOptions, fields};
+use crate::filters::{
+    BlockError, Condition, DedupWindow, Expression, Operation, ParseFormat, Pattern, PatternError,
+    View,
+};
+#[cfg(feature = "json")]
+use crate::filters::LoadError;
+use crate::histogram::{HistogramOptions, histogram};
+use crate::program::{Expr, Op, Program};
+use crate::{ContextItem, process};
+use crate::readers::{
+    CriReader, CsvReader, DockerJsonReader, Encoding, JournalExportFile, JournalJsonReader,
+    LogFile, LogReader, RawRecord, ReadOptions, StreamReader,
+};
+#[cfg(feature = "mmap")]
+use crate::readers::MmapLogFile;
+#[cfg(feature = "json")]
+use crate::store::{StoreError, ViewMetadata, ViewStore};
+use crate::suggest::{suggest_patterns, suggest_view};
+use crate::timestamp::{format_timestamp, parse_timestamp};
+
+/// A file or directory for a test, named after the test and the process so
+/// that concurrent runs don't collide, and removed even if the test fails.
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> TempPath {
+        let name = format!("logviewer-test-{}-{}", std::process::id(), name);
+        TempPath(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.0.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.0);
+        } else {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+}
+
+impl std::ops::Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
 
 fn get_view() -> View {
     View {
@@ -79,6 +138,7 @@ fn get_view() -> View {
                 ],
             },
         ],
+        ..Default::default()
     }
 }
 
@@ -106,6 +166,1852 @@ fn test_process() {
         for (key, value) in &record.variables {
             println!("    {} = {:?}", key, value);
         }
-        println!("");
+        println!();
+    }
+}
+
+#[test]
+fn test_explain() {
+    let view = get_view();
+    let explanation = explain_text(
+        &view,
+        "2020-11-17T00:15:12Z service=web DEBUG getting metadata".to_owned(),
+    ).unwrap();
+    assert!(!explanation.kept);
+    assert_eq!(explanation.record.variables["service"], "web");
+    let printed = format!("{:?}", explanation.trace);
+    assert!(printed.contains("\n  ELIF variable message match "));
+    assert!(printed.ends_with(
+        "\nELIF record match \"\\bDEBUG\\b\" -> matched \"2020-11-17T00:15:12Z service=web DEBUG getting metadata\"\n  SKIP\n"
+    ));
+
+    let mut file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let explanation = explain(&mut file, &view, 0).unwrap().unwrap();
+    assert!(explanation.kept);
+    assert_eq!(explanation.record.variables["service"], "frontend");
+
+    // The records before the offset are evaluated, for the last values
+    let view = View {
+        operations: vec![
+            Operation::Set {
+                target: "previous".to_owned(),
+                expression: Expression::LastVarValue("value".to_owned()),
+            },
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^(?P<value>.*)$".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![],
+            },
+        ],
+        ..Default::default()
+    };
+    let mut reader = StreamReader::new("first\nsecond\n".as_bytes());
+    let explanation = explain(&mut reader, &view, 6).unwrap().unwrap();
+    assert_eq!(explanation.record.text, "second");
+    assert_eq!(explanation.record.variables["previous"], "first");
+    assert_eq!(explanation.trace.steps.len(), 3);
+
+    // Runs of duplicates started before the offset are tracked
+    let view = View {
+        operations: vec![Operation::CollapseRepeats {
+            key: Expression::Record,
+            count: "repeats".to_owned(),
+        }],
+        ..Default::default()
+    };
+    let mut reader = StreamReader::new("a\na\na\nb\n".as_bytes());
+    let explanation = explain(&mut reader, &view, 4).unwrap().unwrap();
+    assert_eq!(explanation.record.text, "a");
+    assert!(!explanation.kept);
+    let mut reader = StreamReader::new("a\na\na\nb\n".as_bytes());
+    let explanation = explain(&mut reader, &view, 6).unwrap().unwrap();
+    assert_eq!(explanation.record.text, "b");
+    assert!(explanation.kept);
+}
+
+#[test]
+fn test_context() {
+    let file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let view = View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("service=db".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![Operation::SkipRecord],
+            },
+        ],
+        ..Default::default()
+    };
+
+    let items: Vec<ContextItem> = process(file, view)
+        .with_context(1, 0)
+        .collect::<Result<_, _>>()
+        .expect("Error during processing");
+    let summary: Vec<String> = items.iter().map(|item| match item {
+        ContextItem::Record(r) if r.context => "context".to_owned(),
+        ContextItem::Record(r) => r.text[21..].to_owned(),
+        ContextItem::Separator => "--".to_owned(),
+    }).collect();
+    assert_eq!(
+        summary,
+        vec![
+            "context", "service=db querying", "--",
+            "context", "service=db querying",
+        ],
+    );
+}
+
+#[test]
+fn test_aggregate() {
+    let file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let mut view = get_view();
+    // Extract HTTP status and response size for frontend records
+    view.operations.push(Operation::If {
+        condition: Condition::Match {
+            expression: Expression::Var("message".to_owned()),
+            pattern: Pattern::new("\" (?P<status>[0-9]{3}) (?P<bytes>[0-9]+) ".to_owned()),
+        },
+        then_ops: vec![],
+        else_ops: vec![],
+    });
+    let aggregation = Aggregation {
+        group_by: vec!["service".to_owned()],
+        metrics: vec![
+            Metric::Count,
+            "sum:bytes".parse().unwrap(),
+            "max:bytes".parse().unwrap(),
+            "p50:bytes".parse().unwrap(),
+        ],
+    };
+    let result = aggregate(process(file, view), &aggregation)
+        .expect("Error during processing");
+    let printed = format!("{:?}", result);
+    assert!(printed.lines().next().unwrap().split_whitespace()
+        .eq(vec!["service", "count", "sum:bytes", "max:bytes", "p50:bytes"]));
+
+    let rows: Vec<(Option<&str>, &[Option<f64>])> = result.rows.iter()
+        .map(|r| (r.key[0].as_deref(), r.values.as_slice()))
+        .collect();
+    assert_eq!(rows, vec![
+        (Some("db"), &[Some(2.0), Some(0.0), None, None][..]),
+        (Some("frontend"), &[Some(4.0), Some(1580.0), Some(1192.0), Some(194.0)][..]),
+        (Some("web"), &[Some(4.0), Some(0.0), None, None][..]),
+    ]);
+
+    // Non-finite values are ignored, invalid percentiles are refused
+    let view = || View {
+        operations: vec![Operation::If {
+            condition: Condition::Match {
+                expression: Expression::Record,
+                pattern: Pattern::new("^(?P<value>.*)$".to_owned()),
+            },
+            then_ops: vec![],
+            else_ops: vec![],
+        }],
+        ..Default::default()
+    };
+    let aggregation = Aggregation {
+        group_by: vec![],
+        metrics: vec![Metric::Percentile("value".to_owned(), 50.0)],
+    };
+    let input = "3\nNaN\n1\ninf\n2".as_bytes();
+    let result = aggregate(process(StreamReader::new(input), view()), &aggregation).unwrap();
+    assert_eq!(result.rows[0].values, vec![Some(2.0)]);
+    let aggregation = Aggregation {
+        group_by: vec![],
+        metrics: vec![Metric::Percentile("value".to_owned(), 200.0)],
+    };
+    assert!(aggregation.check().is_err());
+    let input = "1".as_bytes();
+    assert!(aggregate(process(StreamReader::new(input), Default::default()), &aggregation).is_err());
+
+    // Percentiles of many values are estimated from a sample
+    let input: String = (0..50_000).map(|i| format!("{}\n", i)).collect();
+    let aggregation = Aggregation {
+        group_by: vec![],
+        metrics: vec![Metric::Percentile("value".to_owned(), 50.0), Metric::Count],
+    };
+    let result = aggregate(process(StreamReader::new(input.as_bytes()), view()), &aggregation)
+        .unwrap();
+    let median = result.rows[0].values[0].unwrap();
+    assert!((median - 25_000.0).abs() < 1_000.0, "median {}", median);
+    assert_eq!(result.rows[0].values[1], Some(50_000.0));
+
+    // The number of groups is limited
+    let aggregation = Aggregation {
+        group_by: vec!["value".to_owned()],
+        metrics: vec![Metric::Count],
+    };
+    let error = aggregate(process(StreamReader::new(input.as_bytes()), view()), &aggregation)
+        .unwrap_err();
+    assert_eq!(error.to_string(), "More than 10000 groups");
+}
+
+#[test]
+fn test_histogram() {
+    let file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let options = HistogramOptions {
+        split_by: Some("service".to_owned()),
+        buckets: 10,
+        ..Default::default()
+    };
+    let result = histogram(process(file, get_view()), &options)
+        .expect("Error during processing");
+
+    // 00:15:12 to 01:07:41 in 10 buckets -> 10 minutes each
+    assert_eq!(result.bucket_size, 600);
+    assert_eq!(result.start, 1605571800);
+    assert_eq!(result.totals(), vec![2, 0, 6, 0, 0, 2]);
+    let frontend = result.series.iter()
+        .find(|s| s.key.as_deref() == Some("frontend"))
+        .unwrap();
+    assert_eq!(frontend.counts, vec![1, 0, 2, 0, 0, 1]);
+
+    // Counting buckets are merged when there are too many
+    let input: String = (0..25_000).map(|i| format!("{}\n", 1605571200 + i)).collect();
+    let view = View {
+        operations: vec![Operation::If {
+            condition: Condition::Match {
+                expression: Expression::Record,
+                pattern: Pattern::new("^(?P<time>[0-9]+)$".to_owned()),
+            },
+            then_ops: vec![],
+            else_ops: vec![],
+        }],
+        ..Default::default()
+    };
+    let options = HistogramOptions {
+        buckets: 10,
+        ..Default::default()
+    };
+    let result = histogram(process(StreamReader::new(input.as_bytes()), view), &options)
+        .expect("Error during processing");
+    assert_eq!(result.bucket_size, 3600);
+    assert_eq!(result.start, 1605571200);
+    assert_eq!(result.totals(), vec![3600, 3600, 3600, 3600, 3600, 3600, 3400]);
+}
+
+#[test]
+fn test_timestamps() {
+    assert_eq!(parse_timestamp("2020-11-17T00:15:12Z"), Some(1605572112.0));
+    assert_eq!(parse_timestamp("2020-11-17 01:15:12.5+01:00"), Some(1605572112.5));
+    assert_eq!(parse_timestamp("17/Nov/2020:00:15:12 +0000"), Some(1605572112.0));
+    assert_eq!(parse_timestamp("1605572112000"), Some(1605572112.0));
+    assert_eq!(parse_timestamp("yesterday"), None);
+    for number in &["inf", "-inf", "NaN", "1e300", "-5"] {
+        assert_eq!(parse_timestamp(number), None);
+    }
+    assert_eq!(format_timestamp(1605572112), "2020-11-17T00:15:12Z");
+}
+
+#[test]
+fn test_fields() {
+    let file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let options = FieldsOptions {
+        top: 2,
+        capacity: 2,
+    };
+    let result = fields(process(file, get_view()), &options)
+        .expect("Error during processing");
+
+    assert_eq!(result.records, 10);
+    let names: Vec<&str> = result.fields.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["client", "message", "service", "time", "vhost"]);
+    let service = &result.fields[2];
+    assert_eq!(service.present, 10);
+    assert!(service.approximate);
+    let vhost = &result.fields[4];
+    assert_eq!(vhost.present, 4);
+    assert!(!vhost.approximate);
+    assert_eq!(vhost.top_values[0].value, "example.org");
+    assert_eq!(vhost.top_values[0].count, 4);
+    assert_eq!(vhost.top_values[0].error, 0);
+
+    // The lowest count is replaced, even after counts changed
+    let view = View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^(?P<v>.*)$".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![],
+            },
+        ],
+        ..Default::default()
+    };
+    let input = "a\nb\nb\nb\na\nc\nd\nd\n".as_bytes();
+    let result = fields(process(StreamReader::new(input), view), &options)
+        .expect("Error during processing");
+    let values: Vec<(&str, u64, u64)> = result.fields[0].top_values.iter()
+        .map(|v| (v.value.as_str(), v.count, v.error))
+        .collect();
+    assert_eq!(values, vec![("d", 5, 3), ("b", 3, 0)]);
+}
+
+#[test]
+fn test_suggest() {
+    let mut file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let suggestions = suggest_patterns(&mut file, &Default::default())
+        .expect("Error reading log");
+    assert_eq!(suggestions.len(), 2);
+    assert_eq!(suggestions[0].count, 6);
+    assert_eq!(suggestions[0].pattern.groups, vec!["time", "service", "message"]);
+    assert_eq!(
+        suggestions[1].pattern.groups,
+        vec![
+            "time", "ip", "time2", "string", "string2", "number", "number2",
+            "string3", "string4",
+        ],
+    );
+
+    // The suggested view should extract variables from every line
+    let view = suggest_view(&suggestions);
+    let file = LogFile::open("test.log").expect("Can't open test file test.log");
+    for record in process(file, view) {
+        let record = record.expect("Error during processing");
+        assert!(record.variables.contains_key("time"));
+    }
+
+    // Keys that look like suffixed names get free ones
+    let mut reader = StreamReader::new("a=1 a2=3 a=4\n".as_bytes());
+    let suggestions = suggest_patterns(&mut reader, &Default::default())
+        .expect("Error reading log");
+    assert_eq!(suggestions[0].pattern.groups, vec!["a", "a2", "a3"]);
+}
+
+#[test]
+fn test_cluster() {
+    let mut drain = Drain::default();
+    assert_eq!(drain.add("connection from 10.0.0.1 closed"), 0);
+    assert_eq!(drain.add("connection from 10.0.0.2 closed"), 0);
+    assert_eq!(drain.add("user admin logged in"), 1);
+    assert_eq!(drain.add("connection from 10.0.0.3 reset"), 0);
+    assert_eq!(drain.templates()[0].text(), "connection from <*> <*>");
+    assert_eq!(drain.templates()[0].count, 3);
+
+    // The least used template is replaced when there are too many
+    let mut drain = Drain::with_max_templates(0.4, 4, 2);
+    assert_eq!(drain.add("connection from 10.0.0.1 closed"), 0);
+    assert_eq!(drain.add("connection from 10.0.0.2 closed"), 0);
+    assert_eq!(drain.add("user admin logged in"), 1);
+    assert_eq!(drain.add("disk full"), 2);
+    assert_eq!(drain.add("user root logged in"), 3);
+    let templates: Vec<(usize, String)> = drain.templates().iter()
+        .map(|t| (t.id, t.text()))
+        .collect();
+    assert_eq!(templates, vec![
+        (0, "connection from <*> closed".to_owned()),
+        (3, "user root logged in".to_owned()),
+    ]);
+
+    // Skip records by template, using the Cluster operation
+    let file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let mut view = get_view();
+    view.operations.push(Operation::Cluster {
+        expression: Expression::Var("message".to_owned()),
+        target: "template".to_owned(),
+    });
+    view.operations.push(Operation::If {
+        condition: Condition::Match {
+            expression: Expression::Var("template".to_owned()),
+            pattern: Pattern::new("^0$".to_owned()),
+        },
+        then_ops: vec![Operation::SkipRecord],
+        else_ops: vec![],
+    });
+    let templates: Vec<String> = process(file, view)
+        .map(|r| r.expect("Error during processing").variables["template"].clone())
+        .collect();
+    // First record skipped, "showing frontpage" and "querying" repeated
+    assert_eq!(templates, vec!["1", "2", "3", "4", "5", "3", "4", "6", "7"]);
+
+    let file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let options = ClusterOptions {
+        variable: Some("message".to_owned()),
+        ..Default::default()
+    };
+    let result = cluster(file, get_view(), &options).expect("Error during processing");
+    assert_eq!(result.templates.len(), 8);
+    assert_eq!(result.templates[0].text(), "showing frontpage");
+    assert_eq!(result.templates[0].count, 2);
+}
+
+#[test]
+fn test_dedup() {
+    // Same service within a minute
+    let file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let mut view = get_view();
+    view.operations.push(Operation::Dedup {
+        key: Expression::Var("service".to_owned()),
+        window: DedupWindow::Seconds {
+            seconds: 60.0,
+            time: Expression::Var("time".to_owned()),
+        },
+    });
+    let services: Vec<String> = process(file, view)
+        .map(|r| r.expect("Error during processing").variables["service"].clone())
+        .collect();
+    assert_eq!(services, vec!["frontend", "web", "frontend", "web", "db", "frontend", "web"]);
+
+    // Same message within 3 records: "showing frontpage" and "querying" repeat
+    // 3 records later
+    let file = LogFile::open("test.log").expect("Can't open test file test.log");
+    let mut view = get_view();
+    view.operations.push(Operation::Dedup {
+        key: Expression::Var("message".to_owned()),
+        window: DedupWindow::Records(3),
+    });
+    assert_eq!(process(file, view).count(), 8);
+}
+
+#[test]
+fn test_collapse_repeats() {
+    let input = "a\na\na\nb\na\nskip\nc\nc\n".as_bytes();
+    let view = View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("skip".to_owned()),
+                },
+                then_ops: vec![Operation::SkipRecord],
+                else_ops: vec![],
+            },
+            Operation::CollapseRepeats {
+                key: Expression::Record,
+                count: "repeats".to_owned(),
+            },
+        ],
+        ..Default::default()
+    };
+    let records: Vec<(String, String)> = process(StreamReader::new(input), view)
+        .map(|r| {
+            let r = r.expect("Error during processing");
+            let repeats = r.variables["repeats"].clone();
+            (r.text, repeats)
+        })
+        .collect();
+    let expected: Vec<(String, String)> = vec![("a", "3"), ("b", "1"), ("a", "1"), ("c", "2")]
+        .into_iter()
+        .map(|(t, c)| (t.to_owned(), c.to_owned()))
+        .collect();
+    assert_eq!(records, expected);
+
+    // Duplicates are not shown as context, and the count is still set
+    let view = View {
+        operations: vec![Operation::CollapseRepeats {
+            key: Expression::Record,
+            count: "repeats".to_owned(),
+        }],
+        ..Default::default()
+    };
+    let items: Vec<String> = process(StreamReader::new("a\na\na\nb\nc".as_bytes()), view)
+        .with_context(1, 1)
+        .map(|i| match i.expect("Error during processing") {
+            ContextItem::Record(r) => format!("{} {}", r.text, r.variables["repeats"]),
+            ContextItem::Separator => "--".to_owned(),
+        })
+        .collect();
+    assert_eq!(items, vec!["a 3", "b 1", "c 1"]);
+
+    // Records skipped while a record is held are still output as context
+    let view = View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^s".to_owned()),
+                },
+                then_ops: vec![Operation::SkipRecord],
+                else_ops: vec![],
+            },
+            Operation::CollapseRepeats {
+                key: Expression::Record,
+                count: "repeats".to_owned(),
+            },
+        ],
+        ..Default::default()
+    };
+    let input = "a\ns1\ns2\ns3\ns4\na\ns5\nb".as_bytes();
+    let items: Vec<String> = process(StreamReader::new(input), view)
+        .with_context(1, 1)
+        .map(|i| match i.expect("Error during processing") {
+            ContextItem::Record(r) if r.context => r.text,
+            ContextItem::Record(r) => format!("{} {}", r.text, r.variables["repeats"]),
+            ContextItem::Separator => "--".to_owned(),
+        })
+        .collect();
+    assert_eq!(items, vec!["a 2", "s1", "--", "s5", "b 1"]);
+
+    // Each operation has its own runs, and a record that starts a run but is
+    // skipped later ends the run without starting another
+    let view = View {
+        operations: vec![
+            Operation::Set {
+                target: "first".to_owned(),
+                expression: Expression::Record,
+            },
+            Operation::CollapseRepeats {
+                key: Expression::Var("first".to_owned()),
+                count: "all".to_owned(),
+            },
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("skip".to_owned()),
+                },
+                then_ops: vec![Operation::SkipRecord],
+                else_ops: vec![],
+            },
+            Operation::CollapseRepeats {
+                key: Expression::Record,
+                count: "kept".to_owned(),
+            },
+        ],
+        ..Default::default()
+    };
+    let input = "a\na\nb\nskip\nskip\nc\nskip\nc\n".as_bytes();
+    let records: Vec<String> = process(StreamReader::new(input), view)
+        .map(|r| {
+            let r = r.expect("Error during processing");
+            format!("{} {} {}", r.text, r.variables["all"], r.variables["kept"])
+        })
+        .collect();
+    assert_eq!(records, vec!["a 2 1", "b 1 1", "c 1 2"]);
+}
+
+#[test]
+fn test_parse_formats() {
+    let fields = ParseFormat::Json
+        .parse(r#"{"level": "info", "http": {"status": 200, "ok": true}, "tags": ["a", "b"], "user": null}"#)
+        .unwrap();
+    let fields: HashMap<String, String> = fields.into_iter().collect();
+    assert_eq!(fields["level"], "info");
+    assert_eq!(fields["http.status"], "200");
+    assert_eq!(fields["http.ok"], "true");
+    assert_eq!(fields["tags.1"], "b");
+    assert_eq!(fields["user"], "");
+    assert!(ParseFormat::Json.parse("[1, 2]").is_none());
+    assert!(ParseFormat::Json.parse("not json").is_none());
+
+    assert_eq!(
+        ParseFormat::Logfmt.parse(r#"level=info msg="hello \"world\"" debug path=/a"#),
+        Some(vec![
+            ("level".to_owned(), "info".to_owned()),
+            ("msg".to_owned(), "hello \"world\"".to_owned()),
+            ("debug".to_owned(), "true".to_owned()),
+            ("path".to_owned(), "/a".to_owned()),
+        ]),
+    );
+    assert!(ParseFormat::Logfmt.parse("GET / HTTP/1.1 (200)").is_none());
+
+    assert_eq!(
+        ParseFormat::KeyValue.parse("request done: user='bob smith', status=404;took=3ms"),
+        Some(vec![
+            ("user".to_owned(), "bob smith".to_owned()),
+            ("status".to_owned(), "404".to_owned()),
+            ("took".to_owned(), "3ms".to_owned()),
+        ]),
+    );
+    assert!(ParseFormat::KeyValue.parse("no pairs here").is_none());
+}
+
+#[test]
+fn test_parse_operation() {
+    let view = View {
+        operations: vec![
+            Operation::Parse {
+                expression: Expression::Record,
+                format: ParseFormat::Json,
+                prefix: "json.".to_owned(),
+                else_ops: vec![
+                    Operation::Set {
+                        target: "invalid".to_owned(),
+                        expression: Expression::Constant("yes".to_owned()),
+                    },
+                ],
+            },
+        ],
+        ..Default::default()
+    };
+    let explanation = explain_text(&view, r#"{"a": {"b": "c"}}"#.to_owned()).unwrap();
+    assert_eq!(explanation.record.variables["json.a.b"], "c");
+    assert!(!explanation.record.variables.contains_key("invalid"));
+
+    let explanation = explain_text(&view, "plain text".to_owned()).unwrap();
+    assert_eq!(explanation.record.variables["invalid"], "yes");
+    assert_eq!(
+        format!("{:?}", explanation.trace),
+        "PARSE record AS json -> failed\n  SET invalid = \"yes\"\n",
+    );
+}
+
+#[test]
+fn test_parse_server_formats() {
+    fn parse(format: ParseFormat, text: &str) -> HashMap<String, String> {
+        format.parse(text).unwrap().into_iter().collect()
+    }
+
+    let line = r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#;
+    let fields = parse(ParseFormat::ApacheCommon, line);
+    assert_eq!(fields["client"], "127.0.0.1");
+    assert!(!fields.contains_key("ident"));
+    assert_eq!(fields["user"], "frank");
+    assert_eq!(fields["time"], "10/Oct/2000:13:55:36 -0700");
+    assert_eq!(fields["method"], "GET");
+    assert_eq!(fields["path"], "/apache_pb.gif");
+    assert_eq!(fields["protocol"], "HTTP/1.0");
+    assert_eq!(fields["status"], "200");
+    assert_eq!(fields["bytes"], "2326");
+    assert!(ParseFormat::ApacheCombined.parse(line).is_none());
+
+    let line = r#"10.0.0.2 - - [10/Oct/2000:13:55:36 -0700] "POST /login HTTP/1.1" 302 - "http://example.com/" "Mozilla/5.0 (X11)" "1.2.3.4""#;
+    assert!(ParseFormat::ApacheCombined.parse(line).is_none());
+    let fields = parse(ParseFormat::Nginx, line);
+    assert_eq!(fields["status"], "302");
+    assert!(!fields.contains_key("bytes"));
+    assert_eq!(fields["referer"], "http://example.com/");
+    assert_eq!(fields["user_agent"], "Mozilla/5.0 (X11)");
+    assert_eq!(fields["forwarded_for"], "1.2.3.4");
+
+    let fields = parse(
+        ParseFormat::SyslogRfc3164,
+        "<34>Oct 11 22:14:15 mymachine su[230]: 'su root' failed for lonvick",
+    );
+    assert_eq!(fields["facility"], "4");
+    assert_eq!(fields["severity"], "2");
+    assert_eq!(fields["time"], "Oct 11 22:14:15");
+    assert_eq!(fields["host"], "mymachine");
+    assert_eq!(fields["app"], "su");
+    assert_eq!(fields["pid"], "230");
+    assert_eq!(fields["message"], "'su root' failed for lonvick");
+
+    let fields = parse(
+        ParseFormat::SyslogRfc5424,
+        r#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut="3"] An application event"#,
+    );
+    assert_eq!(fields["priority"], "165");
+    assert_eq!(fields["facility"], "20");
+    assert_eq!(fields["severity"], "5");
+    assert_eq!(fields["time"], "2003-10-11T22:14:15.003Z");
+    assert_eq!(fields["app"], "evntslog");
+    assert!(!fields.contains_key("pid"));
+    assert_eq!(fields["msgid"], "ID47");
+    assert_eq!(fields["structured_data"], r#"[exampleSDID@32473 iut="3"]"#);
+    assert_eq!(fields["message"], "An application event");
+
+    // W3C uses the #Fields directive for the following records
+    let view = View {
+        operations: vec![
+            Operation::Parse {
+                expression: Expression::Record,
+                format: ParseFormat::W3c,
+                prefix: String::new(),
+                else_ops: vec![Operation::SkipRecord],
+            },
+        ],
+        ..Default::default()
+    };
+    let input = "#Version: 1.0\n\
+                 #Fields: date time c-ip cs-method cs-uri-stem sc-status cs(User-Agent)\n\
+                 2020-11-27 00:15:12 10.0.0.1 GET /index.html 200 Mozilla/5.0+(X11)\n";
+    let records: Vec<_> = process(StreamReader::new(input.as_bytes()), view)
+        .map(|r| r.expect("Error during processing"))
+        .collect();
+    assert_eq!(records.len(), 1);
+    let fields = &records[0].variables;
+    assert_eq!(fields["time"], "2020-11-27T00:15:12Z");
+    assert_eq!(fields["client"], "10.0.0.1");
+    assert_eq!(fields["path"], "/index.html");
+    assert_eq!(fields["status"], "200");
+    assert_eq!(fields["user_agent"], "Mozilla/5.0 (X11)");
+}
+
+#[test]
+fn test_named_patterns() {
+    let pattern = Pattern::new(r"^%{IPV4:client} .* \[%{HTTPDATE:time}\]".to_owned());
+    assert_eq!(pattern.groups, vec!["client", "time"]);
+    let m = pattern.match_string(&"10.0.0.1 - - [27/Nov/2020:00:15:12 +0000] \"GET /\"".to_owned()).unwrap();
+    assert_eq!(m["client"], "10.0.0.1");
+    assert_eq!(m["time"], "27/Nov/2020:00:15:12 +0000");
+    assert!(pattern.match_string(&"10.0.0.300 - - [27/Nov/2020:00:15:12 +0000]".to_owned()).is_none());
+
+    // Custom patterns in the view, referencing built-in ones
+    let view: View = serde_json::from_str(r#"{
+        "patterns": {"REQUEST": "%{WORD:method} %{URIPATHPARAM:path}"},
+        "operations": [
+            {"if": {
+                "condition": {"match": {
+                    "expression": "record",
+                    "pattern": "^%{TIMESTAMP_ISO8601:time} %{REQUEST}$"
+                }},
+                "then": [],
+                "else": [
+                    "skipRecord"
+                ]
+            }}
+        ]
+    }"#).unwrap();
+    let text = "2020-11-27T00:15:12Z GET /index.html?a=1".to_owned();
+    let explanation = explain_text(&view, text).unwrap();
+    assert!(explanation.kept);
+    assert_eq!(explanation.record.variables["time"], "2020-11-27T00:15:12Z");
+    assert_eq!(explanation.record.variables["method"], "GET");
+    assert_eq!(explanation.record.variables["path"], "/index.html?a=1");
+
+    // The original text is kept for printing and serialization
+    let json = serde_json::to_string(&view).unwrap();
+    assert!(json.contains(r#""pattern":"^%{TIMESTAMP_ISO8601:time} %{REQUEST}$""#));
+    assert!(json.contains(r#""patterns":{"REQUEST":"%{WORD:method} %{URIPATHPARAM:path}"}"#));
+    assert_eq!(
+        format!("{:?}", view).lines().nth(1).unwrap(),
+        r#"  PATTERN REQUEST = "%{WORD:method} %{URIPATHPARAM:path}""#,
+    );
+
+    assert!(serde_json::from_str::<View>(r#"{"operations": [{"if": {
+        "condition": {"match": {"expression": "record", "pattern": "%{NOPE}"}},
+        "then": [], "else": []
+    }}]}"#).is_err());
+    // Text that is not a reference is left alone
+    let pattern = Pattern::new(r"^%{2}%{INT:count}%{1,2}$".to_owned());
+    assert_eq!(pattern.groups, vec!["count"]);
+    let m = pattern.match_string(&"%%12%".to_owned()).unwrap();
+    assert_eq!(m["count"], "12");
+
+    let library = vec![("LOOP".to_owned(), "a%{LOOP}".to_owned())].into_iter().collect();
+    assert!(matches!(
+        Pattern::with_library("%{LOOP}".to_owned(), &library),
+        Err(PatternError::Recursive(_)),
+    ));
+}
+
+#[test]
+fn test_csv() {
+    let input = "time,client,message\r\n\
+                 2020-11-27T00:15:12Z,10.0.0.1,\"hello, world\"\r\n\
+                 2020-11-27T00:15:13Z,10.0.0.2,\"first line\nsecond \"\"line\"\"\"\r\n\
+                 2020-11-27T00:15:14Z,10.0.0.3,,extra\r\n";
+    let file = CsvReader::new(StreamReader::new(input.as_bytes()));
+    let view = View {
+        operations: vec![
+            Operation::Parse {
+                expression: Expression::Record,
+                format: ParseFormat::Csv { delimiter: ',', columns: vec![] },
+                prefix: String::new(),
+                else_ops: vec![Operation::SkipRecord],
+            },
+        ],
+        ..Default::default()
+    };
+    let records: Vec<_> = process(file, view)
+        .map(|r| r.expect("Error during processing").variables)
+        .collect();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["client"], "10.0.0.1");
+    assert_eq!(records[0]["message"], "hello, world");
+    assert_eq!(records[1]["message"], "first line\nsecond \"line\"");
+    assert_eq!(records[2]["message"], "");
+    assert_eq!(records[2]["4"], "extra");
+
+    let format = ParseFormat::Csv {
+        delimiter: '\t',
+        columns: vec!["a".to_owned(), "b".to_owned()],
+    };
+    assert_eq!(format!("{:?}", format), "tsv COLUMNS a, b");
+    assert_eq!(
+        format.parse("1\t\"x\ty\""),
+        Some(vec![("a".to_owned(), "1".to_owned()), ("b".to_owned(), "x\ty".to_owned())]),
+    );
+    assert!(format.parse("\"unterminated").is_none());
+    assert!(format.parse("\"a\"b").is_none());
+
+    // Joined records are cut at the maximum size, the quotes are still
+    // counted to find where they end
+    let mut reader = CsvReader::new(StreamReader::new("1,\"é\nfirst\nsecond\"\n2,x\n".as_bytes()));
+    reader.max_record_size = Some(6);
+    let record = reader.read_raw_record().unwrap().unwrap();
+    assert_eq!(record.text, "1,\"é\n");
+    assert!(record.truncated);
+    let record = reader.read_raw_record().unwrap().unwrap();
+    assert_eq!(record.text, "2,x");
+    assert!(!record.truncated);
+}
+
+#[test]
+fn test_container_readers() {
+    let view = || View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^(?P<level>[A-Z]+) ".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![],
+            },
+        ],
+        ..Default::default()
+    };
+    let read = |content: &str, cri: bool| {
+        let file = StreamReader::new(content.as_bytes());
+        let records: Vec<_> = if cri {
+            process(CriReader::new(file), view())
+                .map(|r| r.expect("Error during processing"))
+                .collect()
+        } else {
+            process(DockerJsonReader::new(file), view())
+                .map(|r| r.expect("Error during processing"))
+                .collect()
+        };
+        records
+    };
+
+    let records = read(
+        "{\"log\":\"INFO started\\n\",\"stream\":\"stdout\",\"time\":\"2020-11-27T00:15:12.1Z\"}\n\
+         {\"log\":\"ERROR very \",\"stream\":\"stderr\",\"time\":\"2020-11-27T00:15:13.1Z\"}\n\
+         {\"log\":\"long line\\n\",\"stream\":\"stderr\",\"time\":\"2020-11-27T00:15:13.2Z\"}\n\
+         not json\n",
+        false,
+    );
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].text, "INFO started");
+    assert_eq!(records[0].variables["stream"], "stdout");
+    assert_eq!(records[0].variables["level"], "INFO");
+    assert_eq!(records[1].text, "ERROR very long line");
+    assert_eq!(records[1].variables["stream"], "stderr");
+    assert_eq!(records[1].variables["time"], "2020-11-27T00:15:13.1Z");
+    assert_eq!(records[2].text, "not json");
+
+    let records = read(
+        "2020-11-27T00:15:12.1Z stdout F INFO started\n\
+         2020-11-27T00:15:13.1Z stderr P ERROR very \n\
+         2020-11-27T00:15:13.2Z stderr F long line\n\
+         2020-11-27T00:15:14.1Z stdout P WARN cut off\n\
+         garbage\n",
+        true,
+    );
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[0].variables["time"], "2020-11-27T00:15:12.1Z");
+    assert_eq!(records[1].text, "ERROR very long line");
+    assert_eq!(records[1].variables["level"], "ERROR");
+    assert_eq!(records[1].variables["stream"], "stderr");
+    assert_eq!(records[2].text, "WARN cut off");
+    assert_eq!(records[3].text, "garbage");
+
+    // Chunks of both streams can be interleaved, and streams can be read
+    let input = "2020-11-27T00:15:12.1Z stdout P INFO one \n\
+                 2020-11-27T00:15:12.2Z stderr P ERROR two \n\
+                 2020-11-27T00:15:12.3Z stdout F and one\n\
+                 2020-11-27T00:15:12.4Z stderr P and \n\
+                 garbage\n";
+    let records: Vec<String> = process(CriReader::new(StreamReader::new(input.as_bytes())), view())
+        .map(|r| r.expect("Error during processing").text)
+        .collect();
+    assert_eq!(records, vec!["INFO one and one", "ERROR two and ", "garbage"]);
+    let input = "{\"log\":\"INFO one \",\"stream\":\"stdout\"}\n\
+                 {\"log\":\"ERROR two\\n\",\"stream\":\"stderr\"}\n\
+                 {\"log\":\"and one\\n\",\"stream\":\"stdout\"}\n\
+                 {\"log\":\"WARN cut\",\"stream\":\"stdout\"}\n\
+                 not json\n";
+    let mut reader = DockerJsonReader::new(StreamReader::new(input.as_bytes()));
+    let mut records = Vec::new();
+    while let Some(record) = reader.read_record().expect("Error reading") {
+        records.push(record);
+    }
+    assert_eq!(records, vec!["ERROR two", "INFO one and one", "WARN cut", "not json"]);
+
+    let input = "2020-11-27T00:15:12.1Z stdout P 12345\n\
+                 2020-11-27T00:15:12.2Z stdout F 67890\n";
+    let mut reader = CriReader::new(StreamReader::new(input.as_bytes()));
+    reader.max_record_size = Some(8);
+    let record = reader.read_raw_record().unwrap().unwrap();
+    assert_eq!(record.text, "12345678");
+    assert!(record.truncated);
+}
+
+#[test]
+fn test_journal_readers() {
+    let mut export = Vec::new();
+    export.extend_from_slice(b"__CURSOR=s=1\n__REALTIME_TIMESTAMP=1606436112000000\n\
+        _SYSTEMD_UNIT=nginx.service\nPRIORITY=6\nMESSAGE=started\n\n");
+    let second = export.len() as u64;
+    export.extend_from_slice(b"PRIORITY=3\nMESSAGE\n");
+    export.extend_from_slice(&12u64.to_le_bytes());
+    export.extend_from_slice(b"two\nlines\x00\xff!\n\n");
+    let path = TempPath::new("journal.export");
+    std::fs::write(&path, &export).unwrap();
+
+    let mut file = JournalExportFile::open(&path).expect("Can't open test file");
+    let RawRecord { text, fields, .. } = file.read_raw_record().unwrap().unwrap();
+    assert_eq!(text, "started");
+    assert!(fields.contains(&("_SYSTEMD_UNIT".to_owned(), "nginx.service".to_owned())));
+    assert!(fields.contains(&("__REALTIME_TIMESTAMP".to_owned(), "1606436112000000".to_owned())));
+    assert_eq!(file.tell(), second);
+    let RawRecord { text, fields, .. } = file.read_raw_record().unwrap().unwrap();
+    assert_eq!(text, "two\nlines\0\u{fffd}!");
+    assert_eq!(fields, vec![("PRIORITY".to_owned(), "3".to_owned())]);
+    assert_eq!(file.tell(), export.len() as u64);
+    assert!(file.read_record().unwrap().is_none());
+
+    // Seeking to an entry, or into the middle of one
+    file.seek(second).unwrap();
+    assert_eq!(file.read_record().unwrap().unwrap(), "two\nlines\0\u{fffd}!");
+    file.seek(10).unwrap();
+    assert_eq!(file.tell(), second);
+    let view = View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Var("PRIORITY".to_owned()),
+                    pattern: Pattern::new("^[0-3]$".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![Operation::SkipRecord],
+            },
+        ],
+        ..Default::default()
+    };
+    let explanation = explain(&mut file, &view, 0).unwrap().unwrap();
+    assert!(!explanation.kept);
+    let explanation = explain(&mut file, &view, second).unwrap().unwrap();
+    assert!(explanation.kept);
+
+    // Binary data that looks like the end of an entry is skipped over
+    let mut export = Vec::new();
+    export.extend_from_slice(b"MESSAGE\n");
+    export.extend_from_slice(&12u64.to_le_bytes());
+    export.extend_from_slice(b"a\n\nMESSAGE=b\n\n");
+    let third = export.len() as u64;
+    export.extend_from_slice(b"MESSAGE=c\n\n");
+    let path = TempPath::new("journal-binary.export");
+    std::fs::write(&path, &export).unwrap();
+    let mut file = JournalExportFile::open(&path).expect("Can't open test file");
+    file.seek(17).unwrap();
+    assert_eq!(file.tell(), third);
+    assert_eq!(file.read_record().unwrap().unwrap(), "c");
+    file.seek(0).unwrap();
+    assert_eq!(file.read_record().unwrap().unwrap(), "a\n\nMESSAGE=b");
+
+    // Long lines are truncated, and sizes past the end of file are errors
+    let mut export = Vec::new();
+    export.extend_from_slice(b"MESSAGE=long line\nPRIORITY=6\n\nMESSAGE\n");
+    export.extend_from_slice(&u64::MAX.to_le_bytes());
+    export.extend_from_slice(b"data\n\n");
+    let path = TempPath::new("journal-sizes.export");
+    std::fs::write(&path, &export).unwrap();
+    let mut file = JournalExportFile::open(&path).expect("Can't open test file");
+    file.max_field_size = Some(12);
+    let RawRecord { text, fields, truncated, .. } = file.read_raw_record().unwrap().unwrap();
+    assert_eq!(text, "long");
+    assert!(truncated);
+    assert_eq!(fields, vec![("PRIORITY".to_owned(), "6".to_owned())]);
+    let error = file.read_record().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    file.max_field_size = None;
+    file.seek(0).unwrap();
+    let error = file.seek(export.len() as u64).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    let input = "{\"MESSAGE\":\"hello\",\"PRIORITY\":\"6\",\"_SYSTEMD_UNIT\":\"sshd.service\"}\n\
+                 {\"MESSAGE\":[104,105,0],\"PRIORITY\":\"3\",\"_UID\":[\"0\",\"1\"]}\n";
+    let mut file = JournalJsonReader::new(StreamReader::new(input.as_bytes()));
+    let RawRecord { text, fields, .. } = file.read_raw_record().unwrap().unwrap();
+    assert_eq!(text, "hello");
+    assert!(fields.contains(&("_SYSTEMD_UNIT".to_owned(), "sshd.service".to_owned())));
+    let RawRecord { text, fields, .. } = file.read_raw_record().unwrap().unwrap();
+    assert_eq!(text, "hi\0");
+    assert!(fields.contains(&("_UID".to_owned(), "0".to_owned())));
+}
+
+#[test]
+fn test_stream_reader() {
+    let input: &[u8] = b"first\r\nskip this\nlast";
+    let view = View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^skip".to_owned()),
+                },
+                then_ops: vec![Operation::SkipRecord],
+                else_ops: vec![],
+            },
+        ],
+        ..Default::default()
+    };
+    let records: Vec<String> = process(StreamReader::new(input), view)
+        .map(|r| r.expect("Error during processing").text)
+        .collect();
+    assert_eq!(records, vec!["first", "last"]);
+
+    let mut reader = StreamReader::new(input);
+    reader.seek(0).unwrap();
+    assert_eq!(reader.read_record().unwrap().unwrap(), "first");
+    assert_eq!(reader.tell(), 7);
+    let err = reader.seek(0).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn test_encodings() {
+    fn read_all(input: &[u8], encoding: Encoding) -> Vec<(String, bool)> {
+        let options = ReadOptions { encoding, ..Default::default() };
+        let mut reader = StreamReader::with_options(input, &options).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.read_raw_record().unwrap() {
+            records.push((record.text.into_owned(), record.lossy));
+        }
+        assert_eq!(reader.tell(), input.len() as u64);
+        records
+    }
+    let r = |text: &str, lossy| (text.to_owned(), lossy);
+
+    // Invalid UTF-8 doesn't stop processing
+    assert_eq!(
+        read_all(b"\xEF\xBB\xBFok\ncaf\xE9\r\nfine", Encoding::Utf8),
+        vec![r("ok", false), r("caf\u{FFFD}", true), r("fine", false)],
+    );
+    assert_eq!(read_all(b"caf\xE9\n", Encoding::Latin1), vec![r("caf\u{E9}", false)]);
+    assert_eq!(
+        read_all(b"\x80 \x93q\x94\x81\n", Encoding::Windows1252),
+        vec![r("\u{20AC} \u{201C}q\u{201D}\u{FFFD}", true)],
+    );
+
+    // UTF-16, where 0x0A bytes can be part of other characters
+    let encode = |text: &str, big_endian: bool| -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|u| if big_endian { u.to_be_bytes() } else { u.to_le_bytes() })
+            .collect()
+    };
+    for &big_endian in &[false, true] {
+        let input = encode("\u{FEFF}caf\u{E9}\n\u{A0A}\u{1F600}\r\nlast", big_endian);
+        assert_eq!(
+            read_all(&input, Encoding::Utf16),
+            vec![r("caf\u{E9}", false), r("\u{A0A}\u{1F600}", false), r("last", false)],
+        );
+    }
+    let mut input = encode("bad ", false);
+    input.extend_from_slice(&[0x00, 0xD8]);
+    assert_eq!(read_all(&input, Encoding::Utf16), vec![r("bad \u{FFFD}", true)]);
+
+    // The flag is set on records
+    let view = View {
+        operations: vec![],
+        ..Default::default()
+    };
+    let records: Vec<_> = process(StreamReader::new(&b"good\nbad\xFF\n"[..]), view)
+        .map(|r| r.expect("Error during processing"))
+        .collect();
+    assert!(!records[0].lossy);
+    assert!(records[1].lossy);
+    assert_eq!(
+        serde_json::to_string(&records[1]).unwrap(),
+        "{\"text\":\"bad\u{FFFD}\",\"variables\":{},\"color\":\"default\",\"lossy\":true}",
+    );
+}
+
+#[test]
+fn test_max_record_size() {
+    let options = ReadOptions {
+        max_record_size: Some(8),
+        ..Default::default()
+    };
+    let input = "short\nthis is a long line\nabcdefg\u{E9}\nend".as_bytes();
+    let mut reader = StreamReader::with_options(input, &options).unwrap();
+    assert!(!reader.looks_binary());
+    let mut records = Vec::new();
+    let mut positions = Vec::new();
+    while let Some(record) = reader.read_raw_record().unwrap() {
+        records.push((record.text.into_owned(), record.truncated, record.lossy));
+        positions.push(reader.tell());
+    }
+    assert_eq!(
+        records,
+        vec![
+            ("short".to_owned(), false, false),
+            ("this is ".to_owned(), true, false),
+            // Not cut in the middle of a character
+            ("abcdefg".to_owned(), true, false),
+            ("end".to_owned(), false, false),
+        ],
+    );
+    // Positions count the whole records
+    assert_eq!(positions, vec![6, 26, 36, 39]);
+
+    // The limit doesn't count the line ending
+    let input = "12345678\n12345678\r\n123456789\n12345678".as_bytes();
+    let mut reader = StreamReader::with_options(input, &options).unwrap();
+    let mut records = Vec::new();
+    while let Some(record) = reader.read_raw_record().unwrap() {
+        records.push((record.text.into_owned(), record.truncated));
+    }
+    assert_eq!(
+        records,
+        vec![
+            ("12345678".to_owned(), false),
+            ("12345678".to_owned(), false),
+            ("12345678".to_owned(), true),
+            ("12345678".to_owned(), false),
+        ],
+    );
+    let options = ReadOptions {
+        encoding: Encoding::Utf16,
+        max_record_size: Some(4),
+        ..Default::default()
+    };
+    let input: &[u8] = b"a\0b\0\r\0\n\0a\0b\0c\0\n\0";
+    let mut reader = StreamReader::with_options(input, &options).unwrap();
+    let record = reader.read_raw_record().unwrap().unwrap();
+    assert_eq!((record.text.as_ref(), record.truncated), ("ab", false));
+    let record = reader.read_raw_record().unwrap().unwrap();
+    assert_eq!((record.text.as_ref(), record.truncated), ("ab", true));
+    assert_eq!(reader.tell(), input.len() as u64);
+
+    // Binary files are detected
+    let binary: &[u8] = b"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+    assert!(StreamReader::with_options(binary, &Default::default()).unwrap().looks_binary());
+    let options = ReadOptions {
+        refuse_binary: true,
+        ..Default::default()
+    };
+    let err = StreamReader::with_options(binary, &options).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(!StreamReader::with_options(&b"plain\ttext\r\n"[..], &options).unwrap().looks_binary());
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap_reader() {
+    fn read_all<R: LogReader>(mut reader: R) -> Vec<(String, bool, bool, u64)> {
+        let mut records = Vec::new();
+        while let Some(record) = reader.read_raw_record().unwrap() {
+            let (text, truncated, lossy) = (record.text.into_owned(), record.truncated, record.lossy);
+            records.push((text, truncated, lossy, reader.tell()));
+        }
+        records
+    }
+
+    let path = TempPath::new("mmap.log");
+    std::fs::write(
+        &path,
+        b"\xEF\xBB\xBFfirst\r\nthis is a long line\nabcdefg\xC3\xA9\nbad \xFF\n\n12345678\r\nend",
+    ).unwrap();
+    for max_record_size in &[None, Some(8)] {
+        let options = ReadOptions {
+            max_record_size: *max_record_size,
+            ..Default::default()
+        };
+        assert_eq!(
+            read_all(MmapLogFile::open_with_options(&path, &options).unwrap()),
+            read_all(LogFile::open_with_options(&path, &options).unwrap()),
+        );
+    }
+
+    // Seeking
+    let mut file = MmapLogFile::open(&path).unwrap();
+    file.seek(30).unwrap();
+    assert_eq!(file.read_record().unwrap().as_deref(), Some("abcdefg\u{E9}"));
+    file.seek(1000).unwrap();
+    assert_eq!(file.read_record().unwrap(), None);
+
+    // Other encodings are decoded the same way
+    let options = ReadOptions {
+        encoding: Encoding::Latin1,
+        ..Default::default()
+    };
+    assert_eq!(
+        read_all(MmapLogFile::open_with_options(&path, &options).unwrap()),
+        read_all(LogFile::open_with_options(&path, &options).unwrap()),
+    );
+
+    // Empty files can't be mapped
+    std::fs::write(&path, b"").unwrap();
+    assert_eq!(read_all(MmapLogFile::open(&path).unwrap()), vec![]);
+
+    // Processing gives the same records
+    let records: Vec<_> = process(MmapLogFile::open("test.log").unwrap(), get_view())
+        .map(|r| r.unwrap().text)
+        .collect();
+    let expected: Vec<_> = process(LogFile::open("test.log").unwrap(), get_view())
+        .map(|r| r.unwrap().text)
+        .collect();
+    assert!(!records.is_empty());
+    assert_eq!(records, expected);
+}
+
+#[test]
+fn test_variable_spans() {
+    let view = View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^(?P<level>[A-Z]+) (?P<message>.*)$".to_owned()),
+                },
+                then_ops: vec![
+                    // Captures from a captured variable
+                    Operation::If {
+                        condition: Condition::Match {
+                            expression: Expression::Var("message".to_owned()),
+                            pattern: Pattern::new("user=(?P<user>\\w+)".to_owned()),
+                        },
+                        then_ops: vec![],
+                        else_ops: vec![],
+                    },
+                    Operation::Set {
+                        target: "previous".to_owned(),
+                        expression: Expression::LastVarValue("user".to_owned()),
+                    },
+                    Operation::Set {
+                        target: "copy".to_owned(),
+                        expression: Expression::Var("level".to_owned()),
+                    },
+                    Operation::ColorBy(Expression::Var("user".to_owned())),
+                ],
+                else_ops: vec![
+                    // Captures from a constant
+                    Operation::If {
+                        condition: Condition::Match {
+                            expression: Expression::Constant("a-b".to_owned()),
+                            pattern: Pattern::new("-(?P<constant>.)".to_owned()),
+                        },
+                        then_ops: vec![],
+                        else_ops: vec![],
+                    },
+                ],
+            },
+        ],
+        ..Default::default()
+    };
+    let input = "INFO login user=alice\nWARN user=bob failed\nnothing\nINFO done\n".as_bytes();
+    let records: Vec<_> = process(StreamReader::new(input), view)
+        .map(|r| {
+            let r = r.unwrap();
+            let mut variables: Vec<_> = r.variables.into_iter().collect();
+            variables.sort();
+            let color = match r.color {
+                crate::Color::FromValue { value } => value,
+                _ => "-".to_owned(),
+            };
+            (variables, color)
+        })
+        .collect();
+    let vars = |v: &[(&str, &str)]| -> Vec<(String, String)> {
+        v.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    };
+    assert_eq!(
+        records,
+        vec![
+            (
+                vars(&[
+                    ("copy", "INFO"),
+                    ("level", "INFO"),
+                    ("message", "login user=alice"),
+                    ("previous", "alice"),
+                    ("user", "alice"),
+                ]),
+                "alice".to_owned(),
+            ),
+            (
+                vars(&[
+                    ("copy", "WARN"),
+                    ("level", "WARN"),
+                    ("message", "user=bob failed"),
+                    ("previous", "bob"),
+                    ("user", "bob"),
+                ]),
+                "bob".to_owned(),
+            ),
+            (vars(&[("constant", "b")]), "-".to_owned()),
+            // The last value is kept from previous records
+            (
+                vars(&[
+                    ("copy", "INFO"),
+                    ("level", "INFO"),
+                    ("message", "done"),
+                    ("previous", "bob"),
+                ]),
+                "".to_owned(),
+            ),
+        ],
+    );
+}
+
+#[test]
+fn test_elif_chain() {
+    fn elif(pattern: &str, service: &str, else_ops: Vec<Operation>) -> Vec<Operation> {
+        vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Var("message".to_owned()),
+                    pattern: Pattern::new(pattern.to_owned()),
+                },
+                then_ops: vec![
+                    Operation::Set {
+                        target: "service".to_owned(),
+                        expression: Expression::Constant(service.to_owned()),
+                    },
+                ],
+                else_ops,
+            },
+        ]
+    }
+
+    // Patterns overlap, the first one that matches must win
+    let chain = || elif(
+        "^GET (?P<path>/api/\\S*)",
+        "api",
+        elif(
+            "^(?P<method>GET|POST) (?P<path>\\S+)",
+            "web",
+            elif(
+                "error",
+                "errors",
+                elif("^db: (?P<query>.*)", "db", vec![Operation::SkipRecord]),
+            ),
+        ),
+    );
+    let view = || View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^[0-9]+ (?P<message>.*)$".to_owned()),
+                },
+                then_ops: chain(),
+                else_ops: vec![],
+            },
+        ],
+        ..Default::default()
+    };
+    let lines = [
+        "1 GET /api/users",
+        "2 GET /index.html",
+        "3 POST /api/login error",
+        "4 some error",
+        "5 db: SELECT 1",
+        "6 nothing",
+        "no number",
+    ];
+    let records: Vec<_> = process(StreamReader::new(lines.join("\n").as_bytes()), view())
+        .map(|r| r.unwrap())
+        .collect();
+    let summary: Vec<_> = records.iter()
+        .map(|r| (
+            r.text.as_str(),
+            r.variables.get("service").map(String::as_str),
+            r.variables.get("path").map(String::as_str),
+        ))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("1 GET /api/users", Some("api"), Some("/api/users")),
+            ("2 GET /index.html", Some("web"), Some("/index.html")),
+            ("3 POST /api/login error", Some("web"), Some("/api/login")),
+            ("4 some error", Some("errors"), None),
+            ("5 db: SELECT 1", Some("db"), None),
+            ("no number", None, None),
+        ],
+    );
+
+    // Same results when testing each pattern, as explain does
+    let view = view();
+    let explained: Vec<_> = lines.iter()
+        .map(|line| explain_text(&view, line.to_string()).unwrap())
+        .filter(|e| e.kept)
+        .map(|e| e.record)
+        .collect();
+    assert_eq!(explained.len(), records.len());
+    for (a, b) in explained.iter().zip(&records) {
+        assert_eq!(a.text, b.text);
+        assert_eq!(a.variables, b.variables);
+    }
+
+    // Patterns that all start with literal text, rejected by their prefix
+    let mut operations = vec![Operation::Set {
+        target: "message".to_owned(),
+        expression: Expression::Record,
+    }];
+    operations.extend(elif(
+        "^GET /",
+        "get",
+        elif(
+            "^POSTS?\\.",
+            "post",
+            elif("^db\\[(?P<query>.*)\\]", "db", vec![Operation::SkipRecord]),
+        ),
+    ));
+    let view = View { operations, ..Default::default() };
+    let input = "GET /\nPOST.\nPOSTS.\nPOST \ndb[x]\nother".as_bytes();
+    let services: Vec<_> = process(StreamReader::new(input), view)
+        .map(|r| r.unwrap().variables["service"].clone())
+        .collect();
+    assert_eq!(services, vec!["get", "post", "post", "db"]);
+}
+
+#[test]
+fn test_compile_view() {
+    let view = View {
+        operations: vec![
+            // Decided when compiling
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Constant("prod-eu".to_owned()),
+                    pattern: Pattern::new("^(?P<env>\\w+)-(?P<region>\\w+)$".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![Operation::SkipRecord],
+            },
+            // No effect
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("error".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![],
+            },
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^keep".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![
+                    Operation::SkipRecord,
+                    // Never runs
+                    Operation::Set {
+                        target: "unreachable".to_owned(),
+                        expression: Expression::Constant("yes".to_owned()),
+                    },
+                ],
+            },
+            Operation::Set {
+                target: "where".to_owned(),
+                expression: Expression::Var("region".to_owned()),
+            },
+        ],
+        ..Default::default()
+    };
+
+    let program = Program::compile(&view).unwrap();
+    assert_eq!(program.ops.len(), 4);
+    assert!(matches!(program.ops[0], Op::Set { expression: Expr::Constant(_), .. }));
+    assert!(matches!(program.ops[1], Op::Set { expression: Expr::Constant(_), .. }));
+    match &program.ops[2] {
+        Op::If { else_ops, .. } => assert_eq!(else_ops.len(), 1),
+        _ => panic!("Expected If"),
+    }
+    assert!(program.slot("unreachable").is_none());
+    assert_eq!(program.locations.len(), 1);
+
+    let input = "keep this error\ndrop this\nkeep that".as_bytes();
+    let records: Vec<_> = process(StreamReader::new(input), view)
+        .map(|r| {
+            let r = r.unwrap();
+            let mut variables: Vec<_> = r.variables.into_iter().collect();
+            variables.sort();
+            (r.text, variables)
+        })
+        .collect();
+    let variables = vec![
+        ("env".to_owned(), "prod".to_owned()),
+        ("region".to_owned(), "eu".to_owned()),
+        ("where".to_owned(), "eu".to_owned()),
+    ];
+    assert_eq!(
+        records,
+        vec![
+            ("keep this error".to_owned(), variables.clone()),
+            ("keep that".to_owned(), variables),
+        ],
+    );
+}
+
+#[test]
+fn test_check() {
+    let view = View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^(?P<level>[A-Z]+) (a|b)".to_owned()),
+                },
+                then_ops: vec![
+                    Operation::ColorBy(Expression::Var("level".to_owned())),
+                    Operation::Set {
+                        target: "copy".to_owned(),
+                        expression: Expression::Var("levle".to_owned()),
+                    },
+                ],
+                else_ops: vec![
+                    Operation::If {
+                        condition: Condition::Match {
+                            expression: Expression::Record,
+                            pattern: Pattern::new("(?P<rest>.*)".to_owned()),
+                        },
+                        then_ops: vec![],
+                        else_ops: vec![Operation::SkipRecord, Operation::SkipRecord],
+                    },
+                ],
+            },
+            Operation::Parse {
+                expression: Expression::Var("rest".to_owned()),
+                format: ParseFormat::ApacheCommon,
+                prefix: "http.".to_owned(),
+                else_ops: vec![],
+            },
+            Operation::Parse {
+                expression: Expression::Var("http.request".to_owned()),
+                format: ParseFormat::Json,
+                prefix: "json.".to_owned(),
+                else_ops: vec![],
+            },
+            Operation::Set {
+                target: "known".to_owned(),
+                expression: Expression::LastVarValue("json.anything".to_owned()),
+            },
+            Operation::Set {
+                target: "unknown".to_owned(),
+                expression: Expression::Var("http.referer".to_owned()),
+            },
+            Operation::ColorBy(Expression::Var("http.status".to_owned())),
+        ],
+        ..Default::default()
+    };
+    let warnings: Vec<_> = check(&view).into_iter()
+        .map(|w| (w.path, w.kind))
+        .collect();
+    assert_eq!(
+        warnings,
+        vec![
+            ("operations[0].then[0]".to_owned(), WarningKind::OverwrittenColor),
+            ("operations[0].then[1]".to_owned(), WarningKind::UnsetVariable),
+            ("operations[0].else[0]".to_owned(), WarningKind::Unreachable),
+            ("operations[0].else[0].else[0]".to_owned(), WarningKind::Unreachable),
+            ("operations[4]".to_owned(), WarningKind::UnsetVariable),
+        ],
+    );
+    assert_eq!(
+        check(&view)[1].to_string(),
+        "operations[0].then[1]: Variable levle is read but never set",
+    );
+
+    // Groups that capture into local variables must be read
+    let mut view = View {
+        locals: vec!["code".to_owned(), "level".to_owned()].into_iter().collect(),
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^(?P<level>[A-Z]+) (?P<code>[0-9]+) (a|b)".to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![],
+            },
+            Operation::ColorBy(Expression::Var("level".to_owned())),
+        ],
+        ..Default::default()
+    };
+    let warnings = check(&view);
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(
+        warnings[0].to_string(),
+        "operations[0]: Group code of pattern \"^(?P<level>[A-Z]+) (?P<code>[0-9]+) (a|b)\" \
+         sets a local variable that is never read",
+    );
+    view.locals.remove("code");
+    assert!(check(&view).is_empty());
+
+    // Patterns that only look like they match everything are not reported
+    let catch_all = |regex: &str| {
+        let view = View {
+            operations: vec![Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new(regex.to_owned()),
+                },
+                then_ops: vec![],
+                else_ops: vec![Operation::SkipRecord],
+            }],
+            ..Default::default()
+        };
+        check(&view).iter().any(|w| w.kind == WarningKind::Unreachable)
+    };
+    for regex in &["", "^", ".*", "^(?:.*)", "(?s)^.*$"] {
+        assert!(catch_all(regex), "{:?} should be a catch-all", regex);
+    }
+    for regex in &["^$", "^.*$", "^.{0,40}$", "[^\\x00]*", "^(a|.*)"] {
+        assert!(!catch_all(regex), "{:?} should not be a catch-all", regex);
+    }
+}
+
+#[test]
+fn test_local_variables() {
+    let view = View {
+        operations: vec![
+            Operation::If {
+                condition: Condition::Match {
+                    expression: Expression::Record,
+                    pattern: Pattern::new("^(?P<level>\\w+) (?P<message>.*)$".to_owned()),
+                },
+                then_ops: vec![
+                    Operation::If {
+                        condition: Condition::Match {
+                            expression: Expression::Var("message".to_owned()),
+                            pattern: Pattern::new("^user (?P<user>\\w+)".to_owned()),
+                        },
+                        then_ops: vec![],
+                        else_ops: vec![],
+                    },
+                    Operation::Set {
+                        target: "previous".to_owned(),
+                        expression: Expression::LastVarValue("level".to_owned()),
+                    },
+                    Operation::Set {
+                        target: "previous_user".to_owned(),
+                        expression: Expression::LastVarValue("user".to_owned()),
+                    },
+                    Operation::Unset { target: "level".to_owned() },
+                ],
+                else_ops: vec![],
+            },
+        ],
+        locals: vec!["message".to_owned()].into_iter().collect(),
+        untracked: vec!["user".to_owned()].into_iter().collect(),
+        ..Default::default()
+    };
+    assert!(check(&view).iter().any(|w| w.kind == WarningKind::UntrackedVariable));
+
+    let input = "INFO user alice logged in\nWARN user bob logged out".as_bytes();
+    let records: Vec<_> = process(StreamReader::new(input), view)
+        .map(|r| {
+            let r = r.unwrap();
+            let mut variables: Vec<_> = r.variables.into_iter().collect();
+            variables.sort();
+            variables
+        })
+        .collect();
+    let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    };
+    assert_eq!(records, vec![
+        pairs(&[("previous", "INFO"), ("previous_user", ""), ("user", "alice")]),
+        pairs(&[("previous", "WARN"), ("previous_user", ""), ("user", "bob")]),
+    ]);
+}
+
+#[test]
+fn test_blocks() {
+    let mut view = View {
+        blocks: vec![
+            ("access".to_owned(), vec![
+                Operation::If {
+                    condition: Condition::Match {
+                        expression: Expression::Record,
+                        pattern: Pattern::new("^(?P<client>[0-9.]+) ".to_owned()),
+                    },
+                    then_ops: vec![Operation::Call("mark".to_owned())],
+                    else_ops: vec![Operation::SkipRecord],
+                },
+            ]),
+            ("mark".to_owned(), vec![
+                Operation::Set {
+                    target: "kind".to_owned(),
+                    expression: Expression::Constant("access".to_owned()),
+                },
+            ]),
+        ].into_iter().collect(),
+        operations: vec![Operation::Call("access".to_owned())],
+        ..Default::default()
+    };
+    assert!(check(&view).is_empty());
+
+    let input = "10.0.0.1 GET /\nstarting up\n10.0.0.2 GET /about".as_bytes();
+    let records: Vec<_> = process(StreamReader::new(input), view.flatten().unwrap())
+        .map(|r| r.unwrap().variables)
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1]["client"], "10.0.0.2");
+    assert_eq!(records[1]["kind"], "access");
+    let flattened = format!("{:?}", view.flatten().unwrap());
+    assert!(!flattened.contains("CALL") && !flattened.contains("BLOCK"));
+    assert!(format!("{:?}", view).contains("BLOCK mark"));
+
+    // Calls are compiled the same as the flattened view
+    let calls: Vec<_> = process(StreamReader::new(input), view.flatten().unwrap())
+        .map(|r| r.unwrap().text)
+        .collect();
+    let expected: Vec<_> = process(StreamReader::new(input), view)
+        .map(|r| r.unwrap().text)
+        .collect();
+    assert_eq!(calls, expected);
+
+    // Recursion and unknown blocks
+    view = View {
+        blocks: vec![
+            ("a".to_owned(), vec![Operation::Call("b".to_owned())]),
+            ("b".to_owned(), vec![Operation::Call("a".to_owned())]),
+        ].into_iter().collect(),
+        operations: vec![Operation::Call("a".to_owned()), Operation::Call("c".to_owned())],
+        ..Default::default()
+    };
+    let kinds: Vec<_> = check(&view).into_iter().map(|w| (w.path, w.kind)).collect();
+    assert_eq!(kinds, vec![
+        ("operations[1]".to_owned(), WarningKind::UnknownBlock),
+        ("blocks.a[0]".to_owned(), WarningKind::RecursiveCall),
+        ("blocks.b[0]".to_owned(), WarningKind::RecursiveCall),
+    ]);
+    assert!(matches!(view.flatten(), Err(BlockError::Recursive(name)) if name == "a"));
+    view.operations.remove(0);
+    assert!(matches!(view.flatten(), Err(BlockError::UnknownBlock(name)) if name == "c"));
+    // Processing reports them
+    let mut records = process(StreamReader::new("one\ntwo".as_bytes()), view);
+    match records.next() {
+        Some(Err(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+        _ => panic!("Expected an error"),
     }
+    assert!(records.next().is_none());
+
+    // Blocks are compiled once, even if each calls the next one twice
+    let level = |i: usize| format!("level{}", i);
+    let mut blocks: BTreeMap<_, _> = (0..40)
+        .map(|i| (level(i), vec![Operation::Call(level(i + 1)), Operation::Call(level(i + 1))]))
+        .collect();
+    blocks.insert(level(40), vec![Operation::Set {
+        target: "deep".to_owned(),
+        expression: Expression::Constant("yes".to_owned()),
+    }]);
+    let mut view = View {
+        blocks,
+        operations: vec![Operation::Call(level(0))],
+        ..Default::default()
+    };
+    // but running them is limited like flattening
+    assert!(matches!(Program::compile(&view), Err(BlockError::TooLarge)));
+    assert!(matches!(view.flatten(), Err(BlockError::TooLarge)));
+    view.blocks.remove(&level(0));
+    view.operations = vec![Operation::Call(level(30))];
+    assert_eq!(Program::compile(&view).unwrap().blocks.len(), 11);
+    let records: Vec<_> = process(StreamReader::new("one".as_bytes()), view)
+        .map(|r| r.unwrap().variables)
+        .collect();
+    assert_eq!(records[0]["deep"], "yes");
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_includes() {
+    let dir = TempPath::new("includes");
+    let lib = dir.join("lib");
+    std::fs::create_dir_all(&lib).unwrap();
+    std::fs::write(
+        lib.join("common.json"),
+        r#"{
+            "patterns": {"LEVEL": "INFO|WARN|ERROR"},
+            "locals": ["message"],
+            "blocks": {
+                "level": [{"if": {
+                    "condition": {"match": {
+                        "expression": "record",
+                        "pattern": "^(?P<level>%{LEVEL}) (?P<message>.*)$"
+                    }},
+                    "then": [],
+                    "else": []
+                }}],
+                "shadowed": [{"skipRecord": null}]
+            },
+            "operations": [{"skipRecord": null}]
+        }"#,
+    ).unwrap();
+    std::fs::write(
+        dir.join("main.json"),
+        r#"{
+            "includes": ["common.json"],
+            "blocks": {"shadowed": []},
+            "operations": [{"call": "level"}, {"call": "shadowed"}]
+        }"#,
+    ).unwrap();
+    std::fs::write(dir.join("a.json"), r#"{"includes": ["b.json"], "operations": []}"#).unwrap();
+    std::fs::write(dir.join("b.json"), r#"{"includes": ["a.json"], "operations": []}"#).unwrap();
+
+    let main = dir.join("main.json");
+    assert!(matches!(View::load(&main, &[]), Err(LoadError::NotFound(name)) if name == "common.json"));
+    let view = View::load(&main, &[lib]).unwrap();
+    assert!(view.includes.is_empty());
+    assert_eq!(view.blocks.len(), 2);
+    assert!(view.locals.contains("message"));
+    let records: Vec<_> = process(StreamReader::new("WARN disk full".as_bytes()), view)
+        .map(|r| r.unwrap().variables)
+        .collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].get("level").map(String::as_str), Some("WARN"));
+    assert!(!records[0].contains_key("message"));
+
+    assert!(matches!(View::load(&dir.join("a.json"), &[]), Err(LoadError::Cycle(_))));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_view_store() {
+    let dir = TempPath::new("store");
+    let store = ViewStore::new(&*dir);
+    assert!(store.list().unwrap().is_empty());
+
+    let metadata = ViewMetadata {
+        description: "Hide debug messages".to_owned(),
+        author: "ops".to_owned(),
+        log_glob: Some("app-*.log".to_owned()),
+    };
+    let view = View {
+        blocks: vec![("hide".to_owned(), vec![Operation::SkipRecord])].into_iter().collect(),
+        operations: vec![Operation::If {
+            condition: Condition::Match {
+                expression: Expression::Record,
+                pattern: Pattern::new("DEBUG".to_owned()),
+            },
+            then_ops: vec![Operation::Call("hide".to_owned())],
+            else_ops: vec![],
+        }],
+        ..Default::default()
+    };
+    let info = store.save("no-debug", &metadata, &view).unwrap();
+    assert_eq!(info.metadata, metadata);
+    assert!(info.modified.is_some());
+    store.save("other", &Default::default(), &Default::default()).unwrap();
+    let names: Vec<_> = store.list().unwrap().into_iter().map(|i| i.name).collect();
+    assert_eq!(names, vec!["no-debug", "other"]);
+
+    let stored = store.get("no-debug").unwrap();
+    assert_eq!(stored.info.metadata, metadata);
+    assert_eq!(format!("{:?}", stored.view), format!("{:?}", view));
+    // Saved views are regular view files
+    let input = "DEBUG start\nINFO ready".as_bytes();
+    let records: Vec<_> = process(StreamReader::new(input), store.load("no-debug", &[]).unwrap())
+        .map(|r| r.unwrap().text)
+        .collect();
+    assert_eq!(records, vec!["INFO ready"]);
+
+    assert!(matches!(store.get("missing"), Err(StoreError::NotFound(_))));
+    assert!(matches!(store.get("../no-debug"), Err(StoreError::InvalidName(_))));
+    store.delete("other").unwrap();
+    assert!(matches!(store.delete("other"), Err(StoreError::NotFound(_))));
+    assert_eq!(store.list().unwrap().len(), 1);
+
+    // A corrupt file is listed with its error, without hiding the others
+    std::fs::write(dir.join("broken.json"), "{not json").unwrap();
+    let list = store.list().unwrap();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].name, "broken");
+    assert!(list[0].error.is_some());
+    assert!(list[1].error.is_none());
 }
@@ -0,0 +1,175 @@
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun",
+    "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Number of days since 1970-01-01 of a date in the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Simple cursor over the bytes of a timestamp.
+struct Scanner<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn number(&mut self, digits: usize) -> Option<u32> {
+        let end = self.pos + digits;
+        if end > self.bytes.len() {
+            return None;
+        }
+        let mut value = 0;
+        for &b in &self.bytes[self.pos..end] {
+            if !b.is_ascii_digit() {
+                return None;
+            }
+            value = value * 10 + (b - b'0') as u32;
+        }
+        self.pos = end;
+        Some(value)
+    }
+
+    fn expect(&mut self, chars: &[u8]) -> Option<u8> {
+        match self.bytes.get(self.pos) {
+            Some(b) if chars.contains(b) => {
+                self.pos += 1;
+                Some(*b)
+            }
+            _ => None,
+        }
+    }
+
+    fn fraction(&mut self) -> f64 {
+        let mut value = 0.0;
+        let mut scale = 0.1;
+        while let Some(b) = self.bytes.get(self.pos) {
+            if !b.is_ascii_digit() {
+                break;
+            }
+            value += (b - b'0') as f64 * scale;
+            scale /= 10.0;
+            self.pos += 1;
+        }
+        value
+    }
+
+    /// Timezone offset in seconds: `Z`, `+HH:MM`, `+HHMM`, or nothing (UTC)
+    fn timezone(&mut self) -> Option<i64> {
+        match self.expect(b"Z+- ") {
+            None => Some(0),
+            Some(b'Z') => Some(0),
+            Some(b' ') => self.timezone(),
+            Some(sign) => {
+                let hours = self.number(2)? as i64;
+                self.expect(b":");
+                let minutes = self.number(2)? as i64;
+                let offset = hours * 3600 + minutes * 60;
+                Some(if sign == b'-' { -offset } else { offset })
+            }
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+}
+
+/// ISO 8601 / RFC 3339: `2020-11-17T00:15:12Z`, `2020-11-17 00:15:12.123+01:00`
+fn parse_iso8601(s: &str) -> Option<f64> {
+    let mut scan = Scanner { bytes: s.as_bytes(), pos: 0 };
+    let year = scan.number(4)?;
+    scan.expect(b"-")?;
+    let month = scan.number(2)?;
+    scan.expect(b"-")?;
+    let day = scan.number(2)?;
+    scan.expect(b"T ")?;
+    let hour = scan.number(2)?;
+    scan.expect(b":")?;
+    let minute = scan.number(2)?;
+    scan.expect(b":")?;
+    let second = scan.number(2)?;
+    let fraction = if scan.expect(b".,").is_some() { scan.fraction() } else { 0.0 };
+    let offset = scan.timezone()?;
+    if !scan.at_end() || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let days = days_from_civil(year as i64, month, day);
+    let seconds = days * 86400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64;
+    Some((seconds - offset) as f64 + fraction)
+}
+
+/// Common log format: `27/Nov/2020:00:15:12 +0000`
+fn parse_clf(s: &str) -> Option<f64> {
+    let mut scan = Scanner { bytes: s.as_bytes(), pos: 0 };
+    let day = scan.number(2)?;
+    scan.expect(b"/")?;
+    let month = s.get(3..6).and_then(|m| MONTHS.iter().position(|&n| n == m))? as u32 + 1;
+    scan.pos += 3;
+    scan.expect(b"/")?;
+    let year = scan.number(4)?;
+    scan.expect(b":")?;
+    let hour = scan.number(2)?;
+    scan.expect(b":")?;
+    let minute = scan.number(2)?;
+    scan.expect(b":")?;
+    let second = scan.number(2)?;
+    let offset = scan.timezone()?;
+    if !scan.at_end() {
+        return None;
+    }
+    let days = days_from_civil(year as i64, month, day);
+    let seconds = days * 86400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64;
+    Some((seconds - offset) as f64)
+}
+
+/// Start of the year 10000, in seconds since the Unix epoch.
+const MAX_TIMESTAMP: f64 = 253402300800.0;
+
+/// Parse a timestamp into seconds since the Unix epoch.
+///
+/// This recognizes ISO 8601 date-times, the common log format used by HTTP
+/// servers, and plain numbers of seconds (or milliseconds, if too big to be
+/// seconds) since the epoch. Numbers before the epoch or after the year 9999,
+/// including infinity and NaN, are not timestamps.
+pub(crate) fn parse_timestamp(s: &str) -> Option<f64> {
+    let s = s.trim();
+    if let Ok(number) = s.parse::<f64>() {
+        let seconds = if number > 1e11 { number / 1000.0 } else { number };
+        return if (0.0..MAX_TIMESTAMP).contains(&seconds) { Some(seconds) } else { None };
+    }
+    parse_iso8601(s).or_else(|| parse_clf(s))
+}
+
+/// Format seconds since the Unix epoch as an ISO 8601 date-time in UTC.
+pub(crate) fn format_timestamp(seconds: i64) -> String {
+    let days = seconds.div_euclid(86400);
+    let time = seconds.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day,
+        time / 3600, time / 60 % 60, time % 60,
+    )
+}
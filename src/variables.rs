@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::program::{Program, Slot};
+
+/// The value of a variable while a record is being evaluated.
+#[derive(Clone)]
+pub(crate) enum Value {
+    /// Part of the record text, by byte range
+    Span(usize, usize),
+    Shared(Arc<str>),
+    Owned(String),
+}
+
+impl Value {
+    pub(crate) fn as_str<'a>(&'a self, text: &'a str) -> &'a str {
+        match self {
+            Value::Span(start, end) => &text[*start..*end],
+            Value::Shared(value) => value,
+            Value::Owned(value) => value,
+        }
+    }
+}
+
+/// The variables of a record being evaluated.
+///
+/// Variables used by the view are stored by slot, others such as parsed
+/// fields are kept in a list. The storage is reused from record to record.
+#[derive(Default)]
+pub(crate) struct Variables {
+    slots: Vec<Option<Value>>,
+    others: Vec<(Arc<str>, Value)>,
+}
+
+impl Variables {
+    pub(crate) fn get(&self, slot: Slot) -> Option<&Value> {
+        self.slots[slot].as_ref()
+    }
+
+    pub(crate) fn set(&mut self, slot: Slot, value: Value) {
+        self.slots[slot] = Some(value);
+    }
+
+    pub(crate) fn unset(&mut self, slot: Slot) {
+        self.slots[slot] = None;
+    }
+
+    /// Set a variable that the view doesn't use.
+    pub(crate) fn set_other(&mut self, name: Arc<str>, value: Value) {
+        match self.others.iter_mut().find(|(key, _)| *key == name) {
+            Some(entry) => entry.1 = value,
+            None => self.others.push((name, value)),
+        }
+    }
+
+    /// Remove all variables, making room for the given number of slots.
+    pub(crate) fn reset(&mut self, slots: usize) {
+        self.slots.clear();
+        self.slots.resize(slots, None);
+        self.others.clear();
+    }
+
+    /// Copy the variables to output, resolving spans against the record
+    /// text.
+    pub(crate) fn to_map(&self, program: &Program, text: &str) -> HashMap<String, String> {
+        let slots = self.slots.iter()
+            .zip(&program.names)
+            .zip(&program.local)
+            .filter(|(_, &local)| !local)
+            .filter_map(|((value, name), _)| value.as_ref().map(|value| (name, value)));
+        slots.chain(self.others.iter().map(|(name, value)| (name, value)))
+            .map(|(name, value)| (name.to_string(), value.as_str(text).to_owned()))
+            .collect()
+    }
+}
@@ -1,27 +1,447 @@
+use serde_derive::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
 use warp::Filter;
+use warp::http::StatusCode;
 use warp::path;
-use warp::reply::Reply;
+use warp::reply::{Reply, Response};
+
+use crate::{ContextItem, process};
+use crate::aggregate::{Aggregation, aggregate};
+use crate::check::check;
+use crate::cluster::{ClusterOptions, cluster};
+use crate::explain::{explain, explain_text};
+use crate::fields::{FieldsOptions, fields};
+use crate::filters::View;
+use crate::histogram::{HistogramOptions, histogram};
+use crate::readers::{LogFile, ReadOptions};
+use crate::store::{StoreError, ViewMetadata, ViewStore};
+use crate::suggest::{SuggestOptions, suggest_patterns, suggest_view};
 
 pub async fn serve(
     host: std::net::IpAddr,
     port: u16,
+    log: PathBuf,
+    options: ReadOptions,
+    views: Option<ViewStore>,
+    search_path: Vec<PathBuf>,
 ) {
+    let log = Arc::new(Log { path: log, options });
+    let log = warp::any().map(move || log.clone());
+    let search_path = Arc::new(search_path);
+    let search_path = warp::any().map(move || search_path.clone());
+    let views = Arc::new(views);
+    let views = warp::any().map(move || views.clone());
+
     let routes =
         // Index, show interface
         path::end().map(index)
         // Log query
         .or(path("api").and(path("query")).and(path::end())
+            .and(warp::post())
+            .and(log.clone())
+            .and(search_path.clone())
+            .and(warp::body::json())
             .map(query))
+        // Look for mistakes in a view
+        .or(path("api").and(path("check")).and(path::end())
+            .and(warp::post())
+            .and(search_path.clone())
+            .and(warp::body::json())
+            .map(check_view))
+        // Explain a single record
+        .or(path("api").and(path("explain")).and(path::end())
+            .and(warp::post())
+            .and(log.clone())
+            .and(search_path.clone())
+            .and(warp::body::json())
+            .map(explain_record))
+        // Aggregate records
+        .or(path("api").and(path("aggregate")).and(path::end())
+            .and(warp::post())
+            .and(log.clone())
+            .and(search_path.clone())
+            .and(warp::body::json())
+            .map(aggregate_records))
+        // Count records over time
+        .or(path("api").and(path("histogram")).and(path::end())
+            .and(warp::post())
+            .and(log.clone())
+            .and(search_path.clone())
+            .and(warp::body::json())
+            .map(histogram_records))
+        // Variables and their most frequent values
+        .or(path("api").and(path("fields")).and(path::end())
+            .and(warp::post())
+            .and(log.clone())
+            .and(search_path.clone())
+            .and(warp::body::json())
+            .map(field_facets))
+        // Suggest patterns from the log
+        .or(path("api").and(path("suggest")).and(path::end())
+            .and(warp::post())
+            .and(log.clone())
+            .and(warp::body::json())
+            .map(suggest))
+        // Group records into templates
+        .or(path("api").and(path("patterns")).and(path::end())
+            .and(warp::post())
+            .and(log.clone())
+            .and(search_path.clone())
+            .and(warp::body::json())
+            .map(patterns))
+        // Saved views
+        .or(path("api").and(path("views")).and(path::end())
+            .and(warp::get())
+            .and(views.clone())
+            .map(list_views))
+        .or(path("api").and(path("views")).and(path::param()).and(path::end())
+            .and(warp::get())
+            .and(views.clone())
+            .map(get_view))
+        .or(path("api").and(path("views")).and(path::param()).and(path::end())
+            .and(warp::put())
+            .and(views.clone())
+            .and(warp::body::json())
+            .map(save_view))
+        .or(path("api").and(path("views")).and(path::param()).and(path::end())
+            .and(warp::delete())
+            .and(views.clone())
+            .map(delete_view))
     ;
 
     eprintln!("Starting server on {}:{}", host, port);
     warp::serve(routes).run((host, port)).await;
 }
 
+fn error(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"error": message})),
+        status,
+    ).into_response()
+}
+
+/// Report an error from processing, invalid views being the client's fault.
+fn io_error(e: &std::io::Error) -> Response {
+    let status = match e.kind() {
+        std::io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error(status, &e.to_string())
+}
+
 fn index() -> impl Reply {
     "hello"
 }
 
-fn query() -> impl Reply {
-    "{}"
+/// Resolve the includes of a posted view, using the search path given on the
+/// command line. Returns the response to send if that fails.
+fn resolve_includes(view: &mut View, search_path: &[PathBuf]) -> Option<Response> {
+    view.resolve_includes(search_path)
+        .err()
+        .map(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))
+}
+
+/// The log being analyzed, and how to read it.
+struct Log {
+    path: PathBuf,
+    options: ReadOptions,
+}
+
+/// Open the log, refusing binary files which could use a lot of memory.
+fn open_log(log: &Log) -> Result<LogFile, std::io::Error> {
+    LogFile::open_with_options(&log.path, &ReadOptions {
+        refuse_binary: true,
+        ..log.options.clone()
+    })
+}
+
+#[derive(Deserialize)]
+struct QueryRequest {
+    view: View,
+    #[serde(default)]
+    before: usize,
+    #[serde(default)]
+    after: usize,
+    limit: Option<usize>,
+    /// Refuse views that have warnings, instead of only logging them
+    #[serde(default)]
+    strict: bool,
+}
+
+fn query(log: Arc<Log>, search_path: Arc<Vec<PathBuf>>, mut request: QueryRequest) -> Response {
+    if let Some(response) = resolve_includes(&mut request.view, &search_path) {
+        return response;
+    }
+    let warnings = check(&request.view);
+    if !warnings.is_empty() {
+        if request.strict {
+            return warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "error": "The view has warnings",
+                    "warnings": warnings,
+                })),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ).into_response();
+        }
+        for warning in &warnings {
+            eprintln!("Warning: {}", warning);
+        }
+    }
+    let file = match open_log(&log) {
+        Ok(f) => f,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    let limit = request.limit.unwrap_or(usize::MAX);
+    let records = process(file, request.view);
+    let items: Result<Vec<ContextItem>, _> = if request.before > 0 || request.after > 0 {
+        records.with_context(request.before, request.after).take(limit).collect()
+    } else {
+        records.map(|r| r.map(ContextItem::Record)).take(limit).collect()
+    };
+    match items {
+        Ok(items) => warp::reply::json(&items).into_response(),
+        Err(e) => io_error(&e),
+    }
+}
+
+#[derive(Deserialize)]
+struct CheckRequest {
+    view: View,
+}
+
+fn check_view(search_path: Arc<Vec<PathBuf>>, mut request: CheckRequest) -> Response {
+    if let Some(response) = resolve_includes(&mut request.view, &search_path) {
+        return response;
+    }
+    warp::reply::json(&check(&request.view)).into_response()
+}
+
+#[derive(Deserialize)]
+struct ExplainRequest {
+    view: View,
+    offset: Option<u64>,
+    text: Option<String>,
+}
+
+fn explain_record(
+    log: Arc<Log>,
+    search_path: Arc<Vec<PathBuf>>,
+    request: ExplainRequest,
+) -> Response {
+    let ExplainRequest { mut view, offset, text } = request;
+    if let Some(response) = resolve_includes(&mut view, &search_path) {
+        return response;
+    }
+    match (offset, text) {
+        (_, Some(text)) => match explain_text(&view, text) {
+            Ok(explanation) => warp::reply::json(&explanation).into_response(),
+            Err(e) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+        },
+        (Some(offset), None) => {
+            let result = open_log(&log)
+                .and_then(|mut file| explain(&mut file, &view, offset));
+            match result {
+                Ok(Some(explanation)) => warp::reply::json(&explanation).into_response(),
+                Ok(None) => error(StatusCode::NOT_FOUND, "No record at this offset"),
+                Err(e) => io_error(&e),
+            }
+        }
+        (None, None) => error(StatusCode::BAD_REQUEST, "Either offset or text is required"),
+    }
+}
+
+#[derive(Deserialize)]
+struct AggregateRequest {
+    view: View,
+    #[serde(flatten)]
+    aggregation: Aggregation,
+}
+
+fn aggregate_records(
+    log: Arc<Log>,
+    search_path: Arc<Vec<PathBuf>>,
+    mut request: AggregateRequest,
+) -> Response {
+    if let Some(response) = resolve_includes(&mut request.view, &search_path) {
+        return response;
+    }
+    if let Err(e) = request.aggregation.check() {
+        return error(StatusCode::BAD_REQUEST, &e.to_string());
+    }
+    let file = match open_log(&log) {
+        Ok(f) => f,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    match aggregate(process(file, request.view), &request.aggregation) {
+        Ok(result) => warp::reply::json(&result).into_response(),
+        Err(e) => io_error(&e),
+    }
+}
+
+#[derive(Deserialize)]
+struct HistogramRequest {
+    view: View,
+    #[serde(flatten)]
+    options: HistogramOptions,
+}
+
+fn histogram_records(
+    log: Arc<Log>,
+    search_path: Arc<Vec<PathBuf>>,
+    mut request: HistogramRequest,
+) -> Response {
+    if let Some(response) = resolve_includes(&mut request.view, &search_path) {
+        return response;
+    }
+    let file = match open_log(&log) {
+        Ok(f) => f,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    match histogram(process(file, request.view), &request.options) {
+        Ok(result) => warp::reply::json(&result).into_response(),
+        Err(e) => io_error(&e),
+    }
+}
+
+#[derive(Deserialize)]
+struct FieldsRequest {
+    view: View,
+    #[serde(flatten)]
+    options: FieldsOptions,
+}
+
+/// Largest number of values tracked per variable that clients can ask for,
+/// since the server keeps them all in memory.
+const MAX_FIELDS_CAPACITY: usize = 100_000;
+
+fn field_facets(
+    log: Arc<Log>,
+    search_path: Arc<Vec<PathBuf>>,
+    mut request: FieldsRequest,
+) -> Response {
+    if let Some(response) = resolve_includes(&mut request.view, &search_path) {
+        return response;
+    }
+    if request.options.capacity > MAX_FIELDS_CAPACITY {
+        return error(
+            StatusCode::BAD_REQUEST,
+            &format!("capacity can be at most {}", MAX_FIELDS_CAPACITY),
+        );
+    }
+    let file = match open_log(&log) {
+        Ok(f) => f,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    match fields(process(file, request.view), &request.options) {
+        Ok(result) => warp::reply::json(&result).into_response(),
+        Err(e) => io_error(&e),
+    }
+}
+
+fn suggest(log: Arc<Log>, options: SuggestOptions) -> Response {
+    let result = open_log(&log)
+        .and_then(|mut file| suggest_patterns(&mut file, &options));
+    match result {
+        Ok(suggestions) => {
+            let view = suggest_view(&suggestions);
+            warp::reply::json(&serde_json::json!({
+                "view": view,
+                "suggestions": suggestions,
+            })).into_response()
+        }
+        Err(e) => io_error(&e),
+    }
+}
+
+#[derive(Deserialize)]
+struct PatternsRequest {
+    view: View,
+    #[serde(flatten)]
+    options: ClusterOptions,
+}
+
+fn patterns(
+    log: Arc<Log>,
+    search_path: Arc<Vec<PathBuf>>,
+    mut request: PatternsRequest,
+) -> Response {
+    if let Some(response) = resolve_includes(&mut request.view, &search_path) {
+        return response;
+    }
+    let result = open_log(&log)
+        .and_then(|file| cluster(file, request.view, &request.options));
+    match result {
+        Ok(result) => warp::reply::json(&result).into_response(),
+        Err(e) => io_error(&e),
+    }
+}
+
+fn store_error(e: StoreError) -> Response {
+    let status = match e {
+        StoreError::InvalidName(_) => StatusCode::BAD_REQUEST,
+        StoreError::NotFound(_) => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error(status, &e.to_string())
+}
+
+/// Response for the saved views endpoints when there is no directory to keep
+/// them in.
+fn no_store() -> Response {
+    error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "There is no directory for saved views, start the server with --views-dir",
+    )
+}
+
+fn list_views(views: Arc<Option<ViewStore>>) -> Response {
+    let views = match views.as_ref() {
+        Some(views) => views,
+        None => return no_store(),
+    };
+    match views.list() {
+        Ok(list) => warp::reply::json(&list).into_response(),
+        Err(e) => store_error(e),
+    }
+}
+
+fn get_view(name: String, views: Arc<Option<ViewStore>>) -> Response {
+    let views = match views.as_ref() {
+        Some(views) => views,
+        None => return no_store(),
+    };
+    match views.get(&name) {
+        Ok(stored) => warp::reply::json(&stored).into_response(),
+        Err(e) => store_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct SaveRequest {
+    #[serde(flatten)]
+    metadata: ViewMetadata,
+    view: View,
+}
+
+fn save_view(name: String, views: Arc<Option<ViewStore>>, request: SaveRequest) -> Response {
+    let views = match views.as_ref() {
+        Some(views) => views,
+        None => return no_store(),
+    };
+    match views.save(&name, &request.metadata, &request.view) {
+        Ok(info) => warp::reply::json(&info).into_response(),
+        Err(e) => store_error(e),
+    }
+}
+
+fn delete_view(name: String, views: Arc<Option<ViewStore>>) -> Response {
+    let views = match views.as_ref() {
+        Some(views) => views,
+        None => return no_store(),
+    };
+    match views.delete(&name) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => store_error(e),
+    }
 }